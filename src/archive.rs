@@ -0,0 +1,1888 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{BufReader, BufWriter, Cursor, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use aes_gcm::aead::OsRng;
+use anyhow::{bail, Context, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use fastcdc::v2020::FastCDC;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use zeroize::Zeroizing;
+use zstd::stream::{Decoder, Encoder};
+
+use crate::encrypt::{self, Cipher, KdfParams};
+use crate::keys::{
+    Credentials, Envelope, KeySlot, Passphrase, Signer, SigningIdentity, WrongCredentials,
+};
+use crate::recovery::{self, Trailer};
+use crate::volume::{self, ArchiveFile};
+
+/// Magic bytes at the start of every versioned .ubl archive.
+///
+/// Archives written before the header existed start directly with the first
+/// entry (or with the encryption salt) and are read as format version 1.
+pub const MAGIC: &[u8; 4] = b"UBL\0";
+pub const FORMAT_VERSION: u16 = 8;
+/// First format in which every archive with key slots has a header tag.
+/// Some format 5 archives were written before the tag existed.
+const HEADER_TAG_VERSION: u16 = 6;
+/// Starts every record of the chunk table (format 8), so that a salvage can
+/// find the next record after damaged data. A chunk record carries its id;
+/// each entry is also stored as a checksummed record ahead of its chunks, so
+/// files can be named without the index.
+pub const CHUNK_MARKER: &[u8; 8] = b"UBL\x01CHNK";
+pub const ENTRY_MARKER: &[u8; 8] = b"UBL\x01NTRY";
+
+pub const COMPRESSION_LEVEL: i32 = 21;
+/// FastCDC chunk size bounds. Files smaller than the minimum stay one chunk.
+const CHUNK_MIN: u32 = 16 * 1024;
+const CHUNK_AVG: u32 = 64 * 1024;
+const CHUNK_MAX: u32 = 256 * 1024;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+/// Domain separation for archive signatures.
+const SIGNATURE_CONTEXT: &[u8] = b"ubl-signature-v1";
+
+/// Plaintext header stored as JSON right after the magic bytes.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Header {
+    pub version: u16,
+    #[serde(default)]
+    pub encrypted: bool,
+    /// Whether the payload starts with a trained zstd dictionary.
+    #[serde(default)]
+    pub dictionary: bool,
+    /// Random identifier assigned whenever the archive is written.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    /// The archive this one is an incremental backup of.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub base: Option<BaseRef>,
+    /// Wrapped copies of the data key. Encrypted archives without slots
+    /// (format 4 and older) derive the key from the password directly.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub key_slots: Vec<KeySlot>,
+    /// Whether an encrypted archive keeps its index in plaintext, ahead of
+    /// the encrypted contents (format 6).
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub metadata_visible: bool,
+    /// AEAD for the body and key slots. Absent means AES-256-GCM.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cipher: Option<Cipher>,
+    /// Nonce and AEAD tag over the rest of the header, made with the data key.
+    /// Archives that have one also pass the header as associated data when
+    /// encrypting the body.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub header_tag: Option<String>,
+    /// Publisher signature over the rest of the header and the body.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<ArchiveSignature>,
+    /// When the archive was first written, in seconds since the Unix epoch
+    /// (format 7). Updating or rekeying the archive keeps it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created: Option<u64>,
+    /// Name and version of the tool that wrote the contents (format 7).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub creator: Option<String>,
+}
+
+/// Ed25519 signature embedded by `ubl sign`.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ArchiveSignature {
+    /// The signer's public key.
+    pub signer: String,
+    pub signature: String,
+}
+
+/// Result of checking an archive's signature.
+pub enum SignatureStatus {
+    Unsigned,
+    Valid(Signer),
+    /// The signature does not match the archive; holds the claimed signer.
+    Invalid(String),
+}
+
+impl Header {
+    /// Whether a password (rather than only an identity) could open the archive.
+    pub fn accepts_password(&self) -> bool {
+        self.encrypted
+            && (self.key_slots.is_empty()
+                || self
+                    .key_slots
+                    .iter()
+                    .any(|slot| matches!(slot, KeySlot::Password { .. })))
+    }
+
+    /// Authenticates the header under the archive's data key. Must be called
+    /// again after any change to the header.
+    pub fn seal(&mut self, envelope: &Envelope) {
+        self.header_tag = None;
+        let aad = self.tag_aad();
+        let (nonce, tag) = encrypt::encrypt_with_key(&[], &envelope.key, envelope.cipher, &aad);
+        self.header_tag = Some(STANDARD.encode([nonce, tag].concat()));
+    }
+
+    /// Checks the header tag. Only archives from before the tag may lack
+    /// one; from [`HEADER_TAG_VERSION`] on, a missing tag means it was
+    /// stripped.
+    pub fn verify(&self, envelope: &Envelope) -> Result<()> {
+        let Some(tag) = &self.header_tag else {
+            if self.version >= HEADER_TAG_VERSION && self.encrypted && !self.key_slots.is_empty() {
+                bail!("Archive header tampered: its authentication tag is missing");
+            }
+            return Ok(());
+        };
+        let nonce_len = envelope.cipher.nonce_len();
+        let valid = STANDARD
+            .decode(tag)
+            .ok()
+            .filter(|tag| tag.len() >= nonce_len)
+            .and_then(|tag| {
+                let (nonce, tag) = tag.split_at(nonce_len);
+                encrypt::decrypt_with_key(
+                    nonce,
+                    tag,
+                    &envelope.key,
+                    envelope.cipher,
+                    &self.tag_aad(),
+                )
+                .ok()
+            })
+            .is_some();
+        if !valid {
+            bail!("Archive header tampered: it does not match the data it was encrypted with");
+        }
+        Ok(())
+    }
+
+    /// Associated data for the body: every header field except the key slots,
+    /// which `rekey` replaces, the tag and the signature. Empty for untagged
+    /// archives.
+    fn body_aad(&self) -> Vec<u8> {
+        if self.header_tag.is_none() {
+            return Vec::new();
+        }
+        let fields = Header {
+            key_slots: Vec::new(),
+            header_tag: None,
+            signature: None,
+            ..self.clone()
+        };
+        [MAGIC.as_slice(), &serde_json::to_vec(&fields).unwrap()].concat()
+    }
+
+    /// Associated data for the header tag: every field except the tag and
+    /// the signature, which is added afterwards.
+    fn tag_aad(&self) -> Vec<u8> {
+        let fields = Header {
+            header_tag: None,
+            signature: None,
+            ..self.clone()
+        };
+        [MAGIC.as_slice(), &serde_json::to_vec(&fields).unwrap()].concat()
+    }
+}
+
+/// Link from an incremental archive to the archive it was taken against.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct BaseRef {
+    pub id: String,
+    /// Location of the base archive, relative to the incremental's directory
+    /// unless absolute.
+    pub path: String,
+}
+
+/// A content-defined slice of file data, stored once per archive as an
+/// independent zstd frame and referenced by every entry that contains it.
+pub struct Chunk {
+    /// SHA-256 of the uncompressed chunk. Unknown for data loaded from
+    /// archives written before chunking; computed when the archive is rewritten.
+    pub hash: Option<[u8; 32]>,
+    pub original_len: u64,
+    pub data: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum EntryKind {
+    #[default]
+    File,
+    /// Tombstone recording that the file was deleted since the base archive.
+    Deleted,
+    /// Symbolic link whose contents are the link target (format 7).
+    Symlink,
+}
+
+/// User and group ids of a file.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub struct Owner {
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl std::fmt::Display for Owner {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}:{}", self.uid, self.gid)
+    }
+}
+
+/// A single file in the archive, described by the chunks that make it up.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Entry {
+    pub path: String,
+    #[serde(default)]
+    pub kind: EntryKind,
+    pub original_len: u64,
+    /// Modification time in seconds since the Unix epoch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mtime: Option<u64>,
+    /// Unix permission bits, when known (format 7).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<u32>,
+    /// Numeric Unix owner, when known.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owner: Option<Owner>,
+    /// Hex SHA-256 of the whole file contents.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    /// Indices into `Archive::chunks`, in content order.
+    #[serde(default)]
+    pub chunks: Vec<u32>,
+}
+
+impl Entry {
+    pub fn is_deleted(&self) -> bool {
+        self.kind == EntryKind::Deleted
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.kind == EntryKind::Symlink
+    }
+}
+
+/// An archive loaded into memory.
+pub struct Archive {
+    pub id: Option<String>,
+    pub base: Option<BaseRef>,
+    pub dictionary: Option<Vec<u8>>,
+    pub chunks: Vec<Chunk>,
+    pub entries: Vec<Entry>,
+    /// Whether the archive was encrypted on disk.
+    pub encrypted: bool,
+    /// Data key and key slots the archive is written with; `None` writes it
+    /// in plaintext.
+    pub envelope: Option<Envelope>,
+    /// Path of the entry whose data was cut short, if the archive is truncated.
+    pub truncated: Option<String>,
+    /// Whether the index is stored in plaintext next to encrypted contents.
+    pub metadata_visible: bool,
+    /// Percentage of Reed-Solomon parity to store after the archive, so that
+    /// `ubl repair` can rebuild damaged blocks.
+    pub recovery: Option<u8>,
+    /// Set when only the visible index was read, without credentials. The
+    /// entries are known but `chunks` is empty.
+    pub locked: bool,
+    /// Creation time read from the header, written back unchanged when the
+    /// archive is rewritten. `None` stamps the current time.
+    pub created: Option<u64>,
+    chunk_ids: HashMap<[u8; 32], u32>,
+}
+
+impl Archive {
+    pub fn new(dictionary: Option<Vec<u8>>) -> Self {
+        Archive {
+            id: Some(new_id()),
+            base: None,
+            dictionary,
+            chunks: Vec::new(),
+            entries: Vec::new(),
+            encrypted: false,
+            envelope: None,
+            truncated: None,
+            metadata_visible: false,
+            recovery: None,
+            locked: false,
+            created: None,
+            chunk_ids: HashMap::new(),
+        }
+    }
+
+    /// Reads and, if needed, decrypts the archive at `path`.
+    pub fn open(path: &str, credentials: &Credentials) -> Result<Self> {
+        Self::read(path, credentials, false)
+    }
+
+    /// Like [`Archive::open`], but an archive with a visible index also opens
+    /// without credentials. Its entries can then be listed, but the archive
+    /// is `locked`: it has no chunks.
+    pub fn open_index(path: &str, credentials: &Credentials) -> Result<Self> {
+        Self::read(path, credentials, true)
+    }
+
+    fn read(path: &str, credentials: &Credentials, allow_locked: bool) -> Result<Self> {
+        let mut file = ArchiveFile::open(path)?;
+        let mut magic = [0u8; 4];
+        let has_header = file.read_exact(&mut magic).is_ok() && &magic == MAGIC;
+        if !has_header {
+            let mut full_data = Vec::new();
+            file.seek(SeekFrom::Start(0))
+                .and_then(|_| file.read_to_end(&mut full_data))
+                .with_context(|| format!("Failed to read archive '{}'", path))?;
+            return Self::from_legacy(&full_data, credentials.passphrase.as_ref());
+        }
+
+        // The data key is unwrapped from the header before the body is read,
+        // so a wrong password is reported without reading the whole archive.
+        let header = read_header_fields(&mut file)?;
+        let locked = allow_locked && header.metadata_visible && credentials.is_empty();
+        let envelope = if header.encrypted && !header.key_slots.is_empty() && !locked {
+            let opened = Envelope::open(
+                header.key_slots.clone(),
+                header.cipher.unwrap_or_default(),
+                credentials,
+            )?;
+            header.verify(&opened)?;
+            Some(opened)
+        } else {
+            None
+        };
+
+        let mut body = Vec::new();
+        file.read_to_end(&mut body)
+            .with_context(|| format!("Failed to read archive '{}'", path))?;
+        let mut archive = Self::from_parts(header, &body, envelope, credentials, locked)?;
+        archive.recovery = file.recovery().map(|t| t.percent);
+        Ok(archive)
+    }
+
+    fn from_parts(
+        header: Header,
+        mut body: &[u8],
+        envelope: Option<Envelope>,
+        credentials: &Credentials,
+        locked: bool,
+    ) -> Result<Self> {
+        let mut visible_index = None;
+        if header.encrypted && header.metadata_visible {
+            let (index, rest) = split_visible_index(body)?;
+            visible_index = Some(index);
+            body = rest;
+
+            if locked {
+                let mut archive = Archive::new(None);
+                archive.id = header.id;
+                archive.base = header.base;
+                archive.created = header.created;
+                archive.encrypted = true;
+                archive.metadata_visible = true;
+                archive.locked = true;
+                archive.entries = parse_index(index)?;
+                return Ok(archive);
+            }
+        }
+
+        let payload =
+            decrypt_payload(&header, body, visible_index, envelope.as_ref(), credentials)?;
+
+        let mut cursor = Cursor::new(payload.as_slice());
+        let dictionary = if header.dictionary {
+            let dict_len = read_u32(&mut cursor).context("Dictionary is truncated")?;
+            let mut dict = vec![0u8; dict_len as usize];
+            cursor
+                .read_exact(&mut dict)
+                .context("Dictionary is truncated")?;
+            Some(dict)
+        } else {
+            None
+        };
+
+        let mut archive = Archive::new(dictionary);
+        archive.id = header.id;
+        archive.base = header.base;
+        archive.created = header.created;
+        archive.encrypted = header.encrypted;
+        archive.metadata_visible = visible_index.is_some();
+        archive.envelope = envelope;
+        match header.version {
+            ..=2 => archive.read_inline_entries(&mut cursor),
+            3 => {
+                archive.read_chunks(&mut cursor, false)?;
+                archive.read_chunked_entries(&mut cursor)?;
+            }
+            version => {
+                archive.read_chunks(&mut cursor, version >= 8)?;
+                match visible_index {
+                    Some(index) => archive.read_index(&mut Cursor::new(index))?,
+                    None => archive.read_index(&mut cursor)?,
+                }
+            }
+        }
+        Ok(archive)
+    }
+
+    /// Reads an archive written before headers were introduced: a bare entry
+    /// stream, encrypted as a whole when a password was used.
+    fn from_legacy(full_data: &[u8], password: Option<&Passphrase>) -> Result<Self> {
+        let payload = match password {
+            Some(pass) => decrypt_body(full_data, pass)?,
+            None => Zeroizing::new(full_data.to_vec()),
+        };
+
+        let mut archive = Archive::new(None);
+        archive.id = None;
+        archive.encrypted = password.is_some();
+        archive.read_inline_entries(&mut Cursor::new(payload.as_slice()));
+        Ok(archive)
+    }
+
+    /// Serializes the archive, encrypting the payload under the envelope's
+    /// data key if it has one. Only chunks still referenced by an entry are
+    /// written.
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        if self.locked {
+            bail!("Archive contents were not decrypted");
+        }
+        let mut payload = Vec::new();
+        if let Some(dict) = &self.dictionary {
+            payload.extend(&(dict.len() as u32).to_le_bytes());
+            payload.extend(dict);
+        }
+
+        let mut remap: HashMap<u32, u32> = HashMap::new();
+        let mut live = Vec::new();
+        for id in self.entries.iter().flat_map(|e| &e.chunks) {
+            if !remap.contains_key(id) {
+                remap.insert(*id, live.len() as u32);
+                live.push(*id);
+            }
+        }
+
+        let index: Vec<Entry> = self
+            .entries
+            .iter()
+            .map(|entry| Entry {
+                chunks: entry.chunks.iter().map(|id| remap[id]).collect(),
+                ..entry.clone()
+            })
+            .collect();
+
+        // Each entry is followed by the chunks it is the first to use, so
+        // chunks come out in id order.
+        payload.extend(&((index.len() + live.len()) as u32).to_le_bytes());
+        let mut written = 0;
+        for entry in &index {
+            let entry_json = serde_json::to_vec(entry).unwrap();
+            payload.extend(ENTRY_MARKER);
+            payload.extend(&(entry_json.len() as u32).to_le_bytes());
+            payload.extend(&entry_json);
+            payload.extend(Sha256::digest(&entry_json));
+
+            for &id in &entry.chunks {
+                if id as usize != written {
+                    continue;
+                }
+                let chunk = &self.chunks[live[written] as usize];
+                let hash = match chunk.hash {
+                    Some(hash) => hash,
+                    None => self.hash_chunk(chunk)?,
+                };
+                payload.extend(CHUNK_MARKER);
+                payload.extend(&id.to_le_bytes());
+                payload.extend(&hash);
+                payload.extend(&chunk.original_len.to_le_bytes());
+                payload.extend(&(chunk.data.len() as u64).to_le_bytes());
+                payload.extend(&chunk.data);
+                written += 1;
+            }
+        }
+
+        // The index takes up the rest of the payload, unless it is kept
+        // visible ahead of the encrypted contents.
+        let index_json = serde_json::to_vec(&index).unwrap();
+        let index = zstd::encode_all(&index_json[..], COMPRESSION_LEVEL)?;
+        let metadata_visible = self.metadata_visible && self.envelope.is_some();
+        if !metadata_visible {
+            payload.extend(&index);
+        }
+
+        let mut header = Header {
+            version: FORMAT_VERSION,
+            encrypted: self.envelope.is_some(),
+            dictionary: self.dictionary.is_some(),
+            id: self.id.clone(),
+            base: self.base.clone(),
+            key_slots: self
+                .envelope
+                .as_ref()
+                .map(|e| e.slots.clone())
+                .unwrap_or_default(),
+            metadata_visible,
+            cipher: self.envelope.as_ref().map(|e| e.cipher),
+            header_tag: None,
+            signature: None,
+            created: self.created.or_else(|| {
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .ok()
+                    .map(|d| d.as_secs())
+            }),
+            creator: Some(format!("ubl {}", env!("CARGO_PKG_VERSION"))),
+        };
+        let body = match &self.envelope {
+            Some(envelope) => {
+                header.seal(envelope);
+                let mut aad = header.body_aad();
+                let mut body = Vec::new();
+                if metadata_visible {
+                    aad.extend(&index);
+                    body.extend(&(index.len() as u64).to_le_bytes());
+                    body.extend(&index);
+                }
+                let (nonce, ciphertext) =
+                    encrypt::encrypt_with_key(&payload, &envelope.key, envelope.cipher, &aad);
+                body.extend(nonce);
+                body.extend(ciphertext);
+                body
+            }
+            None => payload,
+        };
+        let header_json = serde_json::to_vec(&header).unwrap();
+
+        let mut out = Vec::new();
+        out.extend(MAGIC);
+        out.extend(&(header_json.len() as u32).to_le_bytes());
+        out.extend(&header_json);
+        out.extend(&body);
+        if let Some(percent) = self.recovery {
+            recovery::append(&mut out, percent);
+        }
+        Ok(out)
+    }
+
+    /// Writes the archive to a single file, removing any volumes of an
+    /// earlier split archive at `path`.
+    pub fn write(&self, path: &str) -> Result<()> {
+        volume::write_file(path, &self.to_bytes()?)?;
+        volume::remove_stale(path, 1)
+    }
+
+    /// Writes the archive over the one at `path`, keeping its volume layout
+    /// if it is split. Each file is replaced atomically.
+    pub fn rewrite(&self, path: &str) -> Result<()> {
+        volume::replace(path, &self.to_bytes()?)
+    }
+
+    /// Writes the archive as volumes `path.001`, `path.002`, ... of at most
+    /// `volume_size` bytes. Returns the number of volumes.
+    pub fn write_volumes(&self, path: &str, volume_size: u64) -> Result<usize> {
+        volume::write(path, &self.to_bytes()?, volume_size)
+    }
+
+    /// Writes the archive to a stream without seeking, so it can go to a
+    /// pipe. The archive is serialized in memory first; nothing is written
+    /// until it is complete.
+    pub fn write_to(&self, writer: impl Write) -> Result<()> {
+        let bytes = self.to_bytes()?;
+        let mut writer = BufWriter::new(writer);
+        writer.write_all(&bytes)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Splits `data` into content-defined chunks, compresses the ones not
+    /// already in the archive and stores the file under `path`, replacing any
+    /// existing entry with the same path. Returns the new entry so callers can
+    /// record further metadata.
+    pub fn insert(&mut self, path: String, data: &[u8], mtime: Option<u64>) -> &mut Entry {
+        let mut chunks = Vec::new();
+        for piece in split_chunks(data) {
+            let hash: [u8; 32] = Sha256::digest(piece).into();
+
+            let id = match self.chunk_ids.get(&hash) {
+                Some(id) => *id,
+                None => {
+                    let id = self.chunks.len() as u32;
+                    self.chunks.push(Chunk {
+                        hash: Some(hash),
+                        original_len: piece.len() as u64,
+                        data: self.compress(piece),
+                    });
+                    self.chunk_ids.insert(hash, id);
+                    id
+                }
+            };
+            chunks.push(id);
+        }
+
+        let entry = Entry {
+            path,
+            kind: EntryKind::File,
+            original_len: data.len() as u64,
+            mtime,
+            mode: None,
+            owner: None,
+            sha256: Some(format!("{:x}", Sha256::digest(data))),
+            chunks,
+        };
+        self.push_entry(entry)
+    }
+
+    /// Stores a copy of the entry at `from` under `to`, sharing its chunks.
+    /// Returns whether `from` existed.
+    pub fn copy_entry(&mut self, from: &str, to: String) -> bool {
+        let Some(existing) = self.entries.iter().find(|e| e.path == from) else {
+            return false;
+        };
+        let entry = Entry {
+            path: to,
+            ..existing.clone()
+        };
+        self.push_entry(entry);
+        true
+    }
+
+    /// Records that `path` existed in the base archive but has been deleted.
+    pub fn insert_tombstone(&mut self, path: String) {
+        self.push_entry(Entry {
+            path,
+            kind: EntryKind::Deleted,
+            original_len: 0,
+            mtime: None,
+            mode: None,
+            owner: None,
+            sha256: None,
+            chunks: Vec::new(),
+        });
+    }
+
+    fn push_entry(&mut self, entry: Entry) -> &mut Entry {
+        match self.entries.iter().position(|e| e.path == entry.path) {
+            Some(i) => {
+                self.entries[i] = entry;
+                &mut self.entries[i]
+            }
+            None => {
+                self.entries.push(entry);
+                self.entries.last_mut().unwrap()
+            }
+        }
+    }
+
+    /// Gives the archive a fresh ID so incrementals taken against its previous
+    /// contents no longer match it.
+    pub fn renew_id(&mut self) {
+        self.id = Some(new_id());
+    }
+
+    /// Removes the entry stored under `path`. Returns whether it existed.
+    /// Chunks it leaves unreferenced are dropped when the archive is written.
+    pub fn remove(&mut self, path: &str) -> bool {
+        let before = self.entries.len();
+        self.entries.retain(|e| e.path != path);
+        self.entries.len() != before
+    }
+
+    pub fn compress(&self, data: &[u8]) -> Vec<u8> {
+        let mut compressed = Vec::new();
+        let mut encoder = match &self.dictionary {
+            Some(dict) => Encoder::with_dictionary(&mut compressed, COMPRESSION_LEVEL, dict),
+            None => Encoder::new(&mut compressed, COMPRESSION_LEVEL),
+        }
+        .unwrap();
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap();
+        compressed
+    }
+
+    /// Returns a streaming decoder for an entry's contents. The entry's chunks
+    /// are consecutive zstd frames, which the decoder reads back to back.
+    pub fn decoder(&self, entry: &Entry) -> std::io::Result<Decoder<'_, Cursor<Vec<u8>>>> {
+        self.chunks_decoder(&entry.chunks)
+    }
+
+    /// Like [`Archive::decoder`], but positioned at byte `offset` of the
+    /// entry. Whole chunks before the offset are skipped without being
+    /// decompressed.
+    pub fn decoder_at(
+        &self,
+        entry: &Entry,
+        offset: u64,
+    ) -> std::io::Result<Decoder<'_, Cursor<Vec<u8>>>> {
+        let mut skipped = 0;
+        let mut first = entry.chunks.len();
+        for (i, id) in entry.chunks.iter().enumerate() {
+            let len = self.chunks[*id as usize].original_len;
+            if skipped + len > offset {
+                first = i;
+                break;
+            }
+            skipped += len;
+        }
+
+        let mut decoder = self.chunks_decoder(&entry.chunks[first..])?;
+        std::io::copy(
+            &mut (&mut decoder).take(offset.saturating_sub(skipped)),
+            &mut std::io::sink(),
+        )?;
+        Ok(decoder)
+    }
+
+    fn chunks_decoder(&self, ids: &[u32]) -> std::io::Result<Decoder<'_, Cursor<Vec<u8>>>> {
+        let frames: Vec<u8> = ids
+            .iter()
+            .flat_map(|id| &self.chunks[*id as usize].data)
+            .copied()
+            .collect();
+
+        match &self.dictionary {
+            Some(dict) => Decoder::with_dictionary(Cursor::new(frames), dict),
+            None => Decoder::with_buffer(Cursor::new(frames)),
+        }
+    }
+
+    /// Decompresses a single chunk.
+    pub fn decompress_chunk(&self, chunk: &Chunk) -> std::io::Result<Vec<u8>> {
+        let mut decoder = match &self.dictionary {
+            Some(dict) => Decoder::with_dictionary(&chunk.data[..], dict),
+            None => Decoder::with_buffer(&chunk.data[..]),
+        }?;
+        let mut buf = Vec::new();
+        decoder.read_to_end(&mut buf)?;
+        Ok(buf)
+    }
+
+    /// Total compressed size of the chunks an entry references, counting
+    /// shared chunks in full.
+    pub fn compressed_len(&self, entry: &Entry) -> u64 {
+        entry
+            .chunks
+            .iter()
+            .map(|id| self.chunks[*id as usize].data.len() as u64)
+            .sum()
+    }
+
+    fn hash_chunk(&self, chunk: &Chunk) -> Result<[u8; 32]> {
+        let data = self
+            .decompress_chunk(chunk)
+            .context("Failed to decompress existing entry")?;
+        Ok(Sha256::digest(&data).into())
+    }
+
+    /// Reads entries that carry their compressed data inline (format
+    /// versions 1 and 2). Each one becomes a single chunk. Stops at the first
+    /// entry that is cut short and records its path (or a placeholder if not
+    /// even the path could be read).
+    fn read_inline_entries(&mut self, reader: &mut Cursor<&[u8]>) {
+        let total = reader.get_ref().len() as u64;
+
+        while reader.position() < total {
+            let Some(path) = read_path(reader) else {
+                self.truncated = Some("<unknown>".into());
+                return;
+            };
+
+            let (Ok(original_len), Ok(compressed_len)) = (read_u64(reader), read_u64(reader))
+            else {
+                self.truncated = Some(path);
+                return;
+            };
+
+            let mut data = vec![0u8; compressed_len.min(total) as usize];
+            if reader.read_exact(&mut data).is_err() {
+                self.truncated = Some(path);
+                return;
+            }
+
+            let id = self.chunks.len() as u32;
+            self.chunks.push(Chunk {
+                hash: None,
+                original_len,
+                data,
+            });
+            self.entries.push(Entry {
+                path,
+                kind: EntryKind::File,
+                original_len,
+                mtime: None,
+                mode: None,
+                owner: None,
+                sha256: None,
+                chunks: vec![id],
+            });
+        }
+    }
+
+    /// Reads the chunk table. With `markers` (format 8), the table holds
+    /// marked chunk and entry records; the entry records duplicate the index
+    /// and are skipped.
+    fn read_chunks(&mut self, reader: &mut Cursor<&[u8]>, markers: bool) -> Result<()> {
+        let total = reader.get_ref().len() as u64;
+        let count = read_u32(reader).context("Chunk table is truncated")?;
+
+        for _ in 0..count {
+            if markers && !read_chunk_marker(reader)? {
+                continue;
+            }
+            let id = self.chunks.len() as u32;
+            let mut hash = [0u8; 32];
+            reader
+                .read_exact(&mut hash)
+                .context("Chunk table is truncated")?;
+            let original_len = read_u64(reader).context("Chunk table is truncated")?;
+            let compressed_len = read_u64(reader).context("Chunk table is truncated")?;
+            let mut data = vec![0u8; compressed_len.min(total) as usize];
+            reader
+                .read_exact(&mut data)
+                .context("Chunk table is truncated")?;
+
+            self.chunks.push(Chunk {
+                hash: Some(hash),
+                original_len,
+                data,
+            });
+            self.chunk_ids.insert(hash, id);
+        }
+        Ok(())
+    }
+
+    /// Reads the zstd-compressed JSON index that follows the chunk table.
+    fn read_index(&mut self, reader: &mut impl Read) -> Result<()> {
+        let entries = parse_index(reader)?;
+
+        for entry in &entries {
+            if let Some(id) = entry
+                .chunks
+                .iter()
+                .find(|id| **id as usize >= self.chunks.len())
+            {
+                bail!("Entry '{}' references missing chunk {}", entry.path, id);
+            }
+        }
+        self.entries = entries;
+        Ok(())
+    }
+
+    /// Reads binary entries that reference the chunk table (format version 3).
+    fn read_chunked_entries(&mut self, reader: &mut Cursor<&[u8]>) -> Result<()> {
+        let total = reader.get_ref().len() as u64;
+
+        while reader.position() < total {
+            let Some(path) = read_path(reader) else {
+                self.truncated = Some("<unknown>".into());
+                return Ok(());
+            };
+
+            let (Ok(original_len), Ok(count)) = (read_u64(reader), read_u32(reader)) else {
+                self.truncated = Some(path);
+                return Ok(());
+            };
+
+            let mut chunks = Vec::with_capacity(count.min(1024) as usize);
+            for _ in 0..count {
+                let Ok(id) = read_u32(reader) else {
+                    self.truncated = Some(path);
+                    return Ok(());
+                };
+                if id as usize >= self.chunks.len() {
+                    bail!("Entry '{}' references missing chunk {}", path, id);
+                }
+                chunks.push(id);
+            }
+
+            self.entries.push(Entry {
+                path,
+                kind: EntryKind::File,
+                original_len,
+                mtime: None,
+                mode: None,
+                owner: None,
+                sha256: None,
+                chunks,
+            });
+        }
+        Ok(())
+    }
+}
+
+fn new_id() -> String {
+    let mut id = [0u8; 16];
+    OsRng.fill_bytes(&mut id);
+    hex::encode(id)
+}
+
+/// Reads the marker of a chunk table record. Returns whether it starts a
+/// chunk; an entry record is skipped, leaving `reader` after it.
+fn read_chunk_marker(reader: &mut (impl Read + Seek)) -> Result<bool> {
+    let mut marker = [0u8; 8];
+    reader
+        .read_exact(&mut marker)
+        .context("Chunk table is truncated")?;
+    match &marker {
+        CHUNK_MARKER => {
+            // Chunks are stored in id order, so the id is only needed when
+            // salvaging.
+            read_u32(reader).context("Chunk table is truncated")?;
+            Ok(true)
+        }
+        ENTRY_MARKER => {
+            let len = read_u32(reader).context("Chunk table is truncated")?;
+            reader.seek(SeekFrom::Current(len as i64 + 32))?;
+            Ok(false)
+        }
+        _ => bail!("Chunk table is corrupted; try `extract --salvage`"),
+    }
+}
+
+/// Decodes the zstd-compressed JSON index of format 4 and later.
+pub fn parse_index(reader: impl Read) -> Result<Vec<Entry>> {
+    let index_json = zstd::decode_all(reader).context("Archive index is corrupted")?;
+    serde_json::from_slice(&index_json).context("Archive index is corrupted")
+}
+
+/// Splits the body of a metadata-visible archive into its plaintext index
+/// and the encrypted contents that follow it.
+fn split_visible_index(body: &[u8]) -> Result<(&[u8], &[u8])> {
+    let index_len = read_u64(&mut Cursor::new(body)).context("Archive index is truncated")?;
+    let rest = &body[8..];
+    if (rest.len() as u64) < index_len {
+        bail!("Archive index is truncated");
+    }
+    Ok(rest.split_at(index_len as usize))
+}
+
+/// Reads only the header of the archive at `path`. Returns `None` for
+/// archives written before headers existed.
+pub fn read_header(path: &str) -> Result<Option<Header>> {
+    let mut file = ArchiveFile::open(path)?;
+    let mut magic = [0u8; 4];
+    if file.read_exact(&mut magic).is_err() || &magic != MAGIC {
+        return Ok(None);
+    }
+    read_header_fields(&mut file).map(Some)
+}
+
+/// Writes `header` in place of the header of the archive at `path`, copying
+/// the body over unchanged. The file is replaced atomically.
+pub fn replace_header(path: &str, header: &Header) -> Result<()> {
+    let (_, mut body) = open_body(path)?;
+    let recovery = body.recovery();
+    write_parts(path, header, &mut body, recovery)
+}
+
+/// Encrypts the body of a plaintext archive under the envelope's data key,
+/// without touching the compressed data inside it.
+pub fn encrypt_in_place(path: &str, envelope: &Envelope) -> Result<()> {
+    let (mut header, mut file) = open_body(path)?;
+    if header.encrypted {
+        bail!("Archive is already encrypted");
+    }
+    let mut payload = Vec::new();
+    file.read_to_end(&mut payload)?;
+
+    header.encrypted = true;
+    header.signature = None;
+    header.key_slots = envelope.slots.clone();
+    header.cipher = Some(envelope.cipher);
+    header.seal(envelope);
+    let (nonce, ciphertext) =
+        encrypt::encrypt_with_key(&payload, &envelope.key, envelope.cipher, &header.body_aad());
+    write_parts(
+        path,
+        &header,
+        &mut Cursor::new([nonce, ciphertext].concat()),
+        file.recovery(),
+    )
+}
+
+/// Encrypts an archive from before headers by rewriting it in the current
+/// format. Such an archive may already be encrypted with a password, which
+/// shows only in its payload not reading as a plaintext archive; it is then
+/// left untouched.
+pub fn encrypt_legacy_in_place(path: &str, envelope: Envelope) -> Result<()> {
+    let mut archive = Archive::open(path, &Credentials::default())?;
+    let readable = archive.truncated.is_none()
+        && archive.chunks.iter().all(|chunk| {
+            archive
+                .decompress_chunk(chunk)
+                .is_ok_and(|data| data.len() as u64 == chunk.original_len)
+        });
+    if !readable {
+        bail!(
+            "'{}' has no header and does not read as a plaintext archive; if it is encrypted \
+             with a password, use `ubl rekey` to change its keys or `ubl decrypt` first",
+            path
+        );
+    }
+    archive.envelope = Some(envelope);
+    archive.rewrite(path)
+}
+
+/// Replaces the body of an encrypted archive with its decrypted payload.
+pub fn decrypt_in_place(path: &str, credentials: &Credentials) -> Result<()> {
+    let (mut header, mut file) = open_body(path)?;
+    if !header.encrypted {
+        bail!("Archive is not encrypted");
+    }
+    let mut body = Vec::new();
+    file.read_to_end(&mut body)?;
+
+    let payload = if header.key_slots.is_empty() {
+        let Some(pass) = &credentials.passphrase else {
+            bail!("Archive is encrypted; a password is required");
+        };
+        decrypt_body(&body, pass)?
+    } else {
+        let envelope = Envelope::open(
+            header.key_slots.clone(),
+            header.cipher.unwrap_or_default(),
+            credentials,
+        )?;
+        header.verify(&envelope)?;
+        if header.metadata_visible {
+            // The index moves back to the end of the plaintext payload.
+            let (index, contents) = split_visible_index(&body)?;
+            let aad = [header.body_aad().as_slice(), index].concat();
+            let contents = decrypt_sealed(contents, &envelope, &aad)?;
+            Zeroizing::new([contents.as_slice(), index].concat())
+        } else {
+            decrypt_sealed(&body, &envelope, &header.body_aad())?
+        }
+    };
+    header.encrypted = false;
+    header.signature = None;
+    header.metadata_visible = false;
+    header.key_slots.clear();
+    header.cipher = None;
+    header.header_tag = None;
+    write_parts(path, &header, &mut Cursor::new(payload), file.recovery())
+}
+
+/// The decrypted payload of an archive, read without parsing it so that a
+/// damaged archive can be salvaged.
+pub struct Payload {
+    pub header: Header,
+    pub data: Zeroizing<Vec<u8>>,
+    /// The index of a metadata-visible archive, stored outside the payload.
+    pub visible_index: Option<Vec<u8>>,
+}
+
+/// Reads and decrypts the payload of the archive at `path`. An encrypted
+/// payload is authenticated as a whole, so it only decrypts undamaged.
+pub fn read_payload(path: &str, credentials: &Credentials) -> Result<Payload> {
+    let (header, mut file) = open_body(path)?;
+    let mut body = Vec::new();
+    file.read_to_end(&mut body)
+        .with_context(|| format!("Failed to read archive '{}'", path))?;
+
+    let envelope = if header.encrypted && !header.key_slots.is_empty() {
+        let opened = Envelope::open(
+            header.key_slots.clone(),
+            header.cipher.unwrap_or_default(),
+            credentials,
+        )?;
+        header.verify(&opened)?;
+        Some(opened)
+    } else {
+        None
+    };
+    let (visible_index, body) = if header.encrypted && header.metadata_visible {
+        let (index, rest) = split_visible_index(&body)?;
+        (Some(index), rest)
+    } else {
+        (None, body.as_slice())
+    };
+    let data = decrypt_payload(&header, body, visible_index, envelope.as_ref(), credentials)
+        .context("Encrypted archive is damaged; its contents cannot be salvaged")?;
+    Ok(Payload {
+        visible_index: visible_index.map(<[u8]>::to_vec),
+        header,
+        data,
+    })
+}
+
+/// Entries and chunk statistics of an archive, as reported by `ubl info`.
+pub struct Contents {
+    pub entries: Vec<Entry>,
+    /// Number of unique chunks and their total compressed size. Unknown for
+    /// a metadata-visible archive read without credentials.
+    pub chunks: Option<(usize, u64)>,
+}
+
+/// Reads the index and chunk statistics of the archive at `path`. Plaintext
+/// archives are read by seeking past the chunk data. Returns `None` when the
+/// index is encrypted and no credentials were given.
+pub fn read_contents(path: &str, credentials: &Credentials) -> Result<Option<Contents>> {
+    match read_header(path)? {
+        Some(h) if !h.encrypted && h.version >= 4 => return read_plain_contents(path).map(Some),
+        Some(h) if h.encrypted && !h.metadata_visible && credentials.is_empty() => return Ok(None),
+        _ => {}
+    }
+
+    let archive = Archive::open_index(path, credentials)?;
+    let chunks = (!archive.locked).then(|| {
+        let stored = archive.chunks.iter().map(|c| c.data.len() as u64).sum();
+        (archive.chunks.len(), stored)
+    });
+    Ok(Some(Contents {
+        entries: archive.entries,
+        chunks,
+    }))
+}
+
+fn read_plain_contents(path: &str) -> Result<Contents> {
+    let (header, file) = open_body(path)?;
+    let mut reader = BufReader::new(file);
+    if header.dictionary {
+        let dict_len = read_u32(&mut reader).context("Dictionary is truncated")?;
+        reader.seek_relative(dict_len.into())?;
+    }
+
+    let count = read_u32(&mut reader).context("Chunk table is truncated")?;
+    let mut chunks = 0;
+    let mut stored = 0;
+    for _ in 0..count {
+        if header.version >= 8 && !read_chunk_marker(&mut reader)? {
+            continue;
+        }
+        chunks += 1;
+        // Skip the hash and original length.
+        reader.seek_relative(32 + 8)?;
+        let compressed_len = read_u64(&mut reader).context("Chunk table is truncated")?;
+        reader.seek_relative(compressed_len as i64)?;
+        stored += compressed_len;
+    }
+
+    Ok(Contents {
+        entries: parse_index(reader)?,
+        chunks: Some((chunks, stored)),
+    })
+}
+
+/// Signs the archive at `path` in place, replacing any earlier signature.
+pub fn sign(path: &str, key: &SigningIdentity) -> Result<()> {
+    let (mut header, message) = signed_message(path)?;
+    header.signature = Some(ArchiveSignature {
+        signer: key.public().to_string(),
+        signature: STANDARD.encode(key.sign(&message)),
+    });
+    replace_header(path, &header)
+}
+
+/// Checks the signature of the archive at `path`. Needs no credentials.
+pub fn check_signature(path: &str) -> Result<SignatureStatus> {
+    if read_header(path)?.is_none() {
+        return Ok(SignatureStatus::Unsigned);
+    }
+    let (header, message) = signed_message(path)?;
+    let Some(signature) = header.signature else {
+        return Ok(SignatureStatus::Unsigned);
+    };
+
+    let valid = Signer::parse(&signature.signer).ok().filter(|signer| {
+        STANDARD
+            .decode(&signature.signature)
+            .is_ok_and(|bytes| signer.verify(&message, &bytes))
+    });
+    Ok(match valid {
+        Some(signer) => SignatureStatus::Valid(signer),
+        None => SignatureStatus::Invalid(signature.signer),
+    })
+}
+
+/// The message a signature covers: a digest of the header without its
+/// signature, and of the whole body, which holds the index and chunk hashes.
+fn signed_message(path: &str) -> Result<(Header, Vec<u8>)> {
+    let (header, mut file) = open_body(path)?;
+    let fields = Header {
+        signature: None,
+        ..header.clone()
+    };
+    let mut hasher = Sha256::new();
+    hasher.update(MAGIC);
+    hasher.update(serde_json::to_vec(&fields).unwrap());
+    std::io::copy(&mut file, &mut hasher)
+        .with_context(|| format!("Failed to read archive '{}'", path))?;
+    Ok((header, [SIGNATURE_CONTEXT, &hasher.finalize()].concat()))
+}
+
+/// Opens the archive at `path` and reads its header, leaving the file
+/// positioned at the start of the body.
+fn open_body(path: &str) -> Result<(Header, ArchiveFile)> {
+    let mut file = ArchiveFile::open(path)?;
+    let mut magic = [0u8; 4];
+    if file.read_exact(&mut magic).is_err() || &magic != MAGIC {
+        bail!("'{}' has no archive header", path);
+    }
+    let header = read_header_fields(&mut file)?;
+    Ok((header, file))
+}
+
+/// Atomically replaces the archive at `path` with `header` followed by `body`,
+/// and a fresh recovery record if the archive had one. A split archive keeps
+/// its volume size.
+fn write_parts(
+    path: &str,
+    header: &Header,
+    body: &mut impl Read,
+    recovery: Option<Trailer>,
+) -> Result<()> {
+    let header_json = serde_json::to_vec(header).unwrap();
+    let mut out = MAGIC.to_vec();
+    out.extend(&(header_json.len() as u32).to_le_bytes());
+    out.extend(&header_json);
+    body.read_to_end(&mut out)?;
+    if let Some(trailer) = recovery {
+        recovery::append(&mut out, trailer.percent);
+    }
+    volume::replace(path, &out)
+}
+
+/// Parses the length-prefixed JSON header that follows the magic bytes.
+fn read_header_fields(reader: &mut impl Read) -> Result<Header> {
+    let header_len = read_u32(reader).context("Archive header is truncated")?;
+    let mut header_buf = vec![0u8; header_len as usize];
+    reader
+        .read_exact(&mut header_buf)
+        .context("Archive header is truncated")?;
+    let header: Header =
+        serde_json::from_slice(&header_buf).context("Archive header is corrupted")?;
+    if header.version > FORMAT_VERSION {
+        bail!(
+            "Archive format version {} is newer than this tool supports ({})",
+            header.version,
+            FORMAT_VERSION
+        );
+    }
+    Ok(header)
+}
+
+fn decrypt_body(body: &[u8], password: &Passphrase) -> Result<Zeroizing<Vec<u8>>> {
+    if body.len() < SALT_LEN + NONCE_LEN {
+        bail!("Archive is too small or corrupted");
+    }
+    let salt = &body[..SALT_LEN];
+    let nonce = &body[SALT_LEN..SALT_LEN + NONCE_LEN];
+    let ciphertext = &body[SALT_LEN + NONCE_LEN..];
+
+    // Without key slots, a wrong password cannot be told from damaged data;
+    // it is by far the likelier cause.
+    let key = encrypt::derive_key(password.as_bytes(), salt, &KdfParams::LEGACY)?;
+    encrypt::decrypt_with_key(nonce, ciphertext, &key, Cipher::Aes256Gcm, &[]).map_err(|_| {
+        WrongCredentials("Wrong password for this archive, or the archive is damaged").into()
+    })
+}
+
+/// Decrypts a `nonce ‖ ciphertext` body under the envelope's data key. The
+/// key already unwrapped, so a failure here means the data was altered.
+fn decrypt_sealed(body: &[u8], envelope: &Envelope, aad: &[u8]) -> Result<Zeroizing<Vec<u8>>> {
+    let nonce_len = envelope.cipher.nonce_len();
+    if body.len() < nonce_len {
+        bail!("Archive is too small or corrupted");
+    }
+    let (nonce, ciphertext) = body.split_at(nonce_len);
+    encrypt::decrypt_with_key(nonce, ciphertext, &envelope.key, envelope.cipher, aad)
+        .ok()
+        .with_context(|| {
+            if aad.is_empty() {
+                "Archive data is corrupted, or its header was tampered with"
+            } else {
+                "Archive data is corrupted"
+            }
+        })
+}
+
+/// Decrypts the payload of an archive body, whose visible index (if any) has
+/// already been split off. A plaintext body is the payload.
+fn decrypt_payload(
+    header: &Header,
+    body: &[u8],
+    visible_index: Option<&[u8]>,
+    envelope: Option<&Envelope>,
+    credentials: &Credentials,
+) -> Result<Zeroizing<Vec<u8>>> {
+    match envelope {
+        _ if !header.encrypted => Ok(Zeroizing::new(body.to_vec())),
+        Some(opened) => {
+            let mut aad = header.body_aad();
+            aad.extend(visible_index.unwrap_or_default());
+            decrypt_sealed(body, opened, &aad)
+        }
+        None => {
+            let Some(pass) = &credentials.passphrase else {
+                bail!("Archive is encrypted; a password is required");
+            };
+            decrypt_body(body, pass)
+        }
+    }
+}
+
+fn read_u32(reader: &mut impl Read) -> std::io::Result<u32> {
+    let mut buf = [0u8; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_u64(reader: &mut impl Read) -> std::io::Result<u64> {
+    let mut buf = [0u8; 8];
+    reader.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+fn read_path(reader: &mut impl Read) -> Option<String> {
+    let path_len = read_u32(reader).ok()?;
+    let mut path_buf = vec![0u8; path_len as usize];
+    reader.read_exact(&mut path_buf).ok()?;
+    Some(String::from_utf8(path_buf).unwrap_or_else(|_| "<invalid utf8>".into()))
+}
+
+/// Splits data into content-defined chunks. Empty data still yields one
+/// (empty) chunk so every file decodes from at least one zstd frame.
+pub fn split_chunks(data: &[u8]) -> Vec<&[u8]> {
+    let mut pieces: Vec<&[u8]> = FastCDC::new(data, CHUNK_MIN, CHUNK_AVG, CHUNK_MAX)
+        .map(|cut| &data[cut.offset..cut.offset + cut.length])
+        .collect();
+    if pieces.is_empty() {
+        pieces.push(data);
+    }
+    pieces
+}
+
+/// Modification time of a file in whole seconds since the Unix epoch.
+pub fn file_mtime(path: &Path) -> Option<u64> {
+    let modified = fs::metadata(path).ok()?.modified().ok()?;
+    Some(modified.duration_since(UNIX_EPOCH).ok()?.as_secs())
+}
+
+/// Records the permission bits and owner of the file at `path` in `entry`.
+/// Other platforms have neither, so nothing is recorded there.
+pub fn record_permissions(entry: &mut Entry, path: &Path) {
+    #[cfg(unix)]
+    if let Ok(meta) = fs::metadata(path) {
+        use std::os::unix::fs::MetadataExt;
+        entry.mode = Some(meta.mode() & 0o7777);
+        entry.owner = Some(Owner {
+            uid: meta.uid(),
+            gid: meta.gid(),
+        });
+    }
+    #[cfg(not(unix))]
+    let _ = (entry, path);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys::{Identity, Passphrase};
+    use crate::test_util::{archive_path, noise, read_entry, sample_archive};
+
+    /// Opens an archive from its serialized form.
+    fn open_bytes(bytes: &[u8], credentials: &Credentials) -> Result<Archive> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = archive_path(&dir, "a.ubl");
+        fs::write(&path, bytes).unwrap();
+        Archive::open(&path, credentials)
+    }
+
+    /// A dictionary trained on records that share most of their bytes.
+    fn trained_dictionary() -> Vec<u8> {
+        let samples: Vec<Vec<u8>> = (0..200)
+            .map(|i| {
+                format!(r#"{{"id": {}, "name": "user {}", "active": true}}"#, i, i).into_bytes()
+            })
+            .collect();
+        zstd::dict::from_samples(&samples, 4096).unwrap()
+    }
+
+    #[test]
+    fn dictionary_is_stored_and_used_for_every_entry() {
+        let dictionary = trained_dictionary();
+        let mut archive = Archive::new(Some(dictionary.clone()));
+        archive.insert(
+            "a.json".into(),
+            br#"{"id": 7, "name": "user 7", "active": true}"#,
+            None,
+        );
+        archive.insert(
+            "b.json".into(),
+            br#"{"id": 8, "name": "user 8", "active": false}"#,
+            None,
+        );
+
+        let archive = open_bytes(&archive.to_bytes().unwrap(), &Credentials::default()).unwrap();
+        assert_eq!(archive.dictionary, Some(dictionary));
+        assert_eq!(
+            read_entry(&archive, "a.json"),
+            br#"{"id": 7, "name": "user 7", "active": true}"#
+        );
+        assert_eq!(
+            read_entry(&archive, "b.json"),
+            br#"{"id": 8, "name": "user 8", "active": false}"#
+        );
+    }
+
+    #[test]
+    fn entries_need_the_dictionary_they_were_compressed_with() {
+        let mut archive = Archive::new(Some(trained_dictionary()));
+        archive.insert(
+            "a.json".into(),
+            br#"{"id": 7, "name": "user 7", "active": true}"#,
+            None,
+        );
+        archive.dictionary = None;
+
+        let entry = &archive.entries[0];
+        let mut data = Vec::new();
+        let decoded = archive
+            .decoder(entry)
+            .and_then(|mut decoder| decoder.read_to_end(&mut data));
+        assert!(decoded.is_err());
+    }
+
+    fn roundtrip(archive: &Archive) -> Archive {
+        open_bytes(&archive.to_bytes().unwrap(), &Credentials::default()).unwrap()
+    }
+
+    #[test]
+    fn plaintext_archive_roundtrips() {
+        let archive = roundtrip(&sample_archive());
+        let paths: Vec<_> = archive.entries.iter().map(|e| e.path.as_str()).collect();
+        assert_eq!(paths, ["a.txt", "b/copy.txt", "big.bin", "empty"]);
+        assert_eq!(read_entry(&archive, "a.txt"), b"hello");
+        assert_eq!(read_entry(&archive, "b/copy.txt"), b"hello");
+        assert_eq!(read_entry(&archive, "big.bin"), noise(200 * 1024, 1));
+        assert_eq!(read_entry(&archive, "empty"), b"");
+    }
+
+    #[test]
+    fn identical_chunks_are_stored_once() {
+        let archive = roundtrip(&sample_archive());
+        let big = archive
+            .entries
+            .iter()
+            .find(|e| e.path == "big.bin")
+            .unwrap();
+        assert!(big.chunks.len() > 1);
+        assert_eq!(archive.entries[0].chunks, archive.entries[1].chunks);
+        assert_eq!(archive.chunks.len(), big.chunks.len() + 2);
+    }
+
+    #[test]
+    fn removed_entries_leave_no_chunks_behind() {
+        let mut archive = sample_archive();
+        archive.remove("big.bin");
+
+        let archive = roundtrip(&archive);
+        assert_eq!(archive.chunks.len(), 2);
+        assert_eq!(read_entry(&archive, "b/copy.txt"), b"hello");
+    }
+
+    fn identity_credentials(identities: Vec<Identity>) -> Credentials {
+        Credentials {
+            identities,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn every_recipient_can_open_the_archive() {
+        let (alice, bob) = (Identity::generate(), Identity::generate());
+        let mut archive = sample_archive();
+        archive.envelope = Envelope::seal(
+            Cipher::default(),
+            None,
+            &KdfParams::default(),
+            &[alice.public(), bob.public()],
+        )
+        .unwrap();
+        let bytes = archive.to_bytes().unwrap();
+
+        for identity in [alice, bob] {
+            let archive = open_bytes(&bytes, &identity_credentials(vec![identity])).unwrap();
+            assert_eq!(read_entry(&archive, "a.txt"), b"hello");
+            assert_eq!(read_entry(&archive, "big.bin"), noise(200 * 1024, 1));
+        }
+    }
+
+    #[test]
+    fn encrypted_archive_needs_a_matching_identity() {
+        let mut archive = sample_archive();
+        archive.envelope = Envelope::seal(
+            Cipher::default(),
+            None,
+            &KdfParams::default(),
+            &[Identity::generate().public()],
+        )
+        .unwrap();
+        let bytes = archive.to_bytes().unwrap();
+
+        let err = open_bytes(&bytes, &Credentials::default()).err().unwrap();
+        assert!(err.to_string().contains("encrypted"), "{}", err);
+        let stranger = identity_credentials(vec![Identity::generate()]);
+        assert!(open_bytes(&bytes, &stranger).is_err());
+    }
+
+    #[test]
+    fn xchacha_archive_roundtrips() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = archive_path(&dir, "a.ubl");
+        let identity = Identity::generate();
+        let mut archive = sample_archive();
+        archive.envelope = Envelope::seal(
+            Cipher::XChaCha20Poly1305,
+            None,
+            &KdfParams::default(),
+            &[identity.public()],
+        )
+        .unwrap();
+        archive.write(&path).unwrap();
+
+        let header = read_header(&path).unwrap().unwrap();
+        assert_eq!(header.cipher, Some(Cipher::XChaCha20Poly1305));
+        let archive = Archive::open(&path, &identity_credentials(vec![identity])).unwrap();
+        assert_eq!(read_entry(&archive, "a.txt"), b"hello");
+        assert_eq!(read_entry(&archive, "big.bin"), noise(200 * 1024, 1));
+    }
+
+    #[test]
+    fn tampered_encrypted_body_is_rejected() {
+        let identity = Identity::generate();
+        let mut archive = sample_archive();
+        archive.envelope = Envelope::seal(
+            Cipher::default(),
+            None,
+            &KdfParams::default(),
+            &[identity.public()],
+        )
+        .unwrap();
+        let mut bytes = archive.to_bytes().unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+
+        assert!(open_bytes(&bytes, &identity_credentials(vec![identity])).is_err());
+    }
+
+    #[test]
+    fn header_tag_covers_the_header_fields() {
+        let seal = || {
+            let identity = Identity::generate();
+            Envelope::seal(
+                Cipher::default(),
+                None,
+                &KdfParams::default(),
+                &[identity.public()],
+            )
+            .unwrap()
+            .unwrap()
+        };
+        let envelope = seal();
+        let bytes = Archive::new(None).to_bytes().unwrap();
+        let mut header = read_header_fields(&mut &bytes[MAGIC.len()..]).unwrap();
+        header.seal(&envelope);
+        header.verify(&envelope).unwrap();
+
+        assert!(header.verify(&seal()).is_err());
+        header.dictionary = true;
+        let err = header.verify(&envelope).err().unwrap();
+        assert!(err.to_string().contains("tampered"), "{}", err);
+    }
+
+    #[test]
+    fn header_tag_is_required_from_the_format_that_always_has_one() {
+        let envelope = Envelope::seal(
+            Cipher::default(),
+            None,
+            &KdfParams::default(),
+            &[Identity::generate().public()],
+        )
+        .unwrap()
+        .unwrap();
+        let bytes = Archive::new(None).to_bytes().unwrap();
+        let mut header = read_header_fields(&mut &bytes[MAGIC.len()..]).unwrap();
+        header.encrypted = true;
+        header.key_slots = envelope.slots.clone();
+
+        let err = header.verify(&envelope).err().unwrap();
+        assert!(err.to_string().contains("missing"), "{}", err);
+        header.version = HEADER_TAG_VERSION - 1;
+        header.verify(&envelope).unwrap();
+    }
+
+    #[test]
+    fn tampered_header_is_rejected_on_open() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = archive_path(&dir, "a.ubl");
+        let identity = Identity::generate();
+        let mut archive = sample_archive();
+        archive.envelope = Envelope::seal(
+            Cipher::default(),
+            None,
+            &KdfParams::default(),
+            &[identity.public()],
+        )
+        .unwrap();
+        archive.write(&path).unwrap();
+
+        let mut header = read_header(&path).unwrap().unwrap();
+        header.id = Some("someone else's".into());
+        replace_header(&path, &header).unwrap();
+
+        let err = Archive::open(&path, &identity_credentials(vec![identity]))
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("tampered"), "{}", err);
+    }
+
+    #[test]
+    fn visible_index_lists_entries_without_credentials() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = archive_path(&dir, "a.ubl");
+        let identity = Identity::generate();
+        let mut archive = sample_archive();
+        archive.envelope = Envelope::seal(
+            Cipher::default(),
+            None,
+            &KdfParams::default(),
+            &[identity.public()],
+        )
+        .unwrap();
+        archive.metadata_visible = true;
+        archive.write(&path).unwrap();
+
+        let locked = Archive::open_index(&path, &Credentials::default()).unwrap();
+        assert!(locked.locked && locked.chunks.is_empty());
+        let paths: Vec<_> = locked.entries.iter().map(|e| e.path.as_str()).collect();
+        assert_eq!(paths, ["a.txt", "b/copy.txt", "big.bin", "empty"]);
+        assert!(Archive::open(&path, &Credentials::default()).is_err());
+
+        let archive = Archive::open_index(&path, &identity_credentials(vec![identity])).unwrap();
+        assert!(!archive.locked && archive.metadata_visible);
+        assert_eq!(read_entry(&archive, "big.bin"), noise(200 * 1024, 1));
+    }
+
+    #[test]
+    fn archive_with_recovery_record_roundtrips() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = archive_path(&dir, "a.ubl");
+        let mut archive = sample_archive();
+        archive.recovery = Some(10);
+        archive.write(&path).unwrap();
+
+        let archive = Archive::open(&path, &Credentials::default()).unwrap();
+        assert_eq!(archive.recovery, Some(10));
+        assert_eq!(read_entry(&archive, "big.bin"), noise(200 * 1024, 1));
+    }
+
+    fn signed_archive(dir: &tempfile::TempDir, key: &SigningIdentity) -> String {
+        let path = archive_path(dir, "a.ubl");
+        sample_archive().write(&path).unwrap();
+        sign(&path, key).unwrap();
+        path
+    }
+
+    #[test]
+    fn signature_of_an_untouched_archive_is_valid() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = SigningIdentity::generate();
+        let path = signed_archive(&dir, &key);
+
+        match check_signature(&path).unwrap() {
+            SignatureStatus::Valid(signer) => {
+                assert_eq!(signer.to_string(), key.public().to_string())
+            }
+            _ => panic!("signature should be valid"),
+        }
+    }
+
+    #[test]
+    fn signature_does_not_match_a_tampered_body() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = SigningIdentity::generate();
+        let path = signed_archive(&dir, &key);
+        let mut bytes = fs::read(&path).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        fs::write(&path, bytes).unwrap();
+
+        assert!(matches!(
+            check_signature(&path).unwrap(),
+            SignatureStatus::Invalid(signer) if signer == key.public().to_string()
+        ));
+    }
+
+    #[test]
+    fn signature_does_not_match_a_tampered_header() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = signed_archive(&dir, &SigningIdentity::generate());
+        let mut header = read_header(&path).unwrap().unwrap();
+        header.id = Some("someone else's".into());
+        replace_header(&path, &header).unwrap();
+
+        assert!(matches!(
+            check_signature(&path).unwrap(),
+            SignatureStatus::Invalid(_)
+        ));
+    }
+
+    #[test]
+    fn archive_without_signature_is_unsigned() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = archive_path(&dir, "a.ubl");
+        sample_archive().write(&path).unwrap();
+
+        assert!(matches!(
+            check_signature(&path).unwrap(),
+            SignatureStatus::Unsigned
+        ));
+    }
+
+    /// `ubl compress` of a directory holding `a.txt`, encrypted with the
+    /// password `pw` by a release from before archives had a header.
+    const BASELINE_ARCHIVE: &str = "a1695b0648f1f02ab8c32fe564f8164667b574466f83a1f3428e4b44231ee0f61aee6037204cae8a98cfe0f6549c9977fc177a7136c78989ee70dedc37f56e207348fc5631babb4e1a95ac2c0ba75fdc243612b264fe819f73e1bf298e92bf914f4f9ab9ee";
+
+    #[test]
+    fn baseline_archive_decrypts_with_the_legacy_kdf() {
+        let credentials = Credentials {
+            passphrase: Passphrase::new(Some(String::from("pw").into()), None),
+            ..Default::default()
+        };
+        let bytes = hex::decode(BASELINE_ARCHIVE).unwrap();
+
+        let archive = open_bytes(&bytes, &credentials).unwrap();
+        assert_eq!(read_entry(&archive, "a.txt"), b"hello from the baseline");
+    }
+
+    /// An archive in the format from before headers: each file inline as
+    /// its path, sizes and one zstd frame.
+    fn legacy_archive(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for (path, data) in files {
+            let compressed = zstd::encode_all(*data, 3).unwrap();
+            bytes.extend(&(path.len() as u32).to_le_bytes());
+            bytes.extend(path.as_bytes());
+            bytes.extend(&(data.len() as u64).to_le_bytes());
+            bytes.extend(&(compressed.len() as u64).to_le_bytes());
+            bytes.extend(&compressed);
+        }
+        bytes
+    }
+
+    #[test]
+    fn wrong_password_for_a_baseline_archive_is_wrong_credentials() {
+        let credentials = Credentials {
+            passphrase: Passphrase::new(Some(String::from("guess").into()), None),
+            ..Default::default()
+        };
+        let bytes = hex::decode(BASELINE_ARCHIVE).unwrap();
+
+        let err = open_bytes(&bytes, &credentials).err().unwrap();
+        assert!(err.is::<WrongCredentials>(), "{:#}", err);
+    }
+
+    #[test]
+    fn legacy_plaintext_archive_is_encrypted_in_the_current_format() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = archive_path(&dir, "a.ubl");
+        fs::write(&path, legacy_archive(&[("a.txt", b"hello")])).unwrap();
+        let identity = Identity::generate();
+        let envelope = Envelope::seal(
+            Cipher::default(),
+            None,
+            &KdfParams::default(),
+            &[identity.public()],
+        )
+        .unwrap()
+        .unwrap();
+
+        encrypt_legacy_in_place(&path, envelope).unwrap();
+        assert!(read_header(&path).unwrap().unwrap().encrypted);
+        let archive = Archive::open(&path, &identity_credentials(vec![identity])).unwrap();
+        assert_eq!(read_entry(&archive, "a.txt"), b"hello");
+    }
+
+    #[test]
+    fn legacy_password_archive_is_not_encrypted_again() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = archive_path(&dir, "a.ubl");
+        let bytes = hex::decode(BASELINE_ARCHIVE).unwrap();
+        fs::write(&path, &bytes).unwrap();
+        let envelope = Envelope::seal(
+            Cipher::default(),
+            None,
+            &KdfParams::default(),
+            &[Identity::generate().public()],
+        )
+        .unwrap()
+        .unwrap();
+
+        let err = encrypt_legacy_in_place(&path, envelope).err().unwrap();
+        assert!(err.to_string().contains("ubl rekey"), "{}", err);
+        assert_eq!(fs::read(&path).unwrap(), bytes);
+    }
+
+    #[test]
+    fn encrypting_and_decrypting_in_place_keeps_the_contents() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = archive_path(&dir, "a.ubl");
+        sample_archive().write(&path).unwrap();
+        let identity = Identity::generate();
+        let envelope = Envelope::seal(
+            Cipher::default(),
+            None,
+            &KdfParams::default(),
+            &[identity.public()],
+        )
+        .unwrap()
+        .unwrap();
+
+        encrypt_in_place(&path, &envelope).unwrap();
+        assert!(read_header(&path).unwrap().unwrap().encrypted);
+        assert!(encrypt_in_place(&path, &envelope).is_err());
+        assert!(Archive::open(&path, &Credentials::default()).is_err());
+        let credentials = identity_credentials(vec![identity]);
+        let archive = Archive::open(&path, &credentials).unwrap();
+        assert_eq!(read_entry(&archive, "big.bin"), noise(200 * 1024, 1));
+
+        decrypt_in_place(&path, &credentials).unwrap();
+        assert!(decrypt_in_place(&path, &credentials).is_err());
+        let archive = Archive::open(&path, &Credentials::default()).unwrap();
+        assert!(!archive.encrypted);
+        assert_eq!(read_entry(&archive, "a.txt"), b"hello");
+        assert_eq!(read_entry(&archive, "big.bin"), noise(200 * 1024, 1));
+    }
+
+    #[test]
+    fn rewriting_keeps_the_creation_time() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = archive_path(&dir, "a.ubl");
+        let mut archive = sample_archive();
+        archive.created = Some(1_600_000_000);
+        archive.write(&path).unwrap();
+
+        let mut archive = Archive::open(&path, &Credentials::default()).unwrap();
+        archive.insert("c.txt".into(), b"more", None);
+        archive.rewrite(&path).unwrap();
+        assert_eq!(
+            read_header(&path).unwrap().unwrap().created,
+            Some(1_600_000_000)
+        );
+
+        let identity = Identity::generate();
+        let envelope = Envelope::seal(
+            Cipher::default(),
+            None,
+            &KdfParams::default(),
+            &[identity.public()],
+        )
+        .unwrap()
+        .unwrap();
+        encrypt_in_place(&path, &envelope).unwrap();
+        assert_eq!(
+            read_header(&path).unwrap().unwrap().created,
+            Some(1_600_000_000)
+        );
+    }
+}
@@ -0,0 +1,196 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+
+use crate::archive::{Archive, Entry};
+use crate::keys::Credentials;
+
+/// Guards against base links that loop back on themselves.
+const MAX_CHAIN_LEN: usize = 256;
+
+/// An archive together with every base archive it was taken against,
+/// newest first. A full archive is a chain of one.
+pub struct Chain {
+    pub archives: Vec<Archive>,
+}
+
+impl Chain {
+    /// Opens `path` and follows its base links, checking each base's ID.
+    /// Every archive in the chain is decrypted with the same credentials.
+    pub fn open(path: &str, credentials: &Credentials) -> Result<Self> {
+        let mut archives = vec![Archive::open(path, credentials)?];
+        let mut current = PathBuf::from(path);
+
+        while let Some(base) = archives.last().unwrap().base.clone() {
+            if archives.len() >= MAX_CHAIN_LEN {
+                bail!("Backup chain is longer than {} archives", MAX_CHAIN_LEN);
+            }
+
+            let dir = current.parent().unwrap_or(Path::new(""));
+            let base_path = dir.join(&base.path);
+            let archive =
+                Archive::open(&base_path.to_string_lossy(), credentials).with_context(|| {
+                    format!(
+                        "Base archive '{}' (id {}) is required",
+                        base_path.display(),
+                        base.id
+                    )
+                })?;
+
+            if archive.id.as_deref() != Some(base.id.as_str()) {
+                bail!(
+                    "'{}' is not the archive this backup was taken against (expected id {}, found {})",
+                    base_path.display(),
+                    base.id,
+                    archive.id.as_deref().unwrap_or("none")
+                );
+            }
+            if let Some(entry) = &archive.truncated {
+                bail!(
+                    "Base archive '{}' is truncated at entry '{}'",
+                    base_path.display(),
+                    entry
+                );
+            }
+
+            archives.push(archive);
+            current = base_path;
+        }
+
+        Ok(Chain { archives })
+    }
+
+    /// Returns the tree as of the newest archive: every live path, sorted,
+    /// with the archive holding its latest version.
+    pub fn entries(&self) -> Vec<(&Archive, &Entry)> {
+        let mut tree: BTreeMap<&str, (&Archive, &Entry)> = BTreeMap::new();
+        for archive in self.archives.iter().rev() {
+            for entry in &archive.entries {
+                if entry.is_deleted() {
+                    tree.remove(entry.path.as_str());
+                } else {
+                    tree.insert(&entry.path, (archive, entry));
+                }
+            }
+        }
+        tree.into_values().collect()
+    }
+}
+
+/// Path under which an incremental written to `output` records its `base`:
+/// the bare file name when both live in the same directory, otherwise the
+/// absolute path of the base.
+pub fn base_link(output: &str, base: &str) -> String {
+    let base_path = Path::new(base);
+    // A split base has no file under its own name, only its directory.
+    let canonical = fs::canonicalize(base_path)
+        .or_else(|_| {
+            let name = base_path.file_name().unwrap_or_default();
+            fs::canonicalize(parent_dir(base_path)).map(|dir| dir.join(name))
+        })
+        .unwrap_or_else(|_| base_path.to_path_buf());
+
+    let same_dir = fs::canonicalize(parent_dir(Path::new(output)))
+        .ok()
+        .is_some_and(|dir| canonical.parent() == Some(dir.as_path()));
+
+    match canonical.file_name() {
+        Some(name) if same_dir => name.to_string_lossy().to_string(),
+        _ => canonical.to_string_lossy().to_string(),
+    }
+}
+
+/// Directory containing `path`, as a path that can be opened.
+fn parent_dir(path: &Path) -> &Path {
+    path.parent()
+        .filter(|p| !p.as_os_str().is_empty())
+        .unwrap_or(Path::new("."))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archive::BaseRef;
+    use crate::test_util::{archive_path, read_entry};
+
+    /// Writes a full backup and an incremental of it that changes `b.txt`,
+    /// deletes `c.txt` and adds `d.txt`. Returns the incremental's path.
+    fn write_chain(dir: &tempfile::TempDir) -> String {
+        let mut base = Archive::new(None);
+        base.insert("a.txt".into(), b"one", None);
+        base.insert("b.txt".into(), b"two", None);
+        base.insert("c.txt".into(), b"three", None);
+        base.write(&archive_path(dir, "base.ubl")).unwrap();
+
+        let mut incremental = Archive::new(None);
+        incremental.base = Some(BaseRef {
+            id: base.id.clone().unwrap(),
+            path: "base.ubl".into(),
+        });
+        incremental.insert("b.txt".into(), b"two, changed", None);
+        incremental.insert_tombstone("c.txt".into());
+        incremental.insert("d.txt".into(), b"four", None);
+        let path = archive_path(dir, "incremental.ubl");
+        incremental.write(&path).unwrap();
+        path
+    }
+
+    #[test]
+    fn incremental_is_reconstructed_over_its_base() {
+        let dir = tempfile::tempdir().unwrap();
+        let chain = Chain::open(&write_chain(&dir), &Credentials::default()).unwrap();
+        assert_eq!(chain.archives.len(), 2);
+
+        let tree: Vec<_> = chain
+            .entries()
+            .into_iter()
+            .map(|(archive, entry)| (entry.path.clone(), read_entry(archive, &entry.path)))
+            .collect();
+        assert_eq!(
+            tree,
+            [
+                ("a.txt".to_string(), b"one".to_vec()),
+                ("b.txt".to_string(), b"two, changed".to_vec()),
+                ("d.txt".to_string(), b"four".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn tombstone_is_recorded_in_the_incremental() {
+        let dir = tempfile::tempdir().unwrap();
+        let incremental = Archive::open(&write_chain(&dir), &Credentials::default()).unwrap();
+        let deleted: Vec<_> = incremental
+            .entries
+            .iter()
+            .filter(|e| e.is_deleted())
+            .map(|e| e.path.as_str())
+            .collect();
+        assert_eq!(deleted, ["c.txt"]);
+    }
+
+    #[test]
+    fn replaced_base_is_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = write_chain(&dir);
+        let base_path = archive_path(&dir, "base.ubl");
+        let mut base = Archive::open(&base_path, &Credentials::default()).unwrap();
+        base.renew_id();
+        base.write(&base_path).unwrap();
+
+        let err = Chain::open(&path, &Credentials::default()).err().unwrap();
+        assert!(err.to_string().contains("not the archive"), "{}", err);
+    }
+
+    #[test]
+    fn missing_base_is_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = write_chain(&dir);
+        fs::remove_file(archive_path(&dir, "base.ubl")).unwrap();
+
+        let err = Chain::open(&path, &Credentials::default()).err().unwrap();
+        assert!(err.to_string().contains("is required"), "{}", err);
+    }
+}
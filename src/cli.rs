@@ -1,4 +1,8 @@
-use clap::{Parser, Subcommand};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use zeroize::Zeroizing;
+
+use crate::encrypt::Cipher;
+use crate::output::OutputFormat;
 
 #[derive(Parser)]
 #[command(
@@ -22,6 +26,10 @@ use clap::{Parser, Subcommand};
     "#
 )]
 pub struct Cli {
+    /// Format of the results: `json` and `ndjson` are meant for scripts.
+    /// Can be given before or after the subcommand.
+    #[arg(long, global = true, value_enum, default_value_t = OutputFormat::Text)]
+    pub format: OutputFormat,
     #[command(subcommand)]
     pub command: Commands,
 }
@@ -33,41 +41,199 @@ pub enum Commands {
     /// This command takes an input path (file or directory) and an output
     /// archive path. You can optionally protect the archive with a password.
     Compress {
-        /// The input file or directory to compress.
+        /// The input file or directory to compress, or `-` for stdin.
         input: String,
-        /// The path for the output .ubl archive.
+        /// The path for the output .ubl archive, or `-` for stdout. Output to
+        /// stdout starts once the whole archive is built in memory.
         output: String,
-        /// Optional: Password to encrypt the archive.
-        #[arg(short, long)]
-        password: Option<String>,
+        #[command(flatten)]
+        password: PasswordArgs,
+        /// Optional: Public key (or file of public keys) to encrypt the archive to.
+        /// Can be given multiple times; any one recipient's identity, or the
+        /// password, can then decrypt it.
+        #[arg(short, long, value_name = "PUBKEY")]
+        recipient: Vec<String>,
+        #[command(flatten)]
+        kdf: KdfArgs,
+        /// Cipher for the encrypted archive. XChaCha20-Poly1305 is faster on
+        /// CPUs without AES instructions and uses larger random nonces.
+        #[arg(long, value_enum, default_value_t = Cipher::Aes256Gcm)]
+        cipher: Cipher,
+        /// Keep file names, sizes and hashes readable without the password,
+        /// so `ubl list` works for auditors. Only file contents are encrypted.
+        #[arg(long)]
+        metadata_visible: bool,
+        /// Optional: Identity file to decrypt the base archive given with `--since`.
+        #[arg(short, long, value_name = "KEYFILE")]
+        identity: Vec<String>,
+        /// Train a zstd dictionary from the input files and embed it in the archive.
+        /// Greatly improves the ratio for many small, similar files (logs, configs).
+        #[arg(long)]
+        train_dict: bool,
+        /// Create an incremental backup against an existing archive: only files
+        /// whose size, mtime or contents changed are stored, and deletions are
+        /// recorded. The base archive must use the same password.
+        #[arg(long, value_name = "BASE")]
+        since: Option<String>,
+        /// Optional: Entry name for data read from stdin (default: `stdin`).
+        #[arg(long)]
+        name: Option<String>,
+        /// Optional: Split the archive into volumes of at most this size
+        /// (e.g. `700M`, `4G`), written as `output.001`, `output.002`, ...
+        #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+        volume_size: Option<u64>,
+        /// Optional: Append a recovery record with this much parity (e.g.
+        /// `5%`), so that damaged parts can be rebuilt with `repair`.
+        #[arg(long, value_name = "PERCENT", value_parser = parse_percent)]
+        recovery: Option<u8>,
+    },
+    /// Converts between .ubl archives and tar or zip files.
+    ///
+    /// The formats are taken from the file extensions (.ubl, .tar, .tar.gz,
+    /// .tgz, .tar.zst, .tzst, .zip), and one side must be a .ubl archive.
+    /// Modification times, permissions and symlinks are carried over; tar
+    /// hard links become copies sharing the same chunks. Entries are streamed
+    /// without extracting anything to disk.
+    Convert {
+        /// The archive to read.
+        input: String,
+        /// The archive to write.
+        output: String,
+        #[command(flatten)]
+        password: PasswordArgs,
+        /// Optional: Identity file (X25519 secret key) to decrypt a .ubl input.
+        /// Can be given multiple times.
+        #[arg(short, long, value_name = "KEYFILE")]
+        identity: Vec<String>,
+        /// Optional: Public key (or file of public keys) to encrypt a .ubl
+        /// output to. Can be given multiple times.
+        #[arg(short, long, value_name = "PUBKEY")]
+        recipient: Vec<String>,
+        #[command(flatten)]
+        kdf: KdfArgs,
+        /// Cipher for an encrypted .ubl output.
+        #[arg(long, value_enum, default_value_t = Cipher::Aes256Gcm)]
+        cipher: Cipher,
     },
     /// Extracts contents from a .ubl archive.
     ///
     /// By default, it extracts to a new directory named after the archive
     /// (without the extension). You can specify a custom output directory.
+    /// Incremental archives are restored together with their base archives.
     /// If the archive is password-protected, you must provide the password.
     Extract {
         /// The path to the .ubl archive to extract.
         archive: String,
-        /// Optional: Password to decrypt the archive.
-        #[arg(short, long)]
-        password: Option<String>,
+        #[command(flatten)]
+        password: PasswordArgs,
+        /// Optional: Identity file (X25519 secret key) to decrypt the archive.
+        /// Can be given multiple times.
+        #[arg(short, long, value_name = "KEYFILE")]
+        identity: Vec<String>,
         /// Optional: Directory where the archive contents will be extracted.
         /// If not provided, it defaults to a folder named after the archive.
         #[arg(short, long)]
         output: Option<String>,
+        /// Optional: Restore every readable file of a damaged archive instead
+        /// of stopping at the first error, and write a report of what was
+        /// lost next to the output directory.
+        #[arg(long)]
+        salvage: bool,
+    },
+    /// Writes the contents of files in a .ubl archive to standard output.
+    ///
+    /// Only the requested entries are decompressed. With several paths, their
+    /// contents are written one after another, like `cat`.
+    Cat {
+        /// The path to the .ubl archive.
+        archive: String,
+        /// Paths of the files inside the archive.
+        #[arg(required = true)]
+        paths: Vec<String>,
+        #[command(flatten)]
+        password: PasswordArgs,
+        /// Optional: Identity file (X25519 secret key) to decrypt the archive.
+        /// Can be given multiple times.
+        #[arg(short, long, value_name = "KEYFILE")]
+        identity: Vec<String>,
+        /// Optional: Skip this many bytes at the start of each file.
+        #[arg(long, default_value_t = 0)]
+        offset: u64,
+        /// Optional: Write at most this many bytes of each file.
+        #[arg(long)]
+        length: Option<u64>,
+    },
+    /// Shows what changed between two archives, or an archive and a directory.
+    ///
+    /// Files are reported as added, removed, modified (different contents) or
+    /// touched (same contents, different modification time). Incremental
+    /// archives are compared as the full tree they restore to.
+    Diff {
+        /// The older .ubl archive.
+        old: String,
+        /// The newer .ubl archive, or a directory.
+        new: String,
+        #[command(flatten)]
+        password: PasswordArgs,
+        /// Optional: Identity file (X25519 secret key) to decrypt the archives.
+        /// Can be given multiple times.
+        #[arg(short, long, value_name = "KEYFILE")]
+        identity: Vec<String>,
+        /// Show a unified diff for modified text files.
+        #[arg(short, long)]
+        unified: bool,
+        /// Same as `--format json`.
+        #[arg(long)]
+        json: bool,
+    },
+    /// Shows a summary of a .ubl archive.
+    ///
+    /// Prints the format version, creation time and tool, compression,
+    /// encryption and key slots with their KDF parameters, signature, and
+    /// entry counts and sizes. Only the header and index are read. Entry
+    /// statistics of a fully encrypted archive need the password.
+    Info {
+        /// The path to the .ubl archive.
+        archive: String,
+        #[command(flatten)]
+        password: PasswordArgs,
+        /// Optional: Identity file (X25519 secret key) to decrypt the archive.
+        /// Can be given multiple times.
+        #[arg(short, long, value_name = "KEYFILE")]
+        identity: Vec<String>,
+        /// Same as `--format json`.
+        #[arg(long)]
+        json: bool,
     },
     /// Lists the contents of a .ubl archive.
     ///
     /// This command displays the names of the files and directories stored
     /// within the specified archive without extracting them.
-    /// If the archive is password-protected, the password is required.
+    /// If the archive is password-protected, the password is required,
+    /// unless it was created with `--metadata-visible`.
     List {
         /// The path to the .ubl archive to list.
         archive: String,
-        /// Optional: Password to decrypt the archive for listing contents.
+        /// Optional: Only list files matching these glob patterns, such as
+        /// `'*.txt'` or `'docs/**'`. A pattern naming a directory lists
+        /// everything below it.
+        patterns: Vec<String>,
+        #[command(flatten)]
+        password: PasswordArgs,
+        /// Optional: Identity file (X25519 secret key) to decrypt the archive.
+        /// Can be given multiple times.
+        #[arg(short, long, value_name = "KEYFILE")]
+        identity: Vec<String>,
+        /// Optional: Also show mode, owner, modification time, compression
+        /// ratio, method and SHA-256 of each file.
+        #[arg(short, long, conflicts_with = "tree")]
+        long: bool,
+        /// Optional: Show files as a directory tree, with totals per directory.
         #[arg(short, long)]
-        password: Option<String>,
+        tree: bool,
+        /// Optional: Order files by this key instead of archive order.
+        #[arg(short, long, value_enum)]
+        sort: Option<SortKey>,
     },
     /// Updates an existing .ubl archive by adding, removing, or replacing files.
     ///
@@ -86,29 +252,348 @@ pub enum Commands {
         #[arg(short, long, group = "update_action")]
         remove: Option<String>,
         /// Replace an existing file in the archive with a new one.
-        #[arg(short, long, group = "update_action")]
+        #[arg(short = 'R', long, group = "update_action")]
         replace: Option<String>,
-        /// Optional: Password to decrypt and re-encrypt the archive during update.
+        #[command(flatten)]
+        password: PasswordArgs,
+        /// Optional: Identity file (X25519 secret key) to decrypt the archive.
+        /// Can be given multiple times.
+        #[arg(short, long, value_name = "KEYFILE")]
+        identity: Vec<String>,
+    },
+    /// Repairs a damaged .ubl archive from its recovery record.
+    ///
+    /// Checks every block of the archive against the record and rebuilds the
+    /// damaged ones, as long as no more were lost than the record has parity
+    /// for. The archive is repaired in place unless an output is given. Only
+    /// archives created with `--recovery` can be repaired.
+    Repair {
+        /// The path to the damaged .ubl archive.
+        archive: String,
+        /// Optional: Write the repaired archive here instead.
         #[arg(short, long)]
-        password: Option<String>,
+        output: Option<String>,
     },
     /// Verifies the integrity of a .ubl archive.
     ///
     /// This command checks if the archive is corrupted. A 'deep' verification
-    /// can be performed to check the integrity of the file contents (if implemented).
+    /// also decompresses every file and checks its contents.
     /// If the archive is password-protected, the password is required.
     Verify {
         /// The path to the .ubl archive to verify.
         archive: String,
-        /// Optional: Password to decrypt the archive for verification.
-        #[arg(long)]
-        password: Option<String>,
-        /// Perform a deep verification, checking the integrity of file contents.
-        /// (Note: Actual content hashing might be a TODO item in current implementation).
+        #[command(flatten)]
+        password: PasswordArgs,
+        /// Optional: Identity file (X25519 secret key) to decrypt the archive.
+        /// Can be given multiple times.
+        #[arg(short, long, value_name = "KEYFILE")]
+        identity: Vec<String>,
+        /// Perform a deep verification: decompress every file and check it
+        /// against the SHA-256 stored for each chunk and for the whole file.
+        /// Archives from before these hashes were stored are only checked for
+        /// decompression errors.
         #[arg(long)]
         deep: bool,
-        /// Output verification results in JSON format.
+        /// Same as `--format json`.
         #[arg(long)]
         json: bool,
+        /// Require a valid signature by this public key (or file of public
+        /// keys). Can be given multiple times.
+        #[arg(long, value_name = "PUBKEY")]
+        pubkey: Vec<String>,
+    },
+    /// Signs an archive with an Ed25519 signing key.
+    ///
+    /// The signature covers the header and the whole body, including the
+    /// index and every chunk hash, and is embedded in the archive header.
+    Sign {
+        /// The path to the .ubl archive to sign.
+        archive: String,
+        /// Signing key file, as written by `ubl keygen --sign`.
+        #[arg(short, long, value_name = "KEYFILE")]
+        key: String,
+    },
+    /// Changes the password or recipients of an encrypted archive.
+    ///
+    /// Only the header is rewritten: the archive's data key is wrapped again
+    /// for the new password and recipients, so this is fast for any size.
+    Rekey {
+        /// The path to the encrypted .ubl archive.
+        archive: String,
+        #[command(flatten)]
+        password: PasswordArgs,
+        /// Optional: Identity file (X25519 secret key) to decrypt the archive.
+        /// Can be given multiple times.
+        #[arg(short, long, value_name = "KEYFILE")]
+        identity: Vec<String>,
+        #[command(flatten)]
+        new_password: NewPasswordArgs,
+        /// Optional: Public key (or file of public keys) to encrypt the archive to.
+        /// Can be given multiple times.
+        #[arg(short, long, value_name = "PUBKEY")]
+        recipient: Vec<String>,
+        /// Keep the archive's existing recipients alongside the new ones.
+        #[arg(long)]
+        keep_recipients: bool,
+        #[command(flatten)]
+        kdf: KdfArgs,
+    },
+    /// Encrypts a plaintext archive in place.
+    ///
+    /// The compressed contents are encrypted as they are; no entry is
+    /// decompressed or recompressed.
+    Encrypt {
+        /// The path to the .ubl archive to encrypt.
+        archive: String,
+        #[command(flatten)]
+        password: PasswordArgs,
+        /// Optional: Public key (or file of public keys) to encrypt the archive to.
+        /// Can be given multiple times.
+        #[arg(short, long, value_name = "PUBKEY")]
+        recipient: Vec<String>,
+        #[command(flatten)]
+        kdf: KdfArgs,
+        /// Cipher for the encrypted archive.
+        #[arg(long, value_enum, default_value_t = Cipher::Aes256Gcm)]
+        cipher: Cipher,
+    },
+    /// Removes the encryption from an archive in place.
+    Decrypt {
+        /// The path to the encrypted .ubl archive.
+        archive: String,
+        #[command(flatten)]
+        password: PasswordArgs,
+        /// Optional: Identity file (X25519 secret key) to decrypt the archive.
+        /// Can be given multiple times.
+        #[arg(short, long, value_name = "KEYFILE")]
+        identity: Vec<String>,
+    },
+    /// Generates an X25519 identity for public-key encryption.
+    ///
+    /// The identity file holds the secret key and, in a comment, the public
+    /// key to share with whoever creates archives for you.
+    Keygen {
+        /// Optional: File to write the identity to. Prints it if omitted.
+        #[arg(short, long)]
+        output: Option<String>,
+        /// Generate an Ed25519 signing key for `ubl sign` instead.
+        #[arg(long)]
+        sign: bool,
+    },
+    /// Manages a snapshot repository: a directory holding many backups that
+    /// share deduplicated data, with retention policies.
+    Repo {
+        #[command(subcommand)]
+        command: RepoCommands,
+    },
+}
+
+/// Where the archive password comes from. At most one source may be given;
+/// `--keyfile` can be combined with any of them or used on its own.
+///
+/// Without any of these, commands reading a password-protected archive
+/// prompt for the password when run from a terminal.
+#[derive(Args, Clone)]
+pub struct PasswordArgs {
+    /// Optional: Password for the archive. Visible in shell history and `ps`;
+    /// prefer one of the options below.
+    #[arg(short, long, group = "password_source")]
+    pub password: Option<Zeroizing<String>>,
+    /// Optional: Read the password from the first line of a file.
+    #[arg(long, value_name = "FILE", group = "password_source")]
+    pub password_file: Option<String>,
+    /// Optional: Read the password from an environment variable.
+    #[arg(long, value_name = "VAR", group = "password_source")]
+    pub password_env: Option<String>,
+    /// Optional: Read the password from the first line of an open file descriptor.
+    #[arg(long, value_name = "N", group = "password_source")]
+    pub password_fd: Option<u32>,
+    /// Optional: Prompt for the password without echoing it.
+    #[arg(long, group = "password_source")]
+    pub ask_password: bool,
+    /// Optional: File whose contents are mixed into the key derivation. The
+    /// same file is then needed to decrypt the archive.
+    #[arg(long, value_name = "FILE")]
+    pub keyfile: Option<String>,
+}
+
+/// The password to switch to; the same sources as [`PasswordArgs`].
+#[derive(Args, Clone)]
+pub struct NewPasswordArgs {
+    /// Optional: New password for the archive.
+    #[arg(long, group = "new_password_source")]
+    pub new_password: Option<Zeroizing<String>>,
+    /// Optional: Read the new password from the first line of a file.
+    #[arg(long, value_name = "FILE", group = "new_password_source")]
+    pub new_password_file: Option<String>,
+    /// Optional: Read the new password from an environment variable.
+    #[arg(long, value_name = "VAR", group = "new_password_source")]
+    pub new_password_env: Option<String>,
+    /// Optional: Read the new password from the first line of an open file descriptor.
+    #[arg(long, value_name = "N", group = "new_password_source")]
+    pub new_password_fd: Option<u32>,
+    /// Optional: Prompt for the new password without echoing it.
+    #[arg(long, group = "new_password_source")]
+    pub ask_new_password: bool,
+    /// Optional: New keyfile to mix into the key derivation.
+    #[arg(long, value_name = "FILE")]
+    pub new_keyfile: Option<String>,
+}
+
+/// Cost of deriving the key from a password. The chosen parameters are
+/// stored in the archive, so opening it always uses the same ones.
+#[derive(Args, Clone)]
+pub struct KdfArgs {
+    /// Key derivation preset. Stronger presets are slower to brute-force, and
+    /// also slower to open.
+    #[arg(long, value_enum, default_value_t = KdfPreset::Interactive)]
+    pub kdf_preset: KdfPreset,
+    /// Optional: Override the preset's Argon2 memory cost, in MiB.
+    #[arg(long, value_name = "MIB")]
+    pub kdf_memory: Option<u32>,
+    /// Optional: Override the preset's Argon2 iteration count.
+    #[arg(long, value_name = "N")]
+    pub kdf_iterations: Option<u32>,
+}
+
+/// Parses a byte count with an optional binary unit suffix: `K`, `M`, `G`
+/// or `T`, optionally followed by `B` or `iB`.
+fn parse_size(value: &str) -> Result<u64, String> {
+    let upper = value.trim().to_uppercase();
+    let number = upper
+        .strip_suffix("IB")
+        .or_else(|| upper.strip_suffix('B'))
+        .unwrap_or(&upper);
+    let (digits, shift) = match number.char_indices().last() {
+        Some((i, 'K')) => (&number[..i], 10),
+        Some((i, 'M')) => (&number[..i], 20),
+        Some((i, 'G')) => (&number[..i], 30),
+        Some((i, 'T')) => (&number[..i], 40),
+        _ => (number, 0),
+    };
+    let size = digits
+        .trim()
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(1 << shift))
+        .ok_or_else(|| format!("invalid size '{}'; use e.g. 700M or 4G", value))?;
+    if size == 0 {
+        return Err("size must be greater than zero".to_string());
+    }
+    Ok(size)
+}
+
+/// Parses a percentage such as `5%` or `5`, from 1 to 100.
+fn parse_percent(value: &str) -> Result<u8, String> {
+    value
+        .trim()
+        .trim_end_matches('%')
+        .trim_end()
+        .parse::<u8>()
+        .ok()
+        .filter(|p| (1..=100).contains(p))
+        .ok_or_else(|| format!("invalid percentage '{}'; use 1% to 100%", value))
+}
+
+/// Orders `ubl list` output.
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq)]
+pub enum SortKey {
+    /// By path, alphabetically.
+    Name,
+    /// Largest first.
+    Size,
+    /// Most recently modified first.
+    Mtime,
+    /// Worst compressed (highest compressed/original ratio) first.
+    Ratio,
+}
+
+#[derive(ValueEnum, Clone, Copy)]
+pub enum KdfPreset {
+    /// 64 MiB, 2 iterations.
+    Interactive,
+    /// 256 MiB, 3 iterations.
+    Moderate,
+    /// 1 GiB, 4 iterations.
+    Paranoid,
+}
+
+#[derive(Subcommand)]
+pub enum RepoCommands {
+    /// Creates a new, empty repository.
+    Init {
+        /// The directory to create the repository in.
+        repo: String,
+        #[command(flatten)]
+        password: PasswordArgs,
+    },
+    /// Stores a new snapshot of a file or directory.
+    ///
+    /// Only chunks not already in the repository are written, and files whose
+    /// size and mtime match the previous snapshot of the same path are not
+    /// read again.
+    Backup {
+        /// The repository directory.
+        repo: String,
+        /// The input file or directory to back up.
+        input: String,
+        #[command(flatten)]
+        password: PasswordArgs,
+    },
+    /// Lists the snapshots in a repository.
+    Snapshots {
+        /// The repository directory.
+        repo: String,
+        #[command(flatten)]
+        password: PasswordArgs,
+    },
+    /// Restores a snapshot.
+    Restore {
+        /// The repository directory.
+        repo: String,
+        /// Snapshot ID (or a unique prefix of it), or `latest`.
+        snapshot: String,
+        /// Optional: Directory to restore into. Defaults to `snapshot-<id>`.
+        #[arg(short, long)]
+        output: Option<String>,
+        #[command(flatten)]
+        password: PasswordArgs,
+    },
+    /// Removes snapshots that fall outside the retention policy.
+    ///
+    /// For each `--keep-*` option, the newest snapshot in each of the most
+    /// recent N days, weeks, months or years is kept. A snapshot is kept if
+    /// any option keeps it. Snapshots of each source directory are counted
+    /// separately.
+    Forget {
+        /// The repository directory.
+        repo: String,
+        /// Keep the N most recent snapshots.
+        #[arg(long, default_value_t = 0, value_name = "N")]
+        keep_last: usize,
+        /// Keep the newest snapshot of each of the last N days.
+        #[arg(long, default_value_t = 0, value_name = "N")]
+        keep_daily: usize,
+        /// Keep the newest snapshot of each of the last N weeks.
+        #[arg(long, default_value_t = 0, value_name = "N")]
+        keep_weekly: usize,
+        /// Keep the newest snapshot of each of the last N months.
+        #[arg(long, default_value_t = 0, value_name = "N")]
+        keep_monthly: usize,
+        /// Keep the newest snapshot of each of the last N years.
+        #[arg(long, default_value_t = 0, value_name = "N")]
+        keep_yearly: usize,
+        /// Remove data no longer referenced by any snapshot afterwards.
+        #[arg(long)]
+        prune: bool,
+        #[command(flatten)]
+        password: PasswordArgs,
+    },
+    /// Removes data no longer referenced by any snapshot.
+    Prune {
+        /// The repository directory.
+        repo: String,
+        #[command(flatten)]
+        password: PasswordArgs,
     },
 }
@@ -0,0 +1,69 @@
+use std::collections::HashMap;
+use std::io::{self, BufWriter, Read, Write};
+
+use super::{credentials, exit_on_error, fail};
+use crate::chain::Chain;
+use crate::cli::PasswordArgs;
+use crate::output;
+
+/// Writes the contents of archived files to stdout, one after another.
+/// Nothing but file data goes to stdout, so the output can be piped.
+pub fn run(
+    archive_path: &str,
+    paths: &[String],
+    password: &PasswordArgs,
+    identity: Vec<String>,
+    offset: u64,
+    length: Option<u64>,
+) {
+    if !output::is_text() {
+        fail("`ubl cat` writes file data to stdout; --format json and ndjson do not apply.");
+    }
+    let credentials = credentials(archive_path, password, &identity);
+    let chain = exit_on_error(Chain::open(archive_path, &credentials));
+    if let Some(path) = &chain.archives[0].truncated {
+        fail(format!("Archive is truncated at entry '{}'.", path));
+    }
+
+    let entries: HashMap<&str, _> = chain
+        .entries()
+        .into_iter()
+        .map(|(archive, entry)| (entry.path.as_str(), (archive, entry)))
+        .collect();
+    // Every path is looked up before anything is written, so a typo does
+    // not leave partial output behind.
+    let selected: Vec<_> = paths
+        .iter()
+        .map(|path| {
+            entries
+                .get(path.as_str())
+                .copied()
+                .unwrap_or_else(|| fail(format!("Not found in archive: {}", path)))
+        })
+        .collect();
+
+    let mut stdout = BufWriter::new(io::stdout().lock());
+    for (archive, entry) in selected {
+        if offset >= entry.original_len {
+            continue;
+        }
+        let written = archive.decoder_at(entry, offset).and_then(|decoder| {
+            let mut limited = decoder.take(length.unwrap_or(u64::MAX));
+            io::copy(&mut limited, &mut stdout)
+        });
+        if let Err(e) = written {
+            exit_on_write_error(e, &entry.path);
+        }
+    }
+    if let Err(e) = stdout.flush() {
+        exit_on_write_error(e, "stdout");
+    }
+}
+
+/// A closed pipe (e.g. `ubl cat ... | head`) is a normal way to stop reading.
+fn exit_on_write_error(e: io::Error, what: &str) -> ! {
+    if e.kind() == io::ErrorKind::BrokenPipe {
+        std::process::exit(0);
+    }
+    fail(format!("Failed to stream '{}': {}", what, e));
+}
@@ -1,31 +1,199 @@
-use std::fs::{self, File};
-use std::io::{BufWriter, Write};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, IsTerminal, Read, Write};
 use std::path::Path;
-use std::time::Instant;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 
 use indicatif::{ProgressBar, ProgressStyle};
-use walkdir::WalkDir;
-use zstd::stream::Encoder;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use walkdir::{DirEntry, WalkDir};
 
-use crate::encrypt;
+use super::{exit_on_error, fail, kdf_params, new_passphrase};
+use crate::archive::{self, Archive, BaseRef, Entry};
+use crate::chain::{self, Chain};
+use crate::cli::{KdfArgs, PasswordArgs};
+use crate::encrypt::Cipher;
+use crate::keys::{Credentials, Envelope};
+use crate::output;
+use crate::volume;
 
-pub fn run(input: &str, output: &str, password: Option<String>) {
+/// Maximum size of a trained dictionary (zstd's own default).
+const DICT_SIZE: usize = 112_640;
+/// Only the head of each file is used as a training sample.
+const DICT_SAMPLE_LEN: usize = 128 * 1024;
+/// Input or output path standing for stdin or stdout.
+const STDIO: &str = "-";
+/// Entry name for stdin input when `--name` is not given.
+const DEFAULT_STDIN_NAME: &str = "stdin";
+
+#[derive(Serialize)]
+struct StoredFile<'a> {
+    path: &'a str,
+    /// `added`, or `deleted` for a tombstone in an incremental archive.
+    action: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    size: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct CompressSummary<'a> {
+    archive: &'a str,
+    files: usize,
+    deleted: usize,
+    original_size: u64,
+    volumes: usize,
+    encrypted: bool,
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn run(
+    input: &str,
+    output: &str,
+    password: &PasswordArgs,
+    recipients: Vec<String>,
+    kdf: &KdfArgs,
+    cipher: Cipher,
+    metadata_visible: bool,
+    identity: Vec<String>,
+    train_dict: bool,
+    since: Option<String>,
+    name: Option<String>,
+    volume_size: Option<u64>,
+    recovery: Option<u8>,
+) {
+    let from_stdin = input == STDIO;
+    let to_stdout = output == STDIO;
     let input_path = Path::new(input);
-    if !input_path.exists() {
-        eprintln!("Input path '{}' does not exist.", input);
-        return;
+    if !from_stdin && !input_path.exists() {
+        fail(format!("Input path '{}' does not exist.", input));
+    }
+    if name.is_some() && !from_stdin {
+        fail("--name only applies when compressing stdin (`-`).");
+    }
+    if to_stdout && volume_size.is_some() {
+        fail("--volume-size cannot be used when writing to stdout.");
+    }
+    if to_stdout && !output::is_text() {
+        fail("--format json and ndjson cannot be used when writing the archive to stdout.");
     }
+    if to_stdout && io::stdout().is_terminal() {
+        fail("Refusing to write archive data to a terminal; redirect stdout.");
+    }
+    // Status messages must not end up in an archive written to stdout, nor
+    // in machine-readable output.
+    let mut log: Box<dyn Write> = if !output::is_text() {
+        Box::new(io::sink())
+    } else if to_stdout {
+        Box::new(io::stderr())
+    } else {
+        Box::new(io::stdout())
+    };
+
+    let recipient_keys = super::recipients(&recipients);
 
-    println!("📦 Compressing '{}' into '{}'", input, output);
+    // The identities only open the `--since` base; the passphrase is used
+    // for both the base and the new archive.
+    let credentials = exit_on_error(Credentials::new(new_passphrase(password), &identity));
+    let passphrase = credentials.passphrase.as_ref();
+    let kdf = kdf_params(kdf);
+    if metadata_visible && passphrase.is_none() && recipient_keys.is_empty() {
+        fail("--metadata-visible needs a password, keyfile or recipient.");
+    }
+
+    writeln!(
+        log,
+        "📦 Compressing {} into {}",
+        display_name(input, "stdin"),
+        display_name(output, "stdout")
+    )
+    .unwrap();
     let start = Instant::now();
 
-    let files: Vec<_> = WalkDir::new(input_path)
-        .into_iter()
-        .filter_map(Result::ok)
-        .filter(|e| e.file_type().is_file())
-        .collect();
+    // Stdin becomes a single entry; the walk is skipped entirely.
+    let mut stdin_data = Vec::new();
+    let mut files: Vec<_> = if from_stdin {
+        if let Err(e) = io::stdin().lock().read_to_end(&mut stdin_data) {
+            fail(format!("Failed to read stdin: {}", e));
+        }
+        Vec::new()
+    } else {
+        WalkDir::new(input_path)
+            .into_iter()
+            .filter_map(Result::ok)
+            .filter(|e| e.file_type().is_file())
+            .collect()
+    };
+    let stdin_name = from_stdin.then(|| name.unwrap_or_else(|| DEFAULT_STDIN_NAME.to_string()));
+
+    let mut base = None;
+    let mut deleted = Vec::new();
+    if let Some(base_path) = &since {
+        let chain = exit_on_error(Chain::open(base_path, &credentials));
+        // The identities that open an encrypted base do not say whom to
+        // encrypt the incremental to, and it must not end up in plaintext.
+        if chain.archives.iter().any(|a| a.encrypted)
+            && passphrase.is_none()
+            && recipient_keys.is_empty()
+        {
+            fail(format!(
+                "Base archive '{}' is encrypted; give a password, keyfile or --recipient \
+                 to encrypt the incremental archive as well.",
+                base_path
+            ));
+        }
+        let Some(id) = chain.archives[0].id.clone() else {
+            fail(format!(
+                "Base archive '{}' predates archive IDs; rewrite it with `ubl update` first.",
+                base_path
+            ));
+        };
+
+        let mut previous: HashMap<String, Entry> = chain
+            .entries()
+            .into_iter()
+            .map(|(_, e)| (e.path.clone(), e.clone()))
+            .collect();
+        let total = files.len();
+        files.retain(|e| {
+            let path = relative_path(input_path, e);
+            match previous.remove(&path) {
+                Some(old) => has_changed(e, &old),
+                None => true,
+            }
+        });
+        // Stdin has no mtime to compare, so it is always stored; its chunks
+        // are still deduplicated within the new archive.
+        if let Some(name) = &stdin_name {
+            previous.remove(name);
+        }
+        deleted = previous.into_keys().collect();
+        deleted.sort();
+
+        writeln!(
+            log,
+            "🔗 Incremental against '{}': {} changed, {} deleted, {} unchanged",
+            base_path,
+            files.len(),
+            deleted.len(),
+            total - files.len()
+        )
+        .unwrap();
+        base = Some(BaseRef {
+            id,
+            path: chain::base_link(output, base_path),
+        });
+    }
+
+    let dictionary = if train_dict {
+        train_dictionary(&files, &mut log)
+    } else {
+        None
+    };
+    let mut archive = Archive::new(dictionary);
+    archive.base = base;
 
-    let pb = ProgressBar::new(files.len() as u64);
+    let pb = ProgressBar::new(files.len() as u64 + stdin_name.is_some() as u64);
     pb.set_style(
         ProgressStyle::with_template(
             "{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} {msg}",
@@ -34,55 +202,180 @@ pub fn run(input: &str, output: &str, password: Option<String>) {
         .progress_chars("#>-"),
     );
 
-    let mut archive_buf = Vec::new();
-
     for entry in files {
         let file_path = entry.path();
-        let relative_path = file_path.strip_prefix(input_path).unwrap();
-        let path_str = relative_path.to_string_lossy();
-        let path_bytes = path_str.as_bytes();
-        let path_len = path_bytes.len() as u32;
+        let path_str = relative_path(input_path, &entry);
 
         let data = fs::read(file_path).unwrap();
-        let original_len = data.len() as u64;
-
-        let mut compressed = Vec::new();
-        let mut encoder = Encoder::new(&mut compressed, 21).unwrap();
-        encoder.write_all(&data).unwrap();
-        encoder.finish().unwrap();
-
-        let compressed_len = compressed.len() as u64;
+        let stored = archive.insert(path_str.clone(), &data, archive::file_mtime(file_path));
+        archive::record_permissions(stored, file_path);
+        output::file(StoredFile {
+            path: &path_str,
+            action: "added",
+            size: Some(data.len() as u64),
+        });
 
-        archive_buf.extend(&path_len.to_le_bytes());
-        archive_buf.extend(path_bytes);
-        archive_buf.extend(&original_len.to_le_bytes());
-        archive_buf.extend(&compressed_len.to_le_bytes());
-        archive_buf.extend(&compressed);
+        pb.set_message(path_str);
+        pb.inc(1);
+    }
 
-        pb.set_message(path_str.to_string());
+    if let Some(name) = stdin_name {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .ok()
+            .map(|d| d.as_secs());
+        archive.insert(name.clone(), &stdin_data, now);
+        output::file(StoredFile {
+            path: &name,
+            action: "added",
+            size: Some(stdin_data.len() as u64),
+        });
+        pb.set_message(name);
         pb.inc(1);
     }
 
+    for path in &deleted {
+        output::file(StoredFile {
+            path,
+            action: "deleted",
+            size: None,
+        });
+    }
+    let deleted_count = deleted.len();
+    for path in deleted {
+        archive.insert_tombstone(path);
+    }
+
     pb.finish_with_message("🎉 Compression done");
 
-    let final_data = if let Some(pass) = password {
-        println!("🔒 Encrypting archive...");
-        let (salt, nonce, ciphertext) = encrypt::encrypt(&archive_buf, &pass);
+    archive.envelope = exit_on_error(Envelope::seal(cipher, passphrase, &kdf, &recipient_keys));
+    if !recipient_keys.is_empty() {
+        writeln!(
+            log,
+            "🔒 Encrypting archive for {} recipient(s)...",
+            recipient_keys.len()
+        )
+        .unwrap();
+    } else if passphrase.is_some() {
+        writeln!(log, "🔒 Encrypting archive...").unwrap();
+    }
+    archive.metadata_visible = metadata_visible;
+    archive.recovery = recovery;
+    if let Some(percent) = recovery {
+        writeln!(log, "🛟 Adding a {}% recovery record...", percent).unwrap();
+    }
 
-        let mut encrypted_data = Vec::new();
-        encrypted_data.extend(&salt);
-        encrypted_data.extend(&nonce);
-        encrypted_data.extend(&ciphertext);
-        encrypted_data
+    let written = if to_stdout {
+        archive.write_to(io::stdout().lock()).map(|_| 1)
+    } else if let Some(size) = volume_size {
+        archive.write_volumes(output, size)
     } else {
-        archive_buf
+        archive.write(output).map(|_| 1)
+    };
+    let volumes = match written {
+        Ok(volumes) => volumes,
+        Err(e) => fail(format!("{:#}", e)),
     };
-
-    let out_file = File::create(output).unwrap();
-    let mut writer = BufWriter::new(out_file);
-    writer.write_all(&final_data).unwrap();
 
     let duration = start.elapsed();
-    println!("✅ Archive written to '{}'", output);
-    println!("🕒 Completed in {:.2?}", duration);
+    if volume_size.is_some() {
+        writeln!(
+            log,
+            "✅ Archive written to {} volumes: {} ... {}",
+            volumes,
+            volume::volume_path(output, 1),
+            volume::volume_path(output, volumes)
+        )
+        .unwrap();
+    } else {
+        writeln!(
+            log,
+            "✅ Archive written to {}",
+            display_name(output, "stdout")
+        )
+        .unwrap();
+    }
+    writeln!(log, "🕒 Completed in {:.2?}", duration).unwrap();
+    output::summary(
+        true,
+        CompressSummary {
+            archive: output,
+            files: archive.entries.len() - deleted_count,
+            deleted: deleted_count,
+            original_size: archive.entries.iter().map(|e| e.original_len).sum(),
+            volumes,
+            encrypted: archive.envelope.is_some(),
+        },
+    );
+}
+
+/// Quotes a path for messages, or names the stream `-` stands for.
+fn display_name(path: &str, stream: &str) -> String {
+    if path == STDIO {
+        stream.to_string()
+    } else {
+        format!("'{}'", path)
+    }
+}
+
+fn relative_path(input_path: &Path, entry: &DirEntry) -> String {
+    let relative_path = entry.path().strip_prefix(input_path).unwrap();
+    relative_path.to_string_lossy().to_string()
+}
+
+/// Quick check on size and mtime; files that fail it are hashed so that a
+/// mere `touch` does not store the contents again.
+fn has_changed(file: &DirEntry, old: &Entry) -> bool {
+    let Ok(meta) = file.metadata() else {
+        return true;
+    };
+    if meta.len() != old.original_len {
+        return true;
+    }
+
+    let mtime = archive::file_mtime(file.path());
+    if mtime.is_some() && mtime == old.mtime {
+        return false;
+    }
+
+    match (&old.sha256, fs::read(file.path())) {
+        (Some(old_hash), Ok(data)) => *old_hash != format!("{:x}", Sha256::digest(&data)),
+        _ => true,
+    }
+}
+
+/// Trains a zstd dictionary from the first bytes of every input file.
+/// Returns `None` (and compression proceeds without one) if zstd cannot
+/// build a dictionary from the samples, e.g. when there are too few files.
+fn train_dictionary(files: &[DirEntry], log: &mut dyn Write) -> Option<Vec<u8>> {
+    writeln!(
+        log,
+        "📚 Training dictionary from {} file(s)...",
+        files.len()
+    )
+    .unwrap();
+
+    let samples: Vec<Vec<u8>> = files
+        .iter()
+        .filter_map(|e| fs::read(e.path()).ok())
+        .map(|mut data| {
+            data.truncate(DICT_SAMPLE_LEN);
+            data
+        })
+        .filter(|data| !data.is_empty())
+        .collect();
+
+    match zstd::dict::from_samples(&samples, DICT_SIZE) {
+        Ok(dict) => {
+            writeln!(log, "📚 Trained {} byte dictionary", dict.len()).unwrap();
+            Some(dict)
+        }
+        Err(e) => {
+            eprintln!(
+                "⚠️  Could not train dictionary ({}); continuing without one.",
+                e
+            );
+            None
+        }
+    }
 }
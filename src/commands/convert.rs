@@ -0,0 +1,392 @@
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Component, Path};
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Datelike, Local, NaiveDate, Timelike};
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use serde_json::json;
+use tar::EntryType;
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+
+use super::{credentials, exit_on_error, fail, kdf_params, new_passphrase};
+use crate::archive::{Archive, Entry, EntryKind, Owner};
+use crate::chain::Chain;
+use crate::cli::{KdfArgs, PasswordArgs};
+use crate::encrypt::Cipher;
+use crate::keys::{Credentials, Envelope};
+use crate::output::{self, say};
+
+/// Permissions given to exported files whose mode was never recorded.
+const DEFAULT_MODE: u32 = 0o644;
+const DEFAULT_SYMLINK_MODE: u32 = 0o777;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Format {
+    Ubl,
+    Tar(TarCompression),
+    Zip,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum TarCompression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Format {
+    /// Picks the format from the file extension.
+    fn detect(path: &str) -> Option<Format> {
+        let name = path.to_lowercase();
+        let format = if name.ends_with(".ubl") {
+            Format::Ubl
+        } else if name.ends_with(".tar") {
+            Format::Tar(TarCompression::None)
+        } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            Format::Tar(TarCompression::Gzip)
+        } else if name.ends_with(".tar.zst") || name.ends_with(".tzst") {
+            Format::Tar(TarCompression::Zstd)
+        } else if name.ends_with(".zip") {
+            Format::Zip
+        } else {
+            return None;
+        };
+        Some(format)
+    }
+}
+
+/// What an import stored and what it had to leave out.
+#[derive(Default)]
+struct ImportStats {
+    files: usize,
+    links: usize,
+    skipped: usize,
+}
+
+/// Converts between .ubl archives and tar or zip files. One side must be a
+/// .ubl archive; the other format is taken from its extension.
+#[allow(clippy::too_many_arguments)]
+pub fn run(
+    input: &str,
+    output: &str,
+    password: &PasswordArgs,
+    identity: Vec<String>,
+    recipients: Vec<String>,
+    kdf: &KdfArgs,
+    cipher: Cipher,
+) {
+    let formats = (Format::detect(input), Format::detect(output));
+    let (Some(from), Some(to)) = formats else {
+        let unknown = if formats.0.is_none() { input } else { output };
+        fail(format!(
+            "Unknown format for '{}'; use .ubl, .tar, .tar.gz, .tgz, .tar.zst or .zip.",
+            unknown
+        ));
+    };
+    if (from == Format::Ubl) == (to == Format::Ubl) {
+        fail("Exactly one side of the conversion must be a .ubl archive.");
+    }
+
+    say!("🔄 Converting '{}' to '{}'", input, output);
+    if from == Format::Ubl {
+        let credentials = credentials(input, password, &identity);
+        if !credentials.is_empty() {
+            say!("🔐 Decrypting archive...");
+        }
+        let chain = exit_on_error(Chain::open(input, &credentials));
+        let entries = chain.entries();
+        exit_on_error(export(&entries, output, to));
+        say!("✅ Wrote {} entries to '{}'", entries.len(), output);
+        output::summary(
+            true,
+            json!({ "input": input, "output": output, "entries": entries.len() }),
+        );
+        return;
+    }
+
+    let recipient_keys = super::recipients(&recipients);
+    let credentials = exit_on_error(Credentials::new(new_passphrase(password), &[]));
+    let passphrase = credentials.passphrase.as_ref();
+    let kdf = kdf_params(kdf);
+
+    let mut archive = Archive::new(None);
+    let stats = exit_on_error(import(input, from, &mut archive));
+    archive.envelope = exit_on_error(Envelope::seal(cipher, passphrase, &kdf, &recipient_keys));
+    if archive.envelope.is_some() {
+        say!("🔒 Encrypting archive...");
+    }
+    exit_on_error(archive.write(output));
+
+    if stats.skipped > 0 {
+        say!(
+            "⚠️  Skipped {} entries that .ubl archives cannot hold",
+            stats.skipped
+        );
+    }
+    say!(
+        "✅ Stored {} files and {} symlinks in '{}'",
+        stats.files,
+        stats.links,
+        output
+    );
+    output::summary(
+        true,
+        json!({
+            "input": input,
+            "output": output,
+            "files": stats.files,
+            "symlinks": stats.links,
+            "skipped": stats.skipped,
+            "encrypted": archive.envelope.is_some(),
+        }),
+    );
+}
+
+fn import(input: &str, format: Format, archive: &mut Archive) -> Result<ImportStats> {
+    let file =
+        BufReader::new(File::open(input).with_context(|| format!("Failed to open '{}'", input))?);
+    match format {
+        Format::Tar(TarCompression::None) => import_tar(file, archive),
+        Format::Tar(TarCompression::Gzip) => import_tar(MultiGzDecoder::new(file), archive),
+        Format::Tar(TarCompression::Zstd) => import_tar(zstd::Decoder::new(file)?, archive),
+        Format::Zip => import_zip(file, archive),
+        Format::Ubl => unreachable!(),
+    }
+    .with_context(|| format!("Failed to read '{}'", input))
+}
+
+/// Reads tar entries one at a time. Directories are implied by the paths of
+/// the files inside them; devices and other special files are skipped.
+fn import_tar(reader: impl Read, archive: &mut Archive) -> Result<ImportStats> {
+    let mut stats = ImportStats::default();
+    let mut tar = tar::Archive::new(reader);
+    for entry in tar.entries()? {
+        let mut entry = entry?;
+        if entry.header().entry_type() == EntryType::Directory {
+            continue;
+        }
+        let raw_path = entry.path()?.into_owned();
+        let Some(path) = archive_path(&raw_path) else {
+            warn_skipped(&raw_path.to_string_lossy(), "unsafe path", &mut stats);
+            continue;
+        };
+        let header = entry.header();
+        let kind = header.entry_type();
+        let mtime = header.mtime().ok();
+        let mode = header.mode().ok().map(|m| m & 0o7777);
+        let owner = header
+            .uid()
+            .ok()
+            .zip(header.gid().ok())
+            .and_then(|(uid, gid)| {
+                Some(Owner {
+                    uid: uid.try_into().ok()?,
+                    gid: gid.try_into().ok()?,
+                })
+            });
+        let link = entry.link_name()?.map(|l| l.to_string_lossy().to_string());
+
+        match (kind, link) {
+            (EntryType::Regular | EntryType::Continuous, _) => {
+                let mut data = Vec::with_capacity(entry.size() as usize);
+                entry.read_to_end(&mut data)?;
+                let stored = archive.insert(path, &data, mtime);
+                stored.mode = mode;
+                stored.owner = owner;
+                stats.files += 1;
+            }
+            (EntryType::Symlink, Some(target)) => {
+                archive.insert(path, target.as_bytes(), mtime).kind = EntryKind::Symlink;
+                stats.links += 1;
+            }
+            // Hard links share the chunks of the file they point to.
+            (EntryType::Link, Some(target)) => match archive_path(Path::new(&target)) {
+                Some(target) if archive.copy_entry(&target, path.clone()) => stats.files += 1,
+                _ => warn_skipped(&path, "hard link to a missing file", &mut stats),
+            },
+            _ => warn_skipped(&path, "unsupported entry type", &mut stats),
+        }
+    }
+    Ok(stats)
+}
+
+fn import_zip(reader: BufReader<File>, archive: &mut Archive) -> Result<ImportStats> {
+    let mut stats = ImportStats::default();
+    let mut zip = ZipArchive::new(reader)?;
+    for i in 0..zip.len() {
+        let mut file = zip.by_index(i)?;
+        if file.is_dir() {
+            continue;
+        }
+        let Some(path) = file.enclosed_name().and_then(|p| archive_path(&p)) else {
+            let name = file.name().to_string();
+            warn_skipped(&name, "unsafe path", &mut stats);
+            continue;
+        };
+        let mtime = file.last_modified().and_then(zip_time_to_unix);
+        let symlink = file.is_symlink();
+        let mode = file.unix_mode().map(|m| m & 0o7777);
+
+        let mut data = Vec::with_capacity(file.size() as usize);
+        file.read_to_end(&mut data)?;
+        let entry = archive.insert(path, &data, mtime);
+        if symlink {
+            entry.kind = EntryKind::Symlink;
+            stats.links += 1;
+        } else {
+            entry.mode = mode;
+            stats.files += 1;
+        }
+    }
+    Ok(stats)
+}
+
+fn warn_skipped(path: &str, reason: &str, stats: &mut ImportStats) {
+    eprintln!("⚠️  Skipping '{}': {}", path, reason);
+    stats.skipped += 1;
+}
+
+/// Turns a path from a tar or zip file into an archive entry path. Leading
+/// `/` and `./` are dropped; paths leading outside the archive are rejected.
+fn archive_path(path: &Path) -> Option<String> {
+    let mut parts = Vec::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => parts.push(part.to_string_lossy()),
+            Component::CurDir | Component::RootDir | Component::Prefix(_) => {}
+            Component::ParentDir => return None,
+        }
+    }
+    (!parts.is_empty()).then(|| parts.join("/"))
+}
+
+fn export(entries: &[(&Archive, &Entry)], output: &str, format: Format) -> Result<()> {
+    let file = BufWriter::new(
+        File::create(output).with_context(|| format!("Failed to create '{}'", output))?,
+    );
+    match format {
+        Format::Tar(TarCompression::None) => export_tar(entries, file)?.flush()?,
+        Format::Tar(TarCompression::Gzip) => {
+            let encoder = GzEncoder::new(file, flate2::Compression::default());
+            export_tar(entries, encoder)?.finish()?.flush()?
+        }
+        Format::Tar(TarCompression::Zstd) => {
+            let encoder = zstd::Encoder::new(file, zstd::DEFAULT_COMPRESSION_LEVEL)?;
+            export_tar(entries, encoder)?.finish()?.flush()?
+        }
+        Format::Zip => export_zip(entries, file)?,
+        Format::Ubl => unreachable!(),
+    }
+    Ok(())
+}
+
+/// Streams every entry into a tar file, decompressing one chunk at a time.
+fn export_tar<W: Write>(entries: &[(&Archive, &Entry)], writer: W) -> Result<W> {
+    let mut builder = tar::Builder::new(writer);
+    for (archive, entry) in entries {
+        let mut decoder = archive
+            .decoder(entry)
+            .with_context(|| format!("Failed to decompress '{}'", entry.path))?;
+        let mut header = tar::Header::new_gnu();
+        header.set_mtime(entry.mtime.unwrap_or(0));
+        if let Some(owner) = entry.owner {
+            header.set_uid(owner.uid.into());
+            header.set_gid(owner.gid.into());
+        }
+        if entry.is_symlink() {
+            let mut target = String::new();
+            decoder.read_to_string(&mut target)?;
+            header.set_entry_type(EntryType::Symlink);
+            header.set_mode(DEFAULT_SYMLINK_MODE);
+            header.set_size(0);
+            builder.append_link(&mut header, &entry.path, &target)?;
+        } else {
+            header.set_entry_type(EntryType::Regular);
+            header.set_mode(entry.mode.unwrap_or(DEFAULT_MODE));
+            header.set_size(entry.original_len);
+            builder.append_data(&mut header, &entry.path, decoder)?;
+        }
+    }
+    Ok(builder.into_inner()?)
+}
+
+fn export_zip(entries: &[(&Archive, &Entry)], writer: BufWriter<File>) -> Result<()> {
+    let mut zip = ZipWriter::new(writer);
+    for (archive, entry) in entries {
+        let mut decoder = archive
+            .decoder(entry)
+            .with_context(|| format!("Failed to decompress '{}'", entry.path))?;
+        let mut options = SimpleFileOptions::default()
+            .compression_method(CompressionMethod::Deflated)
+            .large_file(entry.original_len >= u32::MAX as u64);
+        if let Some(time) = entry.mtime.and_then(unix_to_zip_time) {
+            options = options.last_modified_time(time);
+        }
+        if entry.is_symlink() {
+            let mut target = String::new();
+            decoder.read_to_string(&mut target)?;
+            zip.add_symlink(&entry.path, target, options)?;
+        } else {
+            options = options.unix_permissions(entry.mode.unwrap_or(DEFAULT_MODE));
+            zip.start_file(&entry.path, options)?;
+            io::copy(&mut decoder, &mut zip)?;
+        }
+    }
+    zip.finish()?.flush()?;
+    Ok(())
+}
+
+/// Zip timestamps are in local time with two-second resolution.
+fn zip_time_to_unix(time: zip::DateTime) -> Option<u64> {
+    let local =
+        NaiveDate::from_ymd_opt(time.year().into(), time.month().into(), time.day().into())?
+            .and_hms_opt(
+                time.hour().into(),
+                time.minute().into(),
+                time.second().into(),
+            )?
+            .and_local_timezone(Local)
+            .earliest()?;
+    u64::try_from(local.timestamp()).ok()
+}
+
+/// Zip cannot represent times before 1980; those are left unset.
+fn unix_to_zip_time(mtime: u64) -> Option<zip::DateTime> {
+    let local = DateTime::from_timestamp(i64::try_from(mtime).ok()?, 0)?.with_timezone(&Local);
+    zip::DateTime::from_date_and_time(
+        u16::try_from(local.year()).ok()?,
+        local.month() as u8,
+        local.day() as u8,
+        local.hour() as u8,
+        local.minute() as u8,
+        local.second() as u8,
+    )
+    .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn converted(path: &str) -> Option<String> {
+        archive_path(Path::new(path))
+    }
+
+    #[test]
+    fn paths_leading_outside_the_archive_are_rejected() {
+        assert_eq!(converted("../etc/passwd"), None);
+        assert_eq!(converted("a/../../b"), None);
+        assert_eq!(converted("/../b"), None);
+        assert_eq!(converted("."), None);
+    }
+
+    #[test]
+    fn absolute_paths_stay_inside_the_archive() {
+        assert_eq!(converted("/etc/passwd").as_deref(), Some("etc/passwd"));
+        assert_eq!(converted("./a/./b").as_deref(), Some("a/b"));
+        assert_eq!(converted("a/b").as_deref(), Some("a/b"));
+    }
+}
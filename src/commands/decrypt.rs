@@ -0,0 +1,38 @@
+use serde_json::json;
+
+use super::{credentials, exit_on_error, fail};
+use crate::archive::{self, Archive};
+use crate::cli::PasswordArgs;
+use crate::output::{self, say};
+
+/// Removes the encryption from an archive in place, keeping the compressed
+/// data as it is.
+pub fn run(archive_path: &str, password: &PasswordArgs, identity: Vec<String>) {
+    let header = exit_on_error(archive::read_header(archive_path));
+    if matches!(&header, Some(h) if !h.encrypted) {
+        fail("Archive is not encrypted.");
+    }
+
+    let credentials = credentials(archive_path, password, &identity);
+    say!("🔓 Decrypting '{}'...", archive_path);
+    if header.is_some() {
+        exit_on_error(archive::decrypt_in_place(archive_path, &credentials));
+    } else {
+        // Archives from before headers are rewritten in the current format.
+        let mut archive = exit_on_error(Archive::open(archive_path, &credentials));
+        if !archive.encrypted {
+            fail("Archive is not encrypted.");
+        }
+        archive.envelope = None;
+        exit_on_error(archive.rewrite(archive_path));
+    }
+    say!("✅ Archive decrypted.");
+    let signature_removed = header.is_some_and(|h| h.signature.is_some());
+    if signature_removed {
+        say!("⚠️  The archive's signature was removed; sign it again with `ubl sign`.");
+    }
+    output::summary(
+        true,
+        json!({ "archive": archive_path, "signature_removed": signature_removed }),
+    );
+}
@@ -0,0 +1,295 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use similar::TextDiff;
+use walkdir::WalkDir;
+
+use super::{credentials, exit_on_error, human_size};
+use crate::archive::{self, Archive, Entry};
+use crate::chain::Chain;
+use crate::cli::PasswordArgs;
+use crate::output::{self, say};
+
+/// Text diffs are only produced for files up to this size.
+const MAX_TEXT_DIFF_LEN: u64 = 4 * 1024 * 1024;
+
+/// Where the contents of a compared file can be read from.
+enum Source<'a> {
+    Entry(&'a Archive, &'a Entry),
+    File(PathBuf),
+}
+
+struct Item<'a> {
+    size: u64,
+    mtime: Option<u64>,
+    source: Source<'a>,
+}
+
+impl Item<'_> {
+    fn read(&self) -> Result<Vec<u8>> {
+        match &self.source {
+            Source::Entry(archive, entry) => {
+                let mut data = Vec::new();
+                archive
+                    .decoder(entry)
+                    .and_then(|mut decoder| decoder.read_to_end(&mut data))
+                    .with_context(|| format!("Failed to decompress '{}'", entry.path))?;
+                Ok(data)
+            }
+            Source::File(path) => {
+                fs::read(path).with_context(|| format!("Failed to read '{}'", path.display()))
+            }
+        }
+    }
+
+    /// Hex SHA-256 of the contents, from the archive index when recorded.
+    fn sha256(&self) -> Result<String> {
+        match self.recorded_sha256() {
+            Some(hash) => Ok(hash),
+            None => Ok(format!("{:x}", Sha256::digest(self.read()?))),
+        }
+    }
+
+    fn recorded_sha256(&self) -> Option<String> {
+        match &self.source {
+            Source::Entry(_, entry) => entry.sha256.clone(),
+            Source::File(_) => None,
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+enum Change {
+    Added,
+    Removed,
+    /// Contents differ.
+    Modified,
+    /// Same contents, different modification time.
+    Touched,
+}
+
+#[derive(Serialize)]
+struct FileState {
+    size: u64,
+    mtime: Option<u64>,
+    sha256: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Difference {
+    path: String,
+    change: Change,
+    old: Option<FileState>,
+    new: Option<FileState>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    diff: Option<String>,
+}
+
+#[derive(Serialize)]
+struct DiffSummary<'a> {
+    old: &'a str,
+    new: &'a str,
+    added: usize,
+    removed: usize,
+    modified: usize,
+    touched: usize,
+}
+
+/// Compares an archive with another archive or a directory.
+pub fn run(
+    old_path: &str,
+    new_path: &str,
+    password: &PasswordArgs,
+    identity: Vec<String>,
+    unified: bool,
+) {
+    let new_is_dir = Path::new(new_path).is_dir();
+    let mut credentials = credentials(old_path, password, &identity);
+    if credentials.is_empty() && !new_is_dir {
+        credentials = super::credentials(new_path, password, &identity);
+    }
+
+    let old_chain = exit_on_error(Chain::open(old_path, &credentials));
+    let new_chain = if new_is_dir {
+        None
+    } else {
+        Some(exit_on_error(Chain::open(new_path, &credentials)))
+    };
+
+    let old = archive_tree(&old_chain);
+    let new = match &new_chain {
+        Some(chain) => archive_tree(chain),
+        None => directory_tree(Path::new(new_path)),
+    };
+    let differences = exit_on_error(compare(&old, &new, unified));
+    let mut counts: BTreeMap<Change, usize> = BTreeMap::new();
+    for d in &differences {
+        *counts.entry(d.change).or_default() += 1;
+        output::file(d);
+    }
+    let count = |change| counts.get(&change).copied().unwrap_or(0);
+    output::summary(
+        true,
+        DiffSummary {
+            old: old_path,
+            new: new_path,
+            added: count(Change::Added),
+            removed: count(Change::Removed),
+            modified: count(Change::Modified),
+            touched: count(Change::Touched),
+        },
+    );
+    if !output::is_text() {
+        return;
+    }
+
+    say!("🔍 Comparing '{}' with '{}'\n", old_path, new_path);
+    if differences.is_empty() {
+        say!("✅ No differences.");
+        return;
+    }
+
+    for d in &differences {
+        let detail = match (d.change, &d.old, &d.new) {
+            (Change::Added, _, Some(new)) => human_size(new.size),
+            (Change::Removed, Some(old), _) => human_size(old.size),
+            (Change::Modified, Some(old), Some(new)) => {
+                format!("{} → {}", human_size(old.size), human_size(new.size))
+            }
+            _ => "mtime only".to_string(),
+        };
+        let (icon, label) = match d.change {
+            Change::Added => ("➕", "added"),
+            Change::Removed => ("➖", "removed"),
+            Change::Modified => ("✏️ ", "modified"),
+            Change::Touched => ("🕒", "touched"),
+        };
+        say!("{} {:<9} {:<50} ({})", icon, label, d.path, detail);
+        if let Some(diff) = &d.diff {
+            say!("{}", diff);
+        }
+    }
+
+    say!(
+        "\n📊 {} added, {} removed, {} modified, {} touched",
+        count(Change::Added),
+        count(Change::Removed),
+        count(Change::Modified),
+        count(Change::Touched)
+    );
+}
+
+fn archive_tree(chain: &Chain) -> BTreeMap<String, Item<'_>> {
+    chain
+        .entries()
+        .into_iter()
+        .map(|(archive, entry)| {
+            let item = Item {
+                size: entry.original_len,
+                mtime: entry.mtime,
+                source: Source::Entry(archive, entry),
+            };
+            (entry.path.clone(), item)
+        })
+        .collect()
+}
+
+/// Files under `root`, keyed by the same relative paths `compress` stores.
+fn directory_tree(root: &Path) -> BTreeMap<String, Item<'static>> {
+    WalkDir::new(root)
+        .into_iter()
+        .filter_map(Result::ok)
+        .filter(|e| e.file_type().is_file())
+        .map(|e| {
+            let relative = e.path().strip_prefix(root).unwrap();
+            let item = Item {
+                size: e.metadata().map(|m| m.len()).unwrap_or_default(),
+                mtime: archive::file_mtime(e.path()),
+                source: Source::File(e.path().to_path_buf()),
+            };
+            (relative.to_string_lossy().to_string(), item)
+        })
+        .collect()
+}
+
+fn compare(
+    old: &BTreeMap<String, Item>,
+    new: &BTreeMap<String, Item>,
+    unified: bool,
+) -> Result<Vec<Difference>> {
+    let paths: BTreeSet<&String> = old.keys().chain(new.keys()).collect();
+    let mut differences = Vec::new();
+
+    for path in paths {
+        let (change, old_hash, new_hash) = match (old.get(path), new.get(path)) {
+            (None, Some(_)) => (Change::Added, None, None),
+            (Some(_), None) => (Change::Removed, None, None),
+            (Some(a), Some(b)) => {
+                // Hashes are only needed when the sizes cannot tell.
+                if a.size != b.size {
+                    (Change::Modified, None, None)
+                } else {
+                    let (old_hash, new_hash) = (a.sha256()?, b.sha256()?);
+                    let change = if old_hash != new_hash {
+                        Change::Modified
+                    } else if a.mtime.is_some() && b.mtime.is_some() && a.mtime != b.mtime {
+                        Change::Touched
+                    } else {
+                        continue;
+                    };
+                    (change, Some(old_hash), Some(new_hash))
+                }
+            }
+            (None, None) => unreachable!(),
+        };
+
+        let diff = match (old.get(path), new.get(path)) {
+            (Some(a), Some(b)) if unified && change == Change::Modified => text_diff(path, a, b)?,
+            _ => None,
+        };
+        let state = |item: &Item, sha256: Option<String>| FileState {
+            size: item.size,
+            mtime: item.mtime,
+            sha256: sha256.or_else(|| item.recorded_sha256()),
+        };
+        differences.push(Difference {
+            path: path.clone(),
+            change,
+            old: old.get(path).map(|item| state(item, old_hash)),
+            new: new.get(path).map(|item| state(item, new_hash)),
+            diff,
+        });
+    }
+    Ok(differences)
+}
+
+/// A unified diff of two versions of a file, if both are reasonably sized
+/// UTF-8 text.
+fn text_diff(path: &str, old: &Item, new: &Item) -> Result<Option<String>> {
+    if old.size > MAX_TEXT_DIFF_LEN || new.size > MAX_TEXT_DIFF_LEN {
+        return Ok(None);
+    }
+    let (old_data, new_data) = (old.read()?, new.read()?);
+    let (Some(old_text), Some(new_text)) = (as_text(&old_data), as_text(&new_data)) else {
+        return Ok(None);
+    };
+
+    let diff = TextDiff::from_lines(old_text, new_text)
+        .unified_diff()
+        .header(&format!("a/{}", path), &format!("b/{}", path))
+        .to_string();
+    Ok(Some(diff))
+}
+
+fn as_text(data: &[u8]) -> Option<&str> {
+    if data.contains(&0) {
+        return None;
+    }
+    std::str::from_utf8(data).ok()
+}
@@ -0,0 +1,56 @@
+use serde_json::json;
+
+use super::{exit_on_error, fail, kdf_params, new_passphrase, recipients};
+use crate::archive;
+use crate::cli::{KdfArgs, PasswordArgs};
+use crate::encrypt::Cipher;
+use crate::keys::Envelope;
+use crate::output::{self, say};
+
+/// Encrypts a plaintext archive in place. The compressed data is encrypted
+/// as it is, without decompressing any entry.
+pub fn run(
+    archive_path: &str,
+    password: &PasswordArgs,
+    recipient: Vec<String>,
+    kdf: &KdfArgs,
+    cipher: Cipher,
+) {
+    let header = exit_on_error(archive::read_header(archive_path));
+    if matches!(&header, Some(h) if h.encrypted) {
+        fail("Archive is already encrypted; use `ubl rekey` to change its keys.");
+    }
+
+    let passphrase = new_passphrase(password);
+    let recipient_keys = recipients(&recipient);
+    let kdf = kdf_params(kdf);
+    let Some(envelope) = exit_on_error(Envelope::seal(
+        cipher,
+        passphrase.as_ref(),
+        &kdf,
+        &recipient_keys,
+    )) else {
+        fail("Give a password, keyfile or recipient to encrypt the archive to.");
+    };
+
+    say!("🔒 Encrypting '{}'...", archive_path);
+    if header.is_some() {
+        exit_on_error(archive::encrypt_in_place(archive_path, &envelope));
+    } else {
+        exit_on_error(archive::encrypt_legacy_in_place(archive_path, envelope));
+    }
+    say!("✅ Archive encrypted.");
+    let signature_removed = header.is_some_and(|h| h.signature.is_some());
+    if signature_removed {
+        say!("⚠️  The archive's signature was removed; sign it again with `ubl sign`.");
+    }
+    output::summary(
+        true,
+        json!({
+            "archive": archive_path,
+            "recipients": recipient_keys.len(),
+            "password": passphrase.is_some(),
+            "signature_removed": signature_removed,
+        }),
+    );
+}
@@ -1,31 +1,98 @@
+use anyhow::{bail, Context, Result};
 use indicatif::{ProgressBar, ProgressStyle};
-use std::fs::{create_dir_all, File};
-use std::io::{BufRead, BufWriter, Cursor, Read};
-use std::path::Path;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::fmt::Write as _;
+use std::fs::{self, create_dir_all, File};
+use std::io::{BufWriter, Read, Write};
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
 use std::time::Instant;
-use zstd::stream::Decoder;
 
-use crate::encrypt;
+use super::{credentials, exit_on_error, fail, human_size};
+use crate::archive::{Archive, Entry};
+use crate::chain::Chain;
+use crate::cli::PasswordArgs;
+use crate::output::{self, say};
+use crate::salvage::{self, Lost, Salvage};
 
-pub fn run(archive_path: &str, password: Option<String>, output: Option<String>) {
+#[derive(Serialize)]
+struct ExtractedFile<'a> {
+    path: &'a str,
+    /// `restored` or `lost`.
+    status: &'static str,
+    size: Option<u64>,
+    error: Option<&'a str>,
+}
+
+#[derive(Serialize)]
+struct ExtractSummary<'a> {
+    archive: &'a str,
+    output_dir: &'a str,
+    restored: usize,
+    lost: usize,
+    /// The salvage report, with `--salvage`.
+    report: Option<&'a str>,
+}
+
+pub fn run(
+    archive_path: &str,
+    password: &PasswordArgs,
+    identity: Vec<String>,
+    output: Option<String>,
+    salvage: bool,
+) {
     let start = Instant::now();
-    let mut file = File::open(archive_path).unwrap();
-    let mut full_data = Vec::new();
-    file.read_to_end(&mut full_data).unwrap();
 
-    let raw_data = if let Some(pass) = password {
-        println!("🔐 Decrypting archive...");
-        let salt = &full_data[..16];
-        let nonce = &full_data[16..28];
-        let ciphertext = &full_data[28..];
+    let credentials = credentials(archive_path, password, &identity);
+    if !credentials.is_empty() {
+        say!("🔐 Decrypting archive...");
+    }
 
-        encrypt::decrypt(salt, nonce, ciphertext, &pass)
+    let chain;
+    let salvaged = salvage.then(|| {
+        say!("🩹 Scanning for readable files...");
+        exit_on_error(salvage::salvage(archive_path, &credentials))
+    });
+    let entries: Vec<(&Archive, &Entry)> = if let Some(salvaged) = &salvaged {
+        if salvaged.header_lost {
+            say!("⚠️  Archive header is damaged; scanned the whole file.");
+        }
+        if salvaged.index_lost {
+            say!("⚠️  Archive index is damaged; files are named from their own records.");
+        }
+        if let Some(base) = &salvaged.base {
+            say!(
+                "⚠️  This is an incremental of '{}'; files only stored there are not salvaged.",
+                base.path
+            );
+        }
+        let mut entries: Vec<_> = salvaged
+            .archive
+            .entries
+            .iter()
+            .map(|entry| (&salvaged.archive, entry))
+            .collect();
+        entries.sort_by(|a, b| a.1.path.cmp(&b.1.path));
+        entries
     } else {
-        full_data
+        chain = exit_on_error(Chain::open(archive_path, &credentials));
+        if let Some(path) = &chain.archives[0].truncated {
+            fail(format!(
+                "Archive is truncated at entry '{}'; use --salvage to restore the files before it.",
+                path
+            ));
+        }
+        if chain.archives.len() > 1 {
+            say!(
+                "🔗 Reconstructing from a chain of {} archives",
+                chain.archives.len()
+            );
+        }
+        chain.entries()
     };
-
-    let mut reader = Cursor::new(raw_data);
-    println!("📦 Extracting...");
+    say!("📦 Extracting...");
 
     let archive_file = Path::new(archive_path);
     let default_output_dir = archive_file
@@ -35,29 +102,7 @@ pub fn run(archive_path: &str, password: Option<String>, output: Option<String>)
 
     let base_output_dir = output.unwrap_or(default_output_dir);
 
-    let mut files = vec![];
-
-    // First pass: count entries for progress bar
-    {
-        let mut preview = reader.clone();
-        loop {
-            let mut path_len_buf = [0u8; 4];
-            if preview.read_exact(&mut path_len_buf).is_err() {
-                break;
-            }
-            let path_len = u32::from_le_bytes(path_len_buf);
-            preview.consume(path_len as usize + 8 + 8); // path + original_len + compressed_len
-
-            let mut compressed_len_buf = [0u8; 8];
-            preview.read_exact(&mut compressed_len_buf).unwrap();
-            let compressed_len = u64::from_le_bytes(compressed_len_buf);
-            preview.consume(compressed_len as usize);
-
-            files.push(()); // Push dummy to count
-        }
-    }
-
-    let pb = ProgressBar::new(files.len() as u64);
+    let pb = ProgressBar::new(entries.len() as u64);
     pb.set_style(
         ProgressStyle::with_template(
             "{spinner:.green} [{elapsed_precise}] [{bar:40.magenta/blue}] {pos}/{len} {msg}",
@@ -66,44 +111,277 @@ pub fn run(archive_path: &str, password: Option<String>, output: Option<String>)
         .progress_chars("#>-"),
     );
 
-    loop {
-        let mut path_len_buf = [0u8; 4];
-        if reader.read_exact(&mut path_len_buf).is_err() {
-            break;
+    // Links are created last, so no file is written through a link that
+    // points outside the output directory.
+    let mut symlinks = Vec::new();
+    let mut lost = Vec::new();
+    let mut restored = 0;
+    let mut finish = |entry: &Entry, output_path: &Path, result: Result<()>| match result {
+        Ok(()) => {
+            restored += 1;
+            output::file(ExtractedFile {
+                path: &entry.path,
+                status: "restored",
+                size: Some(entry.original_len),
+                error: None,
+            });
+        }
+        Err(e) if salvage => {
+            let _ = fs::remove_file(output_path);
+            let reason = format!("{:#}", e);
+            output::file(ExtractedFile {
+                path: &entry.path,
+                status: "lost",
+                size: None,
+                error: Some(&reason),
+            });
+            lost.push(Lost {
+                path: entry.path.clone(),
+                reason,
+            });
         }
+        Err(e) => {
+            pb.abandon();
+            let _ = fs::remove_file(output_path);
+            fail(format!(
+                    "Failed to extract '{}': {:#}. Use --salvage to restore the readable files of a damaged archive.",
+                    entry.path, e
+                ));
+        }
+    };
+    for (archive, entry) in entries {
+        let output_path = Path::new(&base_output_dir).join(&entry.path);
 
-        let path_len = u32::from_le_bytes(path_len_buf);
-        let mut path_buf = vec![0u8; path_len as usize];
-        reader.read_exact(&mut path_buf).unwrap();
-        let path = String::from_utf8(path_buf).unwrap();
+        let result = restore_entry(archive, entry, &output_path, &mut symlinks);
+        // Links are reported once they are created.
+        if result.is_err() || !entry.is_symlink() {
+            finish(entry, &output_path, result);
+        }
 
-        let mut original_len_buf = [0u8; 8];
-        reader.read_exact(&mut original_len_buf).unwrap();
+        pb.set_message(entry.path.clone());
+        pb.inc(1);
+    }
 
-        let mut compressed_len_buf = [0u8; 8];
-        reader.read_exact(&mut compressed_len_buf).unwrap();
-        let compressed_len = u64::from_le_bytes(compressed_len_buf);
+    for (entry, target, path) in symlinks {
+        finish(entry, &path, restore_symlink(&target, &path));
+    }
 
-        let mut compressed_data = vec![0u8; compressed_len as usize];
-        reader.read_exact(&mut compressed_data).unwrap();
+    pb.finish_with_message("🎉 Extraction complete");
+    let duration = start.elapsed();
 
-        let output_path = Path::new(&base_output_dir).join(&path);
+    let Some(mut salvaged) = salvaged else {
+        say!("✅ All files restored to '{}'", base_output_dir);
+        say!("🕒 Completed in {:.2?}", duration);
+        output::summary(
+            true,
+            ExtractSummary {
+                archive: archive_path,
+                output_dir: &base_output_dir,
+                restored,
+                lost: 0,
+                report: None,
+            },
+        );
+        return;
+    };
 
-        if let Some(parent) = output_path.parent() {
-            create_dir_all(parent).unwrap();
+    for file in &salvaged.lost {
+        output::file(ExtractedFile {
+            path: &file.path,
+            status: "lost",
+            size: None,
+            error: Some(&file.reason),
+        });
+    }
+    lost.splice(0..0, std::mem::take(&mut salvaged.lost));
+    let report_path = format!(
+        "{}.salvage.txt",
+        base_output_dir.trim_end_matches(['/', '\\'])
+    );
+    let report = salvage_report(archive_path, &salvaged, restored, &lost);
+    exit_on_error(
+        fs::write(&report_path, report)
+            .with_context(|| format!("Failed to write '{}'", report_path)),
+    );
+
+    say!("✅ {} files restored to '{}'", restored, base_output_dir);
+    say!("🕒 Completed in {:.2?}", duration);
+    let complete = lost.is_empty() && salvaged.orphaned.0 == 0;
+    if complete {
+        say!("📝 Nothing was lost; report written to '{}'", report_path);
+    } else {
+        eprintln!(
+            "⚠️  {} files could not be restored; see '{}'",
+            lost.len(),
+            report_path
+        );
+    }
+    output::summary(
+        complete,
+        ExtractSummary {
+            archive: archive_path,
+            output_dir: &base_output_dir,
+            restored,
+            lost: lost.len(),
+            report: Some(&report_path),
+        },
+    );
+}
+
+/// Writes one entry below the output directory, checking its contents
+/// against the hash stored for the entry. Links are only collected, to be
+/// created last.
+fn restore_entry<'a>(
+    archive: &Archive,
+    entry: &'a Entry,
+    output_path: &Path,
+    symlinks: &mut Vec<(&'a Entry, String, PathBuf)>,
+) -> Result<()> {
+    if let Some(parent) = output_path.parent() {
+        create_dir_all(parent)
+            .with_context(|| format!("Failed to create '{}'", parent.display()))?;
+    }
+
+    let mut decoder = archive.decoder(entry)?;
+    if entry.is_symlink() {
+        let mut target = String::new();
+        decoder
+            .read_to_string(&mut target)
+            .context("Failed to decompress link target")?;
+        check_hash(entry, Sha256::digest(&target))?;
+        symlinks.push((entry, target, output_path.to_path_buf()));
+        return Ok(());
+    }
+
+    let file = File::create(output_path)
+        .with_context(|| format!("Failed to create '{}'", output_path.display()))?;
+    let mut outfile = BufWriter::new(file);
+    let mut sha = Sha256::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let n = decoder.read(&mut buf).context("Failed to decompress")?;
+        if n == 0 {
+            break;
         }
+        sha.update(&buf[..n]);
+        outfile.write_all(&buf[..n])?;
+    }
+    outfile.flush()?;
+    check_hash(entry, sha.finalize())?;
+    #[cfg(unix)]
+    if let Some(mode) = entry.mode {
+        fs::set_permissions(output_path, fs::Permissions::from_mode(mode))?;
+    }
+    Ok(())
+}
 
-        let mut decoder = Decoder::new(&compressed_data[..]).unwrap();
-        let mut outfile = BufWriter::new(File::create(&output_path).unwrap());
-        std::io::copy(&mut decoder, &mut outfile).unwrap();
+/// Fails if `hash` is not the SHA-256 stored for the entry. Entries from
+/// archives that predate stored hashes are not checked.
+fn check_hash(entry: &Entry, hash: impl std::fmt::LowerHex) -> Result<()> {
+    match &entry.sha256 {
+        Some(expected) if format!("{:x}", hash) != *expected => {
+            bail!("Contents do not match the stored SHA-256")
+        }
+        _ => Ok(()),
+    }
+}
 
-        pb.set_message(path.clone());
-        pb.inc(1);
+/// Describes what `extract --salvage` restored and what was lost.
+fn salvage_report(
+    archive_path: &str,
+    salvaged: &Salvage,
+    restored: usize,
+    lost: &[Lost],
+) -> String {
+    let mut report = String::new();
+    writeln!(report, "Salvage report for '{}'", archive_path).unwrap();
+    writeln!(report).unwrap();
+    writeln!(report, "Restored files: {}", restored).unwrap();
+    writeln!(report, "Lost files:     {}", lost.len()).unwrap();
+    for file in lost {
+        writeln!(report, "  {}: {}", file.path, file.reason).unwrap();
     }
 
-    pb.finish_with_message("🎉 Extraction complete");
-    let duration = start.elapsed();
+    if salvaged.header_lost {
+        writeln!(report, "\nThe archive header was damaged.").unwrap();
+    }
+    if salvaged.index_lost {
+        writeln!(
+            report,
+            "\nThe index was damaged. Files whose own records were also damaged are not listed above."
+        )
+        .unwrap();
+    }
+    let (orphaned, orphaned_size) = salvaged.orphaned;
+    if orphaned > 0 {
+        writeln!(
+            report,
+            "\n{} readable chunks ({}) belong to files whose names were lost.",
+            orphaned,
+            human_size(orphaned_size)
+        )
+        .unwrap();
+    }
+    if !salvaged.damaged.is_empty() {
+        writeln!(report, "\nDamaged byte ranges of the archive payload:").unwrap();
+        for (start, end) in &salvaged.damaged {
+            writeln!(report, "  {}..{} ({})", start, end, human_size(end - start)).unwrap();
+        }
+    }
+    if let Some(base) = &salvaged.base {
+        writeln!(
+            report,
+            "\nThis is an incremental of '{}'; files only stored there were not salvaged.",
+            base.path
+        )
+        .unwrap();
+    }
+    report
+}
+
+/// Recreates a symbolic link, replacing any file already at `path`, but not
+/// a directory. Where links are not supported, the target is written as the
+/// file contents.
+fn restore_symlink(target: &str, path: &Path) -> Result<()> {
+    match path.symlink_metadata() {
+        Ok(meta) if meta.is_dir() => {
+            bail!(
+                "Failed to create link '{}': a directory is in the way",
+                path.display()
+            )
+        }
+        Ok(_) => fs::remove_file(path)
+            .with_context(|| format!("Failed to replace '{}'", path.display()))?,
+        Err(_) => {}
+    }
+    #[cfg(unix)]
+    std::os::unix::fs::symlink(target, path)
+        .with_context(|| format!("Failed to create link '{}'", path.display()))?;
+    #[cfg(not(unix))]
+    fs::write(path, target).with_context(|| format!("Failed to create '{}'", path.display()))?;
+    Ok(())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
 
-    println!("✅ All files restored to '{}'", base_output_dir);
-    println!("🕒 Completed in {:.2?}", duration);
+    #[test]
+    fn link_replaces_a_file_but_not_a_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("file");
+        fs::write(&file, b"old").unwrap();
+        restore_symlink("target", &file).unwrap();
+        assert_eq!(fs::read_link(&file).unwrap(), Path::new("target"));
+
+        let subdir = dir.path().join("dir");
+        fs::create_dir(&subdir).unwrap();
+        let err = restore_symlink("target", &subdir).err().unwrap();
+        assert!(
+            err.to_string().contains("directory is in the way"),
+            "{}",
+            err
+        );
+        assert!(subdir.is_dir());
+    }
 }
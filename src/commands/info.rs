@@ -0,0 +1,257 @@
+use chrono::{DateTime, Local};
+use serde::Serialize;
+
+use super::{exit_on_error, human_size, listing_credentials};
+use crate::archive::{self, BaseRef, SignatureStatus};
+use crate::cli::PasswordArgs;
+use crate::encrypt::{Cipher, KdfParams};
+use crate::keys::KeySlot;
+use crate::output;
+use crate::volume::ArchiveFile;
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum SlotInfo {
+    Password { kdf: KdfParams },
+    X25519,
+}
+
+#[derive(Serialize)]
+struct SignatureInfo {
+    signer: String,
+    valid: bool,
+}
+
+#[derive(Serialize)]
+struct EntryStats {
+    files: usize,
+    deleted: usize,
+    original_size: u64,
+    /// Unknown when only the index could be read.
+    chunks: Option<usize>,
+    stored_size: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct Info {
+    archive: String,
+    file_size: u64,
+    /// Number of files a split archive is stored in; 1 otherwise.
+    volumes: usize,
+    /// Parity of the recovery record as a percentage, if there is one.
+    recovery: Option<u8>,
+    /// 1 for archives written before the header was introduced.
+    format_version: u16,
+    id: Option<String>,
+    /// Seconds since the Unix epoch.
+    created: Option<u64>,
+    creator: Option<String>,
+    compression: String,
+    dictionary: bool,
+    encrypted: bool,
+    cipher: Option<String>,
+    metadata_visible: bool,
+    header_authenticated: bool,
+    key_slots: Vec<SlotInfo>,
+    base: Option<BaseRef>,
+    signature: Option<SignatureInfo>,
+    /// Absent when the index is encrypted and no credentials were given.
+    entries: Option<EntryStats>,
+}
+
+/// Summarizes an archive from its header and index, without decompressing
+/// any file contents.
+pub fn run(archive_path: &str, password: &PasswordArgs, identity: Vec<String>) {
+    let file = exit_on_error(ArchiveFile::open(archive_path));
+    let header = exit_on_error(archive::read_header(archive_path));
+    let credentials = listing_credentials(archive_path, password, &identity);
+    let contents = exit_on_error(archive::read_contents(archive_path, &credentials));
+
+    let signature = match exit_on_error(archive::check_signature(archive_path)) {
+        SignatureStatus::Unsigned => None,
+        SignatureStatus::Valid(signer) => Some(SignatureInfo {
+            signer: signer.to_string(),
+            valid: true,
+        }),
+        SignatureStatus::Invalid(signer) => Some(SignatureInfo {
+            signer,
+            valid: false,
+        }),
+    };
+
+    let entries = contents.map(|contents| {
+        let live = contents.entries.iter().filter(|e| !e.is_deleted());
+        EntryStats {
+            files: live.clone().count(),
+            deleted: contents.entries.iter().filter(|e| e.is_deleted()).count(),
+            original_size: live.map(|e| e.original_len).sum(),
+            chunks: contents.chunks.map(|(count, _)| count),
+            stored_size: contents.chunks.map(|(_, stored)| stored),
+        }
+    });
+
+    let info = match header {
+        Some(header) => {
+            let mut key_slots: Vec<SlotInfo> = header
+                .key_slots
+                .iter()
+                .map(|slot| match slot {
+                    KeySlot::Password { kdf, .. } => SlotInfo::Password { kdf: *kdf },
+                    KeySlot::X25519 { .. } => SlotInfo::X25519,
+                })
+                .collect();
+            // Encrypted archives without slots derive the key from the
+            // password directly.
+            if header.encrypted && key_slots.is_empty() {
+                key_slots.push(SlotInfo::Password {
+                    kdf: KdfParams::LEGACY,
+                });
+            }
+            Info {
+                archive: archive_path.to_string(),
+                file_size: file.disk_len(),
+                volumes: file.volume_count(),
+                recovery: file.recovery().map(|t| t.percent),
+                format_version: header.version,
+                id: header.id,
+                created: header.created,
+                creator: header.creator,
+                compression: "zstd".to_string(),
+                dictionary: header.dictionary,
+                encrypted: header.encrypted,
+                cipher: header
+                    .encrypted
+                    .then(|| header.cipher.unwrap_or_default().to_string()),
+                metadata_visible: header.metadata_visible,
+                header_authenticated: header.header_tag.is_some(),
+                key_slots,
+                base: header.base,
+                signature,
+                entries,
+            }
+        }
+        None => Info {
+            archive: archive_path.to_string(),
+            file_size: file.disk_len(),
+            volumes: file.volume_count(),
+            recovery: file.recovery().map(|t| t.percent),
+            format_version: 1,
+            id: None,
+            created: None,
+            creator: None,
+            compression: "zstd".to_string(),
+            dictionary: false,
+            encrypted: !credentials.is_empty(),
+            cipher: (!credentials.is_empty()).then(|| Cipher::default().to_string()),
+            metadata_visible: false,
+            header_authenticated: false,
+            key_slots: Vec::new(),
+            base: None,
+            signature,
+            entries,
+        },
+    };
+
+    if output::is_text() {
+        print_info(&info);
+    }
+    output::summary(true, &info);
+}
+
+fn print_info(info: &Info) {
+    let yes_no = |flag: bool| if flag { "yes" } else { "no" };
+
+    println!("ℹ️  Archive '{}'\n", info.archive);
+    let version = match info.format_version {
+        1 => "1 (no header)".to_string(),
+        v => v.to_string(),
+    };
+    println!("{:<16} {}", "Format version", version);
+    println!("{:<16} {}", "File size", human_size(info.file_size));
+    if info.volumes > 1 {
+        println!("{:<16} {}", "Volumes", info.volumes);
+    }
+    if let Some(percent) = info.recovery {
+        println!("{:<16} {}% parity", "Recovery record", percent);
+    }
+    if let Some(id) = &info.id {
+        println!("{:<16} {}", "Id", id);
+    }
+    if let Some(created) = info.created {
+        let time = DateTime::from_timestamp(created as i64, 0)
+            .map(|t| {
+                t.with_timezone(&Local)
+                    .format("%Y-%m-%d %H:%M:%S")
+                    .to_string()
+            })
+            .unwrap_or_else(|| created.to_string());
+        println!("{:<16} {}", "Created", time);
+    }
+    if let Some(creator) = &info.creator {
+        println!("{:<16} {}", "Creator", creator);
+    }
+    let compression = if info.dictionary {
+        format!("{} with trained dictionary", info.compression)
+    } else {
+        info.compression.clone()
+    };
+    println!("{:<16} {}", "Compression", compression);
+
+    match &info.cipher {
+        Some(cipher) => println!("{:<16} yes, {}", "Encrypted", cipher),
+        None => println!("{:<16} no", "Encrypted"),
+    }
+    if info.encrypted {
+        println!(
+            "{:<16} {}",
+            "Metadata visible",
+            yes_no(info.metadata_visible)
+        );
+        println!("{:<16} {}", "Header tag", yes_no(info.header_authenticated));
+        for (i, slot) in info.key_slots.iter().enumerate() {
+            let label = if i == 0 { "Key slots" } else { "" };
+            match slot {
+                SlotInfo::Password { kdf } => println!("{:<16} password ({})", label, kdf),
+                SlotInfo::X25519 => println!("{:<16} x25519 recipient", label),
+            }
+        }
+    }
+    if let Some(base) = &info.base {
+        println!("{:<16} '{}' (id {})", "Incremental of", base.path, base.id);
+    }
+    match &info.signature {
+        Some(s) if s.valid => println!("{:<16} ✅ valid, by {}", "Signature", s.signer),
+        Some(s) => println!("{:<16} ❌ INVALID, claims {}", "Signature", s.signer),
+        None => println!("{:<16} none", "Signature"),
+    }
+
+    println!();
+    let Some(stats) = &info.entries else {
+        println!("🔒 Index is encrypted; give a password or identity for entry statistics.");
+        return;
+    };
+    println!("{:<16} {}", "Files", stats.files);
+    if stats.deleted > 0 {
+        println!("{:<16} {}", "Deletions", stats.deleted);
+    }
+    println!(
+        "{:<16} {}",
+        "Original size",
+        human_size(stats.original_size)
+    );
+    match (stats.chunks, stats.stored_size) {
+        (Some(chunks), Some(stored)) => {
+            println!("{:<16} {}", "Chunks", chunks);
+            let ratio = if stats.original_size > 0 {
+                format!(
+                    " ({:.1}% of original)",
+                    stored as f64 / stats.original_size as f64 * 100.0
+                )
+            } else {
+                String::new()
+            };
+            println!("{:<16} {}{}", "Stored size", human_size(stored), ratio);
+        }
+        _ => println!("{:<16} encrypted", "Stored size"),
+    }
+}
@@ -0,0 +1,68 @@
+use std::fs::OpenOptions;
+use std::io::Write;
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+
+use serde_json::json;
+use zeroize::Zeroizing;
+
+use super::fail;
+use crate::keys::{Identity, SigningIdentity};
+use crate::output::{self, say};
+
+pub fn run(output: Option<String>, sign: bool) {
+    // The secret key is the output itself; it cannot share stdout with a report.
+    if output.is_none() && !output::is_text() {
+        fail("--format json and ndjson need the key written to a file with -o.");
+    }
+    let (public, secret, kind) = if sign {
+        let key = SigningIdentity::generate();
+        (
+            key.public().to_string(),
+            key.to_secret_string(),
+            "Signing key",
+        )
+    } else {
+        let identity = Identity::generate();
+        (
+            identity.public().to_string(),
+            identity.to_secret_string(),
+            "Identity",
+        )
+    };
+    let contents = Zeroizing::new(format!(
+        "# created: {}\n# public key: {}\n{}\n",
+        chrono::Local::now().format("%Y-%m-%dT%H:%M:%S%:z"),
+        public,
+        *secret
+    ));
+
+    let Some(path) = output else {
+        print!("{}", *contents);
+        return;
+    };
+
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+
+    let written = options
+        .open(&path)
+        .and_then(|mut file| file.write_all(contents.as_bytes()));
+    if let Err(e) = written {
+        fail(format!(
+            "Failed to write {} to '{}': {}",
+            kind.to_lowercase(),
+            path,
+            e
+        ));
+    }
+
+    say!("🔑 {} written to '{}'", kind, path);
+    say!("Public key: {}", public);
+    output::summary(
+        true,
+        json!({ "kind": if sign { "signing" } else { "identity" }, "path": path, "public_key": public }),
+    );
+}
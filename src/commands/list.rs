@@ -1,120 +1,626 @@
-use std::fs::File;
-use std::io::{BufReader, Cursor, Read};
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
 
-use crate::encrypt;
+use chrono::{DateTime, Local};
+use glob::Pattern;
+use serde::Serialize;
 
-fn human_size(bytes: u64) -> String {
-    const KB: u64 = 1024;
-    const MB: u64 = 1024 * KB;
-    const GB: u64 = 1024 * MB;
+use super::{exit_on_error, fail, human_size, listing_credentials};
+use crate::archive::{Archive, BaseRef, Entry, EntryKind, Owner};
+use crate::cli::{PasswordArgs, SortKey};
+use crate::output::{self, say};
 
-    match bytes {
-        b if b >= GB => format!("{:.2} GB", b as f64 / GB as f64),
-        b if b >= MB => format!("{:.2} MB", b as f64 / MB as f64),
-        b if b >= KB => format!("{:.2} KB", b as f64 / KB as f64),
-        _ => format!("{} B", bytes), // FIXED: use `bytes` here
-    }
+/// Hex digits of the SHA-256 shown by `--long`.
+const SHORT_HASH_LEN: usize = 12;
+
+#[derive(Serialize)]
+struct ListedFile<'a> {
+    path: &'a str,
+    kind: EntryKind,
+    size: u64,
+    /// Unknown for deletions and for contents listed without the key.
+    compressed_size: Option<u64>,
+    mtime: Option<u64>,
+    mode: Option<u32>,
+    owner: Option<Owner>,
+    sha256: Option<&'a str>,
 }
 
-pub fn run(archive_path: &str, password: Option<String>) {
-    let archive_file = match File::open(archive_path) {
-        Ok(f) => f,
-        Err(e) => {
-            eprintln!("❌ Failed to open archive '{}': {}", archive_path, e);
-            return;
-        }
-    };
+#[derive(Serialize)]
+struct ListSummary<'a> {
+    archive: &'a str,
+    files: u64,
+    deleted: u64,
+    original_size: u64,
+    compressed_size: Option<u64>,
+    metadata_visible: bool,
+    base: Option<&'a BaseRef>,
+}
+
+/// How a column is padded.
+#[derive(Clone, Copy)]
+enum Align {
+    Left,
+    Right,
+}
 
-    let mut full_data = Vec::new();
-    let mut reader = BufReader::new(archive_file);
-    if reader.read_to_end(&mut full_data).is_err() {
-        eprintln!("❌ Failed to read archive.");
-        return;
+/// What files and directories are ordered by.
+struct SortFields<'a> {
+    name: &'a str,
+    size: u64,
+    compressed: Option<u64>,
+    mtime: Option<u64>,
+}
+
+impl SortFields<'_> {
+    fn ratio(&self) -> Option<f64> {
+        ratio(self.size, self.compressed)
     }
+}
 
-    let raw_data = if let Some(pass) = password {
-        if full_data.len() < 28 {
-            eprintln!("❌ Archive is too small or corrupted.");
-            return;
+/// A directory of the `--tree` view, with the totals of everything below it.
+#[derive(Default)]
+struct Dir<'a> {
+    dirs: BTreeMap<&'a str, Dir<'a>>,
+    files: Vec<(&'a str, &'a Entry)>,
+    file_count: u64,
+    original: u64,
+    compressed: u64,
+    mtime: Option<u64>,
+}
+
+impl<'a> Dir<'a> {
+    fn insert(&mut self, path: &'a str, entry: &'a Entry, compressed: u64) {
+        if !entry.is_deleted() {
+            self.file_count += 1;
+            self.original += entry.original_len;
+            self.compressed += compressed;
+            self.mtime = self.mtime.max(entry.mtime);
+        }
+        match path.split_once('/') {
+            Some((dir, rest)) => self
+                .dirs
+                .entry(dir)
+                .or_default()
+                .insert(rest, entry, compressed),
+            None => self.files.push((path, entry)),
         }
+    }
 
-        println!("🔐 Decrypting archive...");
+    fn sort_fields<'b>(&self, name: &'b str, locked: bool) -> SortFields<'b> {
+        SortFields {
+            name,
+            size: self.original,
+            compressed: (!locked).then_some(self.compressed),
+            mtime: self.mtime,
+        }
+    }
+}
 
-        let salt = &full_data[..16];
-        let nonce = &full_data[16..28];
-        let ciphertext = &full_data[28..];
+pub fn run(
+    archive_path: &str,
+    patterns: &[String],
+    password: &PasswordArgs,
+    identity: Vec<String>,
+    long: bool,
+    tree: bool,
+    sort: Option<SortKey>,
+) {
+    let patterns: Vec<Pattern> = patterns
+        .iter()
+        .map(|p| {
+            Pattern::new(p).unwrap_or_else(|e| fail(format!("Invalid pattern '{}': {}", p, e)))
+        })
+        .collect();
+    let credentials = listing_credentials(archive_path, password, &identity);
+    if !credentials.is_empty() {
+        say!("🔐 Decrypting archive...");
+    }
 
-        encrypt::decrypt(salt, nonce, ciphertext, &pass)
-    } else {
-        full_data
+    let archive = exit_on_error(Archive::open_index(archive_path, &credentials));
+    let compressed_len = |entry: &Entry| {
+        (!entry.is_deleted() && !archive.locked).then(|| archive.compressed_len(entry))
     };
 
-    let mut cursor = Cursor::new(raw_data);
+    let mut entries: Vec<&Entry> = archive
+        .entries
+        .iter()
+        .filter(|e| matches(&patterns, &e.path))
+        .collect();
+    if let Some(key) = sort {
+        entries.sort_by(|a, b| {
+            compare(
+                key,
+                &entry_sort_fields(a, compressed_len(a)),
+                &entry_sort_fields(b, compressed_len(b)),
+            )
+        });
+    }
+
     let mut total_original = 0u64;
     let mut total_compressed = 0u64;
     let mut file_count = 0u64;
+    for entry in &entries {
+        output::file(ListedFile {
+            path: &entry.path,
+            kind: entry.kind,
+            size: entry.original_len,
+            compressed_size: compressed_len(entry),
+            mtime: entry.mtime,
+            mode: entry.mode,
+            owner: entry.owner,
+            sha256: entry.sha256.as_deref(),
+        });
+        if !entry.is_deleted() {
+            total_original += entry.original_len;
+            total_compressed += compressed_len(entry).unwrap_or(0);
+            file_count += 1;
+        }
+    }
 
-    println!("\n📦 Contents of '{}':\n", archive_path);
-    println!(
-        "{:<40} {:>15} {:>15}",
-        "Path", "Original Size", "Compressed Size"
+    say!("\n📦 Contents of '{}':\n", archive_path);
+    if archive.metadata_visible {
+        say!("🔓 Metadata visible: names and sizes are stored unencrypted, contents are encrypted.\n");
+    }
+    if let Some(base) = &archive.base {
+        say!(
+            "🔗 Incremental backup of '{}' (id {})\n",
+            base.path,
+            base.id
+        );
+    }
+
+    let total_compressed_text = if archive.locked {
+        "encrypted".to_string()
+    } else {
+        human_size(total_compressed)
+    };
+    if output::is_text() {
+        let table = if tree {
+            tree_table(&archive, &entries, sort, archive_path)
+        } else if long {
+            long_table(&archive, &entries)
+        } else {
+            short_table(&archive, &entries)
+        };
+        let total = table.total_columns.map(|(path, original, compressed)| {
+            let mut total = vec![String::new(); table.header.len()];
+            total[path] = "TOTAL".to_string();
+            total[original] = human_size(total_original);
+            total[compressed] = total_compressed_text;
+            if let Some(column) = table.ratio_column {
+                total[column] = ratio_text(
+                    total_original,
+                    (!archive.locked).then_some(total_compressed),
+                );
+            }
+            total
+        });
+        print_table(&table, total.as_deref());
+    }
+
+    // Deduplication covers the whole archive, so it is left out of filtered listings.
+    let stored: u64 = archive.chunks.iter().map(|c| c.data.len() as u64).sum();
+    let unique: u64 = archive.chunks.iter().map(|c| c.original_len).sum();
+    let references: usize = archive.entries.iter().map(|e| e.chunks.len()).sum();
+    if patterns.is_empty() && !archive.locked && references > archive.chunks.len() {
+        say!(
+            "🧩 Deduplication: {} chunk references, {} unique — {} of data stored once, saved {} ({} on disk)",
+            references,
+            archive.chunks.len(),
+            human_size(unique),
+            human_size(total_original.saturating_sub(unique)),
+            human_size(total_compressed.saturating_sub(stored))
+        );
+    }
+    if let Some(dict) = &archive.dictionary {
+        say!("📚 Shared dictionary: {}", human_size(dict.len() as u64));
+    }
+    let deleted = entries.len() as u64 - file_count;
+    output::summary(
+        true,
+        ListSummary {
+            archive: archive_path,
+            files: file_count,
+            deleted,
+            original_size: total_original,
+            compressed_size: (!archive.locked).then_some(total_compressed),
+            metadata_visible: archive.metadata_visible,
+            base: archive.base.as_ref(),
+        },
     );
-    println!("{:-<74}", "");
+    let matched = if patterns.is_empty() {
+        String::new()
+    } else {
+        format!(
+            " (matching {} of {} entries)",
+            entries.len(),
+            archive.entries.len()
+        )
+    };
+    if deleted > 0 {
+        say!(
+            "\n📄 {} files listed, {} deleted{}.\n✅ Done.",
+            file_count,
+            deleted,
+            matched
+        );
+    } else {
+        say!("\n📄 {} files listed{}.\n✅ Done.", file_count, matched);
+    }
+}
 
-    loop {
-        let mut path_len_buf = [0u8; 4];
-        if cursor.read_exact(&mut path_len_buf).is_err() {
-            break;
-        }
+/// Whether `path`, or a directory it is in, matches one of `patterns`.
+/// With no patterns, everything matches.
+fn matches(patterns: &[Pattern], path: &str) -> bool {
+    patterns.is_empty()
+        || std::iter::successors(Some(path), |p| p.rsplit_once('/').map(|(dir, _)| dir))
+            .any(|candidate| patterns.iter().any(|p| p.matches(candidate)))
+}
 
-        let path_len = u32::from_le_bytes(path_len_buf);
-        let mut path_buf = vec![0u8; path_len as usize];
-        if cursor.read_exact(&mut path_buf).is_err() {
-            break;
-        }
+fn entry_sort_fields(entry: &Entry, compressed: Option<u64>) -> SortFields<'_> {
+    SortFields {
+        name: &entry.path,
+        size: entry.original_len,
+        compressed,
+        mtime: entry.mtime,
+    }
+}
 
-        let path = String::from_utf8(path_buf).unwrap_or_else(|_| "<invalid utf8>".into());
+/// Orders by `key`, largest, newest or worst compressed first, then by
+/// name. Unknown values come last.
+fn compare(key: SortKey, a: &SortFields, b: &SortFields) -> Ordering {
+    let by_key = match key {
+        SortKey::Name => Ordering::Equal,
+        SortKey::Size => b.size.cmp(&a.size),
+        SortKey::Mtime => b.mtime.cmp(&a.mtime),
+        SortKey::Ratio => b.ratio().partial_cmp(&a.ratio()).unwrap_or(Ordering::Equal),
+    };
+    by_key.then_with(|| a.name.cmp(b.name))
+}
 
-        let mut original_len_buf = [0u8; 8];
-        if cursor.read_exact(&mut original_len_buf).is_err() {
-            break;
-        }
-        let original_len = u64::from_le_bytes(original_len_buf);
+/// Compressed size as a fraction of the original, when both are known.
+fn ratio(original: u64, compressed: Option<u64>) -> Option<f64> {
+    compressed
+        .filter(|_| original > 0)
+        .map(|c| c as f64 / original as f64)
+}
 
-        let mut compressed_len_buf = [0u8; 8];
-        if cursor.read_exact(&mut compressed_len_buf).is_err() {
-            break;
-        }
-        let compressed_len = u64::from_le_bytes(compressed_len_buf);
-
-        // Skip the compressed data
-        if cursor
-            .by_ref()
-            .take(compressed_len)
-            .read_to_end(&mut Vec::new())
-            .is_err()
-        {
-            break;
+fn ratio_text(original: u64, compressed: Option<u64>) -> String {
+    ratio(original, compressed).map_or("-".to_string(), |r| format!("{:.0}%", r * 100.0))
+}
+
+/// Rows of text to print in aligned columns, and which columns the TOTAL
+/// row fills, if there is one: its label, original and compressed size, and
+/// ratio if shown.
+struct Table {
+    header: Vec<(&'static str, Align)>,
+    rows: Vec<Vec<String>>,
+    total_columns: Option<(usize, usize, usize)>,
+    ratio_column: Option<usize>,
+}
+
+/// Sizes of an entry as listed: original and compressed.
+fn size_texts(archive: &Archive, entry: &Entry) -> (String, String) {
+    if entry.is_deleted() {
+        ("deleted".to_string(), "-".to_string())
+    } else if archive.locked {
+        (human_size(entry.original_len), "encrypted".to_string())
+    } else {
+        (
+            human_size(entry.original_len),
+            human_size(archive.compressed_len(entry)),
+        )
+    }
+}
+
+fn short_table(archive: &Archive, entries: &[&Entry]) -> Table {
+    let rows = entries
+        .iter()
+        .map(|entry| {
+            let (original, compressed) = size_texts(archive, entry);
+            vec![entry.path.clone(), original, compressed]
+        })
+        .collect();
+    Table {
+        header: vec![
+            ("Path", Align::Left),
+            ("Original Size", Align::Right),
+            ("Compressed Size", Align::Right),
+        ],
+        rows,
+        total_columns: Some((0, 1, 2)),
+        ratio_column: None,
+    }
+}
+
+fn long_table(archive: &Archive, entries: &[&Entry]) -> Table {
+    let rows = entries
+        .iter()
+        .map(|entry| {
+            let (original, compressed) = size_texts(archive, entry);
+            let compressed_len =
+                (!entry.is_deleted() && !archive.locked).then(|| archive.compressed_len(entry));
+            let hash = entry
+                .sha256
+                .as_deref()
+                .map_or("-", |h| &h[..SHORT_HASH_LEN.min(h.len())]);
+            vec![
+                mode_text(entry),
+                entry.owner.map_or("-".to_string(), |o| o.to_string()),
+                original,
+                compressed,
+                if entry.is_deleted() {
+                    "-".to_string()
+                } else {
+                    ratio_text(entry.original_len, compressed_len)
+                },
+                method(archive, entry).to_string(),
+                entry.mtime.map_or("-".to_string(), time_text),
+                hash.to_string(),
+                entry.path.clone(),
+            ]
+        })
+        .collect();
+    Table {
+        header: vec![
+            ("Mode", Align::Left),
+            ("Owner", Align::Left),
+            ("Size", Align::Right),
+            ("Compressed", Align::Right),
+            ("Ratio", Align::Right),
+            ("Method", Align::Left),
+            ("Modified", Align::Left),
+            ("SHA-256", Align::Left),
+            ("Path", Align::Left),
+        ],
+        rows,
+        total_columns: Some((8, 2, 3)),
+        ratio_column: Some(4),
+    }
+}
+
+/// Lays the entries out as a tree. Directories come before files; both are
+/// ordered by `sort`, or by name.
+fn tree_table(archive: &Archive, entries: &[&Entry], sort: Option<SortKey>, root: &str) -> Table {
+    let mut tree = Dir::default();
+    for entry in entries {
+        let compressed = if entry.is_deleted() || archive.locked {
+            0
+        } else {
+            archive.compressed_len(entry)
+        };
+        tree.insert(&entry.path, entry, compressed);
+    }
+    let mut rows = Vec::new();
+    rows.push(dir_row(archive, &tree, root.to_string()));
+    tree_rows(archive, &tree, sort.unwrap_or(SortKey::Name), "", &mut rows);
+    Table {
+        header: vec![
+            ("Path", Align::Left),
+            ("Files", Align::Right),
+            ("Original Size", Align::Right),
+            ("Compressed Size", Align::Right),
+        ],
+        rows,
+        // The root row already holds the totals.
+        total_columns: None,
+        ratio_column: None,
+    }
+}
+
+fn dir_row(archive: &Archive, dir: &Dir, label: String) -> Vec<String> {
+    let compressed = if archive.locked {
+        "encrypted".to_string()
+    } else {
+        human_size(dir.compressed)
+    };
+    vec![
+        label,
+        dir.file_count.to_string(),
+        human_size(dir.original),
+        compressed,
+    ]
+}
+
+fn tree_rows(
+    archive: &Archive,
+    dir: &Dir,
+    key: SortKey,
+    prefix: &str,
+    rows: &mut Vec<Vec<String>>,
+) {
+    let mut dirs: Vec<_> = dir.dirs.iter().collect();
+    dirs.sort_by(|(a_name, a), (b_name, b)| {
+        compare(
+            key,
+            &a.sort_fields(a_name, archive.locked),
+            &b.sort_fields(b_name, archive.locked),
+        )
+    });
+    let compressed_len = |entry: &Entry| {
+        (!entry.is_deleted() && !archive.locked).then(|| archive.compressed_len(entry))
+    };
+    let mut files = dir.files.clone();
+    files.sort_by(|(a_name, a), (b_name, b)| {
+        compare(
+            key,
+            &SortFields {
+                name: a_name,
+                ..entry_sort_fields(a, compressed_len(a))
+            },
+            &SortFields {
+                name: b_name,
+                ..entry_sort_fields(b, compressed_len(b))
+            },
+        )
+    });
+
+    let count = dirs.len() + files.len();
+    for (i, (name, child)) in dirs.into_iter().enumerate() {
+        let last = i + 1 == count;
+        let label = format!("{}{}{}/", prefix, branch(last), name);
+        rows.push(dir_row(archive, child, label));
+        let child_prefix = format!("{}{}", prefix, if last { "    " } else { "│   " });
+        tree_rows(archive, child, key, &child_prefix, rows);
+    }
+    let offset = count - files.len();
+    for (i, (name, entry)) in files.into_iter().enumerate() {
+        let (original, compressed) = size_texts(archive, entry);
+        let link = if entry.is_symlink() { " (symlink)" } else { "" };
+        rows.push(vec![
+            format!(
+                "{}{}{}{}",
+                prefix,
+                branch(offset + i + 1 == count),
+                name,
+                link
+            ),
+            String::new(),
+            original,
+            compressed,
+        ]);
+    }
+}
+
+fn branch(last: bool) -> &'static str {
+    if last {
+        "└── "
+    } else {
+        "├── "
+    }
+}
+
+/// Prints the table with its header and TOTAL row, each column as wide as
+/// its widest cell, so that long paths do not push later columns out of line.
+fn print_table(table: &Table, total: Option<&[String]>) {
+    let header: Vec<String> = table.header.iter().map(|(h, _)| h.to_string()).collect();
+    let widths: Vec<usize> = (0..header.len())
+        .map(|i| {
+            std::iter::once(header.as_slice())
+                .chain(table.rows.iter().map(Vec::as_slice))
+                .chain(total)
+                .map(|row| row[i].chars().count())
+                .max()
+                .unwrap_or(0)
+        })
+        .collect();
+    let line = |row: &[String]| {
+        let cells: Vec<String> = row
+            .iter()
+            .zip(&table.header)
+            .zip(&widths)
+            .enumerate()
+            .map(|(i, ((cell, (_, align)), width))| match align {
+                // The last column is not padded, so lines have no trailing spaces.
+                Align::Left if i + 1 == row.len() => cell.clone(),
+                Align::Left => format!("{:<width$}", cell),
+                Align::Right => format!("{:>width$}", cell),
+            })
+            .collect();
+        cells.join("  ")
+    };
+    let rule = "-".repeat(widths.iter().sum::<usize>() + 2 * (widths.len() - 1));
+
+    println!("{}", line(&header));
+    println!("{}", rule);
+    for row in &table.rows {
+        println!("{}", line(row));
+    }
+    if let Some(total) = total {
+        println!("{}", rule);
+        println!("{}", line(total));
+    }
+}
+
+/// `ls -l` style mode: the kind, then read, write and execute permission for
+/// owner, group and others. Unknown permissions are shown as `?`.
+fn mode_text(entry: &Entry) -> String {
+    let (kind, mode) = match entry.kind {
+        EntryKind::Symlink => ('l', entry.mode.or(Some(0o777))),
+        EntryKind::Deleted => return "-".to_string(),
+        EntryKind::File => ('-', entry.mode),
+    };
+    let Some(mode) = mode else {
+        return format!("{}?????????", kind);
+    };
+    let mut text = String::from(kind);
+    for shift in [6, 3, 0] {
+        let bits = mode >> shift;
+        text.push(if bits & 4 != 0 { 'r' } else { '-' });
+        text.push(if bits & 2 != 0 { 'w' } else { '-' });
+        text.push(if bits & 1 != 0 { 'x' } else { '-' });
+    }
+    text
+}
+
+/// How the entry's contents are compressed.
+fn method(archive: &Archive, entry: &Entry) -> &'static str {
+    if entry.is_deleted() {
+        "-"
+    } else if archive.dictionary.is_some() {
+        "zstd+dict"
+    } else {
+        "zstd"
+    }
+}
+
+fn time_text(mtime: u64) -> String {
+    DateTime::from_timestamp(mtime as i64, 0)
+        .map(|t| t.with_timezone(&Local).format("%Y-%m-%d %H:%M").to_string())
+        .unwrap_or_else(|| mtime.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn patterns(globs: &[&str]) -> Vec<Pattern> {
+        globs.iter().map(|g| Pattern::new(g).unwrap()).collect()
+    }
+
+    fn fields(
+        name: &str,
+        size: u64,
+        compressed: Option<u64>,
+        mtime: Option<u64>,
+    ) -> SortFields<'_> {
+        SortFields {
+            name,
+            size,
+            compressed,
+            mtime,
         }
+    }
 
-        println!(
-            "{:<40} {:>15} {:>15}",
-            path,
-            human_size(original_len),
-            human_size(compressed_len)
-        );
+    fn sorted<'a>(key: SortKey, mut files: Vec<SortFields<'a>>) -> Vec<&'a str> {
+        files.sort_by(|a, b| compare(key, a, b));
+        files.iter().map(|f| f.name).collect()
+    }
 
-        total_original += original_len;
-        total_compressed += compressed_len;
-        file_count += 1;
+    #[test]
+    fn pattern_matches_a_path_or_a_directory_it_is_in() {
+        assert!(matches(&[], "any/path"));
+        let globs = patterns(&["*.txt", "docs"]);
+        assert!(matches(&globs, "a.txt"));
+        assert!(matches(&globs, "docs/guide/intro.md"));
+        assert!(!matches(&globs, "b.bin"));
+        assert!(!matches(&globs, "src/docs.rs"));
+        assert!(matches(&patterns(&["src/*"]), "src/a/b.rs"));
     }
 
-    println!("{:-<74}", "");
-    println!(
-        "{:<40} {:>15} {:>15}",
-        "TOTAL",
-        human_size(total_original),
-        human_size(total_compressed)
-    );
-    println!("\n📄 {} files listed.\n✅ Done.", file_count);
+    #[test]
+    fn sort_keys_put_the_largest_newest_or_worst_first() {
+        let files = || {
+            vec![
+                fields("b", 100, Some(90), Some(3)),
+                fields("a", 100, Some(10), None),
+                fields("c", 300, None, Some(5)),
+            ]
+        };
+        assert_eq!(sorted(SortKey::Name, files()), ["a", "b", "c"]);
+        assert_eq!(sorted(SortKey::Size, files()), ["c", "a", "b"]);
+        assert_eq!(sorted(SortKey::Mtime, files()), ["c", "b", "a"]);
+        assert_eq!(sorted(SortKey::Ratio, files()), ["b", "a", "c"]);
+    }
 }
@@ -1,5 +1,309 @@
+pub mod cat;
 pub mod compress;
+pub mod convert;
+pub mod decrypt;
+pub mod diff;
+pub mod encrypt;
 pub mod extract;
+pub mod info;
+pub mod keygen;
 pub mod list;
+pub mod rekey;
+pub mod repair;
+pub mod repo;
+pub mod sign;
 pub mod update;
 pub mod verify;
+
+use std::fs;
+use std::io::IsTerminal;
+
+use anyhow::{bail, Context, Result};
+
+use crate::archive;
+use crate::cli::{KdfArgs, KdfPreset, NewPasswordArgs, PasswordArgs};
+use crate::encrypt::KdfParams;
+use crate::keys::{Credentials, Passphrase, Recipient, WrongCredentials};
+use crate::output;
+use zeroize::Zeroizing;
+
+/// Builds the credentials for opening `archive_path`, exiting if a password
+/// source or identity file cannot be read. When nothing was given and the
+/// archive takes a password, prompts for one on a terminal.
+pub fn credentials(
+    archive_path: &str,
+    password: &PasswordArgs,
+    identity: &[String],
+) -> Credentials {
+    resolve_credentials(archive_path, password, identity, false)
+}
+
+/// Like [`credentials`], but does not prompt for archives whose index can be
+/// read without a password.
+pub fn listing_credentials(
+    archive_path: &str,
+    password: &PasswordArgs,
+    identity: &[String],
+) -> Credentials {
+    resolve_credentials(archive_path, password, identity, true)
+}
+
+fn resolve_credentials(
+    archive_path: &str,
+    password: &PasswordArgs,
+    identity: &[String],
+    index_only: bool,
+) -> Credentials {
+    let mut passphrase = exit_on_error(read_passphrase(password.into(), "Password", false));
+    if passphrase.is_none()
+        && identity.is_empty()
+        && std::io::stdin().is_terminal()
+        && matches!(archive::read_header(archive_path), Ok(Some(h))
+            if h.accepts_password() && !(index_only && h.metadata_visible))
+    {
+        passphrase = Passphrase::new(
+            Some(exit_on_error(prompt_password("Password", false))),
+            None,
+        );
+    }
+    exit_on_error(Credentials::new(passphrase, identity))
+}
+
+/// Reads the passphrase of an existing repository. Prompts only with
+/// `--ask-password`.
+pub fn current_passphrase(password: &PasswordArgs) -> Option<Passphrase> {
+    exit_on_error(read_passphrase(password.into(), "Password", false))
+}
+
+/// Reads the passphrase for a new archive, asking twice when prompting.
+pub fn new_passphrase(password: &PasswordArgs) -> Option<Passphrase> {
+    exit_on_error(read_passphrase(password.into(), "Password", true))
+}
+
+/// Like [`new_passphrase`], for commands that also read the current password.
+pub fn replacement_passphrase(password: &NewPasswordArgs) -> Option<Passphrase> {
+    exit_on_error(read_passphrase(password.into(), "New password", true))
+}
+
+/// Loads every `--recipient` public key, exiting if one cannot be read.
+pub fn recipients(args: &[String]) -> Vec<Recipient> {
+    let mut keys = Vec::new();
+    for recipient in args {
+        keys.extend(exit_on_error(Recipient::load(recipient)));
+    }
+    keys
+}
+
+/// Borrowed view of [`PasswordArgs`] or [`NewPasswordArgs`], so both are read
+/// the same way without copying the password.
+struct PasswordSource<'a> {
+    password: Option<&'a str>,
+    file: Option<&'a str>,
+    env: Option<&'a str>,
+    fd: Option<u32>,
+    ask: bool,
+    keyfile: Option<&'a str>,
+}
+
+impl<'a> From<&'a PasswordArgs> for PasswordSource<'a> {
+    fn from(args: &'a PasswordArgs) -> Self {
+        PasswordSource {
+            password: args.password.as_deref().map(String::as_str),
+            file: args.password_file.as_deref(),
+            env: args.password_env.as_deref(),
+            fd: args.password_fd,
+            ask: args.ask_password,
+            keyfile: args.keyfile.as_deref(),
+        }
+    }
+}
+
+impl<'a> From<&'a NewPasswordArgs> for PasswordSource<'a> {
+    fn from(args: &'a NewPasswordArgs) -> Self {
+        PasswordSource {
+            password: args.new_password.as_deref().map(String::as_str),
+            file: args.new_password_file.as_deref(),
+            env: args.new_password_env.as_deref(),
+            fd: args.new_password_fd,
+            ask: args.ask_new_password,
+            keyfile: args.new_keyfile.as_deref(),
+        }
+    }
+}
+
+fn read_passphrase(
+    source: PasswordSource,
+    label: &str,
+    confirm: bool,
+) -> Result<Option<Passphrase>> {
+    let password = if let Some(password) = source.password {
+        Some(Zeroizing::new(password.to_string()))
+    } else if let Some(path) = source.file {
+        let contents = Zeroizing::new(
+            fs::read_to_string(path)
+                .with_context(|| format!("Failed to read password file '{}'", path))?,
+        );
+        Some(first_line(&contents))
+    } else if let Some(var) = source.env {
+        Some(Zeroizing::new(std::env::var(var).with_context(|| {
+            format!("Environment variable {} is not set", var)
+        })?))
+    } else if let Some(fd) = source.fd {
+        let contents = Zeroizing::new(
+            fs::read_to_string(format!("/dev/fd/{}", fd))
+                .with_context(|| format!("Failed to read password from file descriptor {}", fd))?,
+        );
+        Some(first_line(&contents))
+    } else if source.ask {
+        Some(prompt_password(label, confirm)?)
+    } else {
+        None
+    };
+
+    let keyfile = match source.keyfile {
+        Some(path) => {
+            Some(Zeroizing::new(fs::read(path).with_context(|| {
+                format!("Failed to read keyfile '{}'", path)
+            })?))
+        }
+        None => None,
+    };
+    Ok(Passphrase::new(password, keyfile))
+}
+
+fn prompt_password(label: &str, confirm: bool) -> Result<Zeroizing<String>> {
+    let password = Zeroizing::new(
+        rpassword::prompt_password(format!("🔑 {}: ", label)).context("Failed to read password")?,
+    );
+    if confirm {
+        let again = Zeroizing::new(
+            rpassword::prompt_password(format!("🔑 Confirm {}: ", label.to_lowercase()))
+                .context("Failed to read password")?,
+        );
+        if password != again {
+            bail!("Passwords do not match");
+        }
+    }
+    if password.is_empty() {
+        bail!("Password cannot be empty");
+    }
+    Ok(password)
+}
+
+/// Resolves the KDF preset and overrides, exiting if they are not usable.
+pub fn kdf_params(args: &KdfArgs) -> KdfParams {
+    let mut params = match args.kdf_preset {
+        KdfPreset::Interactive => KdfParams::INTERACTIVE,
+        KdfPreset::Moderate => KdfParams::MODERATE,
+        KdfPreset::Paranoid => KdfParams::PARANOID,
+    };
+    if let Some(mib) = args.kdf_memory {
+        params.memory_kib = mib.saturating_mul(1024);
+    }
+    if let Some(iterations) = args.kdf_iterations {
+        params.iterations = iterations;
+    }
+    exit_on_error(params.validate());
+    params
+}
+
+fn first_line(contents: &str) -> Zeroizing<String> {
+    Zeroizing::new(contents.lines().next().unwrap_or_default().to_string())
+}
+
+/// Unwraps a result, printing the error and exiting on failure.
+pub fn exit_on_error<T>(result: Result<T>) -> T {
+    result.unwrap_or_else(|e| {
+        output::error(&format!("{:#}", e));
+        eprintln!("❌ {:#}", e);
+        std::process::exit(exit_code(&e));
+    })
+}
+
+/// Prints an error and exits with status 1.
+pub fn fail(message: impl std::fmt::Display) -> ! {
+    output::error(&message.to_string());
+    eprintln!("❌ {}", message);
+    std::process::exit(1);
+}
+
+/// Exit status for a wrong password or identity, so scripts can tell it
+/// apart from other failures (1) and usage errors (2).
+pub const WRONG_CREDENTIALS_EXIT_CODE: i32 = 3;
+
+fn exit_code(error: &anyhow::Error) -> i32 {
+    if error.chain().any(|e| e.is::<WrongCredentials>()) {
+        WRONG_CREDENTIALS_EXIT_CODE
+    } else {
+        1
+    }
+}
+
+/// Formats a byte count using binary units.
+pub fn human_size(bytes: u64) -> String {
+    const KB: u64 = 1024;
+    const MB: u64 = 1024 * KB;
+    const GB: u64 = 1024 * MB;
+
+    match bytes {
+        b if b >= GB => format!("{:.2} GB", b as f64 / GB as f64),
+        b if b >= MB => format!("{:.2} MB", b as f64 / MB as f64),
+        b if b >= KB => format!("{:.2} KB", b as f64 / KB as f64),
+        _ => format!("{} B", bytes), // FIXED: use `bytes` here
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sha2::{Digest, Sha256};
+
+    use super::*;
+    use crate::test_util::parse_args;
+
+    fn passphrase(args: &[&str]) -> Option<Passphrase> {
+        let args: PasswordArgs = parse_args(args);
+        read_passphrase((&args).into(), "Password", false).unwrap()
+    }
+
+    #[test]
+    fn password_file_contributes_its_first_line() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("password");
+        fs::write(&path, "secret\nnot part of it\n").unwrap();
+
+        let passphrase = passphrase(&["--password-file", path.to_str().unwrap()]).unwrap();
+        assert_eq!(passphrase.as_bytes(), b"secret");
+    }
+
+    #[test]
+    fn keyfile_combines_with_a_password_from_the_environment() {
+        let dir = tempfile::tempdir().unwrap();
+        let keyfile = dir.path().join("keyfile");
+        fs::write(&keyfile, b"key material").unwrap();
+        std::env::set_var("UBL_TEST_PASSWORD", "secret");
+
+        let passphrase = passphrase(&[
+            "--password-env",
+            "UBL_TEST_PASSWORD",
+            "--keyfile",
+            keyfile.to_str().unwrap(),
+        ])
+        .unwrap();
+        assert_eq!(
+            passphrase.as_bytes(),
+            [b"secret".as_slice(), &Sha256::digest(b"key material")].concat()
+        );
+    }
+
+    #[test]
+    fn no_source_gives_no_passphrase() {
+        assert!(passphrase(&[]).is_none());
+    }
+
+    #[test]
+    fn wrong_credentials_have_their_own_exit_code() {
+        let wrong = anyhow::Error::new(WrongCredentials("wrong")).context("Failed to open");
+        assert_eq!(exit_code(&wrong), WRONG_CREDENTIALS_EXIT_CODE);
+        assert_eq!(exit_code(&anyhow::anyhow!("damaged")), 1);
+    }
+}
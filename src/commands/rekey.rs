@@ -0,0 +1,168 @@
+use serde_json::json;
+
+use super::{credentials, exit_on_error, fail, kdf_params, recipients, replacement_passphrase};
+use crate::archive::{self, Archive};
+use crate::cli::{KdfArgs, NewPasswordArgs, PasswordArgs};
+use crate::encrypt::Cipher;
+use crate::keys::{Envelope, KeySlot};
+use crate::output::{self, say};
+
+/// Wraps the archive's data key for a new password and/or recipients,
+/// rewriting only the header.
+pub fn run(
+    archive_path: &str,
+    password: &PasswordArgs,
+    identity: Vec<String>,
+    new_password: &NewPasswordArgs,
+    new_recipients: Vec<String>,
+    keep_recipients: bool,
+    kdf: &KdfArgs,
+) {
+    let header = exit_on_error(archive::read_header(archive_path));
+    if matches!(&header, Some(h) if !h.encrypted) {
+        fail("Archive is not encrypted.");
+    }
+
+    let credentials = credentials(archive_path, password, &identity);
+    let passphrase = replacement_passphrase(new_password);
+    let recipient_keys = recipients(&new_recipients);
+    let kdf = kdf_params(kdf);
+    if passphrase.is_none() && recipient_keys.is_empty() && !keep_recipients {
+        fail("Give a new password, keyfile or recipient to rekey the archive to.");
+    }
+
+    say!("🔑 Rekeying '{}'...", archive_path);
+
+    // Archives from before key slots have their body encrypted with the
+    // password itself, so they are rewritten in full under a new data key.
+    let Some(mut header) = header.filter(|h| !h.key_slots.is_empty()) else {
+        if keep_recipients {
+            fail("Archive has no recipients to keep.");
+        }
+        let mut archive = exit_on_error(Archive::open(archive_path, &credentials));
+        if !archive.encrypted {
+            fail("Archive is not encrypted.");
+        }
+        if let Some(path) = &archive.truncated {
+            fail(format!("Archive is truncated at entry '{}'.", path));
+        }
+        say!("⚠️  Archive predates key slots; rewriting it in full.");
+        archive.envelope = exit_on_error(Envelope::seal(
+            Cipher::default(),
+            passphrase.as_ref(),
+            &kdf,
+            &recipient_keys,
+        ));
+        exit_on_error(archive.rewrite(archive_path));
+        say!("✅ Archive rekeyed.");
+        output::summary(
+            true,
+            json!({
+                "archive": archive_path,
+                "key_slots": archive.envelope.as_ref().map_or(0, |e| e.slots.len()),
+                "signature_removed": false,
+            }),
+        );
+        return;
+    };
+
+    let envelope = exit_on_error(Envelope::open(
+        header.key_slots.clone(),
+        header.cipher.unwrap_or_default(),
+        &credentials,
+    ));
+    exit_on_error(header.verify(&envelope));
+    let mut rekeyed = exit_on_error(Envelope::with_key(
+        envelope.key,
+        envelope.cipher,
+        passphrase.as_ref(),
+        &kdf,
+        &recipient_keys,
+    ));
+    if keep_recipients {
+        rekeyed.slots.extend(
+            envelope
+                .slots
+                .into_iter()
+                .filter(|slot| matches!(slot, KeySlot::X25519 { .. })),
+        );
+    }
+    if rekeyed.slots.is_empty() {
+        fail("Archive has no recipients to keep; give a new password or recipient.");
+    }
+
+    header.key_slots = rekeyed.slots.clone();
+    let signature_removed = header.signature.take().is_some();
+    if signature_removed {
+        say!("⚠️  The archive's signature was removed; sign it again with `ubl sign`.");
+    }
+    // Archives from before header tags have a body without associated data,
+    // which a new tag would not match.
+    if header.header_tag.is_some() {
+        header.seal(&rekeyed);
+    }
+    exit_on_error(archive::replace_header(archive_path, &header));
+    say!(
+        "✅ Archive rekeyed: {} key slot(s).",
+        header.key_slots.len()
+    );
+    output::summary(
+        true,
+        json!({
+            "archive": archive_path,
+            "key_slots": header.key_slots.len(),
+            "signature_removed": signature_removed,
+        }),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::encrypt::KdfParams;
+    use crate::keys::{Credentials, Identity};
+    use crate::test_util::{archive_path, noise, parse_args, read_entry, sample_archive};
+
+    fn opened_by(identity: Identity, path: &str) -> anyhow::Result<Archive> {
+        let credentials = Credentials {
+            identities: vec![identity],
+            ..Default::default()
+        };
+        Archive::open(path, &credentials)
+    }
+
+    #[test]
+    fn rekeyed_archive_keeps_its_contents() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = archive_path(&dir, "a.ubl");
+        let (old, new) = (Identity::generate(), Identity::generate());
+        let mut archive = sample_archive();
+        archive.envelope = Envelope::seal(
+            Cipher::default(),
+            None,
+            &KdfParams::default(),
+            &[old.public()],
+        )
+        .unwrap();
+        archive.write(&path).unwrap();
+        let old_file = archive_path(&dir, "old.key");
+        fs::write(&old_file, old.to_secret_string()).unwrap();
+
+        run(
+            &path,
+            &parse_args(&[]),
+            vec![old_file],
+            &parse_args(&[]),
+            vec![new.public().to_string()],
+            false,
+            &parse_args(&[]),
+        );
+
+        assert!(opened_by(old, &path).is_err());
+        let archive = opened_by(new, &path).unwrap();
+        assert_eq!(read_entry(&archive, "a.txt"), b"hello");
+        assert_eq!(read_entry(&archive, "big.bin"), noise(200 * 1024, 1));
+    }
+}
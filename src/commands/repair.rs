@@ -0,0 +1,76 @@
+use std::fs;
+
+use anyhow::Context;
+use serde::Serialize;
+
+use super::{exit_on_error, fail};
+use crate::output::{self, say};
+use crate::recovery;
+use crate::volume::ArchiveFile;
+
+#[derive(Serialize)]
+struct RepairSummary<'a> {
+    archive: &'a str,
+    blocks: usize,
+    rebuilt: usize,
+    record_damaged: bool,
+    /// Where the repaired archive was written, if anywhere.
+    written_to: Option<&'a str>,
+}
+
+/// Rebuilds the damaged blocks of an archive from its recovery record and
+/// writes it back, to `output` if given or else in place.
+pub fn run(archive_path: &str, output: Option<&str>) {
+    let mut file = exit_on_error(ArchiveFile::open(archive_path));
+    let Some(trailer) = file.recovery() else {
+        fail(format!(
+            "No recovery record found in '{}'. Only archives created with --recovery can be repaired.",
+            archive_path
+        ));
+    };
+    let mut data = exit_on_error(
+        file.read_all()
+            .with_context(|| format!("Failed to read '{}'", archive_path)),
+    );
+
+    say!(
+        "🛟 Checking '{}' against its {}% recovery record...",
+        archive_path,
+        trailer.percent
+    );
+    let repair = exit_on_error(recovery::repair(&mut data, &trailer));
+    let mut summary = RepairSummary {
+        archive: archive_path,
+        blocks: repair.blocks,
+        rebuilt: repair.rebuilt,
+        record_damaged: repair.record_damaged,
+        written_to: None,
+    };
+    if repair.rebuilt == 0 && !repair.record_damaged {
+        say!("✅ No damage found in {} blocks.", repair.blocks);
+        output::summary(true, summary);
+        return;
+    }
+    if repair.rebuilt > 0 {
+        say!("🔧 Rebuilt {} of {} blocks.", repair.rebuilt, repair.blocks);
+    }
+    if repair.record_damaged {
+        say!("🔧 Rewrote the damaged recovery record.");
+    }
+
+    match output {
+        Some(output) => {
+            exit_on_error(
+                fs::write(output, &data).with_context(|| format!("Failed to write '{}'", output)),
+            );
+            say!("✅ Repaired archive written to '{}'", output);
+            summary.written_to = Some(output);
+        }
+        None => {
+            exit_on_error(file.rewrite(&data));
+            say!("✅ Repaired '{}' in place", archive_path);
+            summary.written_to = Some(archive_path);
+        }
+    }
+    output::summary(true, summary);
+}
@@ -0,0 +1,343 @@
+use std::collections::HashMap;
+use std::fs::{self, create_dir_all, File};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::time::Instant;
+
+use anyhow::{bail, Context, Result};
+use indicatif::{ProgressBar, ProgressStyle};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use walkdir::WalkDir;
+
+use super::{current_passphrase, exit_on_error, fail, human_size, new_passphrase};
+use crate::archive;
+use crate::cli::PasswordArgs;
+use crate::output::{self, say};
+use crate::repository::{Repository, Retention, Snapshot, SnapshotEntry};
+
+fn open_repo(repo: &str, password: &PasswordArgs) -> Repository {
+    let passphrase = current_passphrase(password);
+    exit_on_error(Repository::open(repo, passphrase.as_ref()))
+}
+
+fn progress_bar(len: usize) -> ProgressBar {
+    let pb = ProgressBar::new(len as u64);
+    pb.set_style(
+        ProgressStyle::with_template(
+            "{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} {msg}",
+        )
+        .unwrap()
+        .progress_chars("#>-"),
+    );
+    pb
+}
+
+pub fn init(repo: &str, password: &PasswordArgs) {
+    let passphrase = new_passphrase(password);
+    let repository = exit_on_error(Repository::init(repo, passphrase.as_ref()));
+    say!("✅ Created repository '{}' (id {})", repo, repository.id);
+    if passphrase.is_some() {
+        say!("🔒 Chunks and snapshots will be encrypted.");
+    }
+    output::summary(
+        true,
+        json!({ "repository": repo, "id": repository.id, "encrypted": passphrase.is_some() }),
+    );
+}
+
+/// Stores a new snapshot of `input`. Files whose size and mtime match the
+/// previous snapshot of the same source are not read again.
+pub fn backup(repo: &str, input: &str, password: &PasswordArgs) {
+    let repository = open_repo(repo, password);
+    let _lock = exit_on_error(repository.lock_shared());
+    let input_path = Path::new(input);
+    if !input_path.exists() {
+        fail(format!("Input path '{}' does not exist.", input));
+    }
+
+    let source = exit_on_error(
+        fs::canonicalize(input_path).with_context(|| format!("Failed to resolve '{}'", input)),
+    )
+    .to_string_lossy()
+    .to_string();
+    let parent: HashMap<String, SnapshotEntry> = exit_on_error(repository.snapshots())
+        .into_iter()
+        .rev()
+        .find(|s| s.source == source)
+        .map(|s| s.entries.into_iter().map(|e| (e.path.clone(), e)).collect())
+        .unwrap_or_default();
+
+    say!("📦 Backing up '{}' into repository '{}'", input, repo);
+    let start = Instant::now();
+
+    let files: Vec<_> = WalkDir::new(input_path)
+        .into_iter()
+        .filter_map(Result::ok)
+        .filter(|e| e.file_type().is_file())
+        .collect();
+
+    let pb = progress_bar(files.len());
+    let mut entries = Vec::new();
+    let mut unchanged = 0;
+    let mut added_bytes = 0;
+
+    for file in files {
+        let file_path = file.path();
+        let path = file_path
+            .strip_prefix(input_path)
+            .unwrap()
+            .to_string_lossy()
+            .to_string();
+        let mtime = archive::file_mtime(file_path);
+        let len = file.metadata().map(|m| m.len()).ok();
+
+        if let Some(old) = parent.get(&path) {
+            if mtime.is_some() && old.mtime == mtime && Some(old.original_len) == len {
+                entries.push(old.clone());
+                unchanged += 1;
+                pb.inc(1);
+                continue;
+            }
+        }
+
+        let data = exit_on_error(
+            fs::read(file_path)
+                .with_context(|| format!("Failed to read '{}'", file_path.display())),
+        );
+        let mut chunks = Vec::new();
+        for piece in archive::split_chunks(&data) {
+            let (id, written) = exit_on_error(repository.store_chunk(piece));
+            added_bytes += written;
+            chunks.push(id);
+        }
+
+        entries.push(SnapshotEntry {
+            path: path.clone(),
+            original_len: data.len() as u64,
+            mtime,
+            sha256: format!("{:x}", Sha256::digest(&data)),
+            chunks,
+        });
+
+        pb.set_message(path);
+        pb.inc(1);
+    }
+
+    pb.finish_with_message("🎉 Backup done");
+
+    let mut snapshot = Snapshot {
+        id: String::new(),
+        time: chrono::Utc::now().timestamp(),
+        source,
+        entries,
+    };
+    exit_on_error(repository.save_snapshot(&mut snapshot));
+
+    say!(
+        "✅ Snapshot {} saved: {} files ({} unchanged), {} of new data",
+        snapshot.short_id(),
+        snapshot.entries.len(),
+        unchanged,
+        human_size(added_bytes)
+    );
+    say!("🕒 Completed in {:.2?}", start.elapsed());
+    output::summary(
+        true,
+        json!({
+            "repository": repo,
+            "snapshot": snapshot.id,
+            "files": snapshot.entries.len(),
+            "unchanged": unchanged,
+            "new_bytes": added_bytes,
+        }),
+    );
+}
+
+pub fn snapshots(repo: &str, password: &PasswordArgs) {
+    let repository = open_repo(repo, password);
+    let snapshots = exit_on_error(repository.snapshots());
+
+    say!(
+        "{:<10} {:<20} {:>8} {:>12}  Source",
+        "ID",
+        "Time",
+        "Files",
+        "Size"
+    );
+    say!("{:-<74}", "");
+    for snapshot in &snapshots {
+        output::file(json!({
+            "id": snapshot.id,
+            "time": snapshot.time,
+            "files": snapshot.entries.len(),
+            "size": snapshot.total_size(),
+            "source": snapshot.source,
+        }));
+        say!(
+            "{:<10} {:<20} {:>8} {:>12}  {}",
+            snapshot.short_id(),
+            snapshot.local_time().format("%Y-%m-%d %H:%M:%S"),
+            snapshot.entries.len(),
+            human_size(snapshot.total_size()),
+            snapshot.source
+        );
+    }
+    say!("{:-<74}", "");
+    say!("📸 {} snapshot(s).", snapshots.len());
+    output::summary(
+        true,
+        json!({ "repository": repo, "snapshots": snapshots.len() }),
+    );
+}
+
+pub fn restore(repo: &str, snapshot: &str, output: Option<String>, password: &PasswordArgs) {
+    let repository = open_repo(repo, password);
+    let snapshot = exit_on_error(repository.find_snapshot(snapshot));
+    let base_output_dir = output.unwrap_or_else(|| format!("snapshot-{}", snapshot.short_id()));
+
+    say!(
+        "📦 Restoring snapshot {} ({}) to '{}'",
+        snapshot.short_id(),
+        snapshot.local_time().format("%Y-%m-%d %H:%M:%S"),
+        base_output_dir
+    );
+    let start = Instant::now();
+
+    let pb = progress_bar(snapshot.entries.len());
+    for entry in &snapshot.entries {
+        let output_path = Path::new(&base_output_dir).join(&entry.path);
+        exit_on_error(restore_file(&repository, entry, &output_path));
+
+        pb.set_message(entry.path.clone());
+        pb.inc(1);
+    }
+
+    pb.finish_with_message("🎉 Restore complete");
+    say!("✅ All files restored to '{}'", base_output_dir);
+    say!("🕒 Completed in {:.2?}", start.elapsed());
+    output::summary(
+        true,
+        json!({
+            "repository": repo,
+            "snapshot": snapshot.id,
+            "output_dir": base_output_dir,
+            "restored": snapshot.entries.len(),
+        }),
+    );
+}
+
+/// Writes one snapshot entry to `output_path`, creating its directory.
+fn restore_file(repository: &Repository, entry: &SnapshotEntry, output_path: &Path) -> Result<()> {
+    if let Some(parent) = output_path.parent() {
+        create_dir_all(parent)
+            .with_context(|| format!("Failed to create '{}'", parent.display()))?;
+    }
+    let file = File::create(output_path)
+        .with_context(|| format!("Failed to create '{}'", output_path.display()))?;
+    let mut outfile = BufWriter::new(file);
+    let mut sha = Sha256::new();
+    for id in &entry.chunks {
+        let data = repository.load_chunk(id)?;
+        sha.update(&data);
+        outfile
+            .write_all(&data)
+            .with_context(|| format!("Failed to write '{}'", output_path.display()))?;
+    }
+    outfile
+        .flush()
+        .with_context(|| format!("Failed to write '{}'", output_path.display()))?;
+    if format!("{:x}", sha.finalize()) != entry.sha256 {
+        bail!(
+            "Restored '{}' does not match the SHA-256 stored in the snapshot",
+            entry.path
+        );
+    }
+    Ok(())
+}
+
+pub fn forget(repo: &str, retention: Retention, prune: bool, password: &PasswordArgs) {
+    if retention.is_empty() {
+        fail("Specify at least one --keep-* option.");
+    }
+
+    let repository = open_repo(repo, password);
+    let _lock = exit_on_error(repository.lock_exclusive());
+    let snapshots = exit_on_error(repository.snapshots());
+    let keep = retention.apply(&snapshots);
+
+    for snapshot in &snapshots {
+        let time = snapshot.local_time().format("%Y-%m-%d %H:%M:%S");
+        let kept = keep.contains(&snapshot.id);
+        if kept {
+            say!("📌 keep   {}  {}", snapshot.short_id(), time);
+        } else {
+            exit_on_error(repository.remove_snapshot(&snapshot.id));
+            say!("🗑 forget {}  {}", snapshot.short_id(), time);
+        }
+        output::file(json!({
+            "id": snapshot.id,
+            "time": snapshot.time,
+            "action": if kept { "keep" } else { "forget" },
+        }));
+    }
+    say!("✅ Kept {} of {} snapshot(s).", keep.len(), snapshots.len());
+
+    let pruned = prune.then(|| prune_chunks(&repository));
+    output::summary(
+        true,
+        json!({
+            "repository": repo,
+            "kept": keep.len(),
+            "forgotten": snapshots.len() - keep.len(),
+            "pruned": pruned,
+        }),
+    );
+}
+
+pub fn prune(repo: &str, password: &PasswordArgs) {
+    let repository = open_repo(repo, password);
+    let _lock = exit_on_error(repository.lock_exclusive());
+    let pruned = prune_chunks(&repository);
+    output::summary(true, json!({ "repository": repo, "pruned": pruned }));
+}
+
+/// Removes unreferenced chunks and reports what was removed.
+fn prune_chunks(repository: &Repository) -> serde_json::Value {
+    say!("🧹 Pruning unreferenced data...");
+    let (removed, freed) = exit_on_error(repository.prune());
+    say!(
+        "✅ Removed {} chunk(s), freed {}.",
+        removed,
+        human_size(freed)
+    );
+    json!({ "chunks": removed, "freed": freed })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::archive_path;
+
+    #[test]
+    fn restored_file_is_checked_against_its_hash() {
+        let dir = tempfile::tempdir().unwrap();
+        let repository = Repository::init(&archive_path(&dir, "repo"), None).unwrap();
+        let (id, _) = repository.store_chunk(b"hello").unwrap();
+        let mut entry = SnapshotEntry {
+            path: "a.txt".into(),
+            original_len: 5,
+            mtime: None,
+            sha256: format!("{:x}", Sha256::digest(b"hello")),
+            chunks: vec![id],
+        };
+        let output = dir.path().join("a.txt");
+
+        restore_file(&repository, &entry, &output).unwrap();
+        assert_eq!(fs::read(&output).unwrap(), b"hello");
+
+        entry.sha256 = format!("{:x}", Sha256::digest(b"other"));
+        let err = restore_file(&repository, &entry, &output).err().unwrap();
+        assert!(err.to_string().contains("SHA-256"), "{}", err);
+    }
+}
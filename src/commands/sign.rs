@@ -0,0 +1,18 @@
+use serde_json::json;
+
+use super::exit_on_error;
+use crate::archive;
+use crate::keys::SigningIdentity;
+use crate::output::{self, say};
+
+/// Embeds an Ed25519 signature in the archive header, replacing any
+/// earlier one. Encrypted archives are signed without decrypting them.
+pub fn run(archive_path: &str, key_path: &str) {
+    let key = exit_on_error(SigningIdentity::load(key_path));
+    exit_on_error(archive::sign(archive_path, &key));
+    say!("✍️  Signed '{}' by {}", archive_path, key.public());
+    output::summary(
+        true,
+        json!({ "archive": archive_path, "signer": key.public().to_string() }),
+    );
+}
@@ -1,133 +1,111 @@
-use std::{
-    fs::File,
-    io::{Cursor, Read},
-    path::Path,
-};
-
-use crate::encrypt;
+use std::path::Path;
+
+use super::{credentials, exit_on_error, fail};
+use crate::archive::{self, Archive, SignatureStatus};
+use crate::cli::PasswordArgs;
+use crate::keys::Signer;
+use crate::output::{self, say};
+use crate::volume::ArchiveFile;
 use sha2::{Digest, Sha256};
-use zstd::stream::Decoder;
 
 use serde::Serialize;
 
 #[derive(Serialize)]
 struct FileVerificationResult {
     path: String,
+    /// `ok` or `corrupted`.
     status: String,
     sha256: String,
     error: Option<String>,
 }
 
-/// Verifies archive integrity and optionally decompresses files for deep inspection.
-pub fn run(archive_path: &str, password: Option<String>, deep: bool, json: bool) {
-    let archive_path = Path::new(archive_path);
-    if !archive_path.exists() {
-        eprintln!("❌ Archive not found: {}", archive_path.display());
-        std::process::exit(1);
-    }
-
-    let mut file = File::open(archive_path).unwrap_or_else(|e| {
-        eprintln!("❌ Failed to open archive: {}", e);
-        std::process::exit(1);
-    });
-
-    let mut full_data = Vec::new();
-    file.read_to_end(&mut full_data).unwrap();
+#[derive(Serialize)]
+struct SignatureResult {
+    signer: String,
+    valid: bool,
+    /// Whether the signer is one of the keys given with `--pubkey`.
+    trusted: bool,
+}
 
-    let raw_data = {
-        let result = if let Some(pass) = password {
-            if full_data.len() < 28 {
-                Err("❌ Archive is too small or corrupted.".to_string())
-            } else {
-                let salt = &full_data[..16];
-                let nonce = &full_data[16..28];
-                let ciphertext = &full_data[28..];
+#[derive(Serialize)]
+struct VerificationSummary<'a> {
+    archive: &'a str,
+    files: usize,
+    failed: usize,
+    signature: Option<SignatureResult>,
+}
 
-                println!("🔐 Decrypting archive...");
+/// Verifies archive integrity and optionally decompresses files for deep inspection.
+pub fn run(
+    archive_path: &str,
+    password: &PasswordArgs,
+    identity: Vec<String>,
+    deep: bool,
+    pubkey: &[String],
+) {
+    // Also reports missing volumes of a split archive up front.
+    exit_on_error(ArchiveFile::open(archive_path));
+    let archive_path = Path::new(archive_path);
 
-                let decrypted = encrypt::decrypt(salt, nonce, ciphertext, &pass);
-                Ok(decrypted)
-            }
-        } else {
-            Ok(full_data)
-        };
-
-        match result {
-            Ok(data) => data,
-            Err(msg) => {
-                eprintln!("{}", msg);
-                std::process::exit(1);
-            }
-        }
-    };
+    // The signature is checked before decrypting, so a forged archive is
+    // rejected without being opened.
+    let trusted: Vec<Signer> = pubkey
+        .iter()
+        .flat_map(|arg| exit_on_error(Signer::load(arg)))
+        .collect();
+    let signature = check_signature(&archive_path.to_string_lossy(), &trusted);
+
+    let credentials = credentials(&archive_path.to_string_lossy(), password, &identity);
+    if !credentials.is_empty() {
+        say!("🔐 Decrypting archive...");
+    }
+    let archive = exit_on_error(Archive::open(&archive_path.to_string_lossy(), &credentials));
 
-    let mut reader = Cursor::new(raw_data);
     let mut total_files = 0;
     let mut failed = false;
 
     let mut results: Vec<FileVerificationResult> = Vec::new();
 
-    while let Ok(_) = reader.read_exact(&mut [0u8; 4]) {
-        reader.set_position(reader.position() - 4);
-
-        let mut path_len_buf = [0u8; 4];
-        if reader.read_exact(&mut path_len_buf).is_err() {
-            break;
-        }
-        let path_len = u32::from_le_bytes(path_len_buf);
-
-        let mut path_buf = vec![0u8; path_len as usize];
-        reader.read_exact(&mut path_buf).unwrap();
-        let path = String::from_utf8(path_buf).unwrap_or_else(|_| "<invalid utf8>".into());
-
-        let mut _original_len = [0u8; 8];
-        reader.read_exact(&mut _original_len).unwrap();
-
-        let mut compressed_len_buf = [0u8; 8];
-        reader.read_exact(&mut compressed_len_buf).unwrap();
-        let compressed_len = u64::from_le_bytes(compressed_len_buf);
-
-        let mut compressed_data = vec![0u8; compressed_len as usize];
-        if reader.read_exact(&mut compressed_data).is_err() {
-            results.push(FileVerificationResult {
-                path,
-                status: "Corrupted".into(),
-                sha256: "".into(),
-                error: Some("Incomplete compressed data".into()),
-            });
-            failed = true;
-            continue;
-        }
-
+    for entry in archive.entries.iter().filter(|e| !e.is_deleted()) {
         let mut sha = Sha256::new();
-        let mut status = "OK";
+        let mut status = "ok";
         let mut error_msg = None;
 
         if deep {
-            match Decoder::new(&compressed_data[..]) {
-                Ok(mut decoder) => {
-                    let mut buf = Vec::new();
-                    if decoder.read_to_end(&mut buf).is_err() {
-                        status = "Corrupted";
+            for id in &entry.chunks {
+                let chunk = &archive.chunks[*id as usize];
+                match archive.decompress_chunk(chunk) {
+                    Ok(buf) => {
+                        let hash: [u8; 32] = Sha256::digest(&buf).into();
+                        if chunk.hash.is_some_and(|expected| expected != hash) {
+                            status = "corrupted";
+                            error_msg = Some("Chunk hash mismatch".into());
+                            failed = true;
+                        }
+                        sha.update(&buf);
+                    }
+                    Err(_) => {
+                        status = "corrupted";
                         error_msg = Some("Decompression failed".into());
                         failed = true;
-                    } else {
-                        sha.update(&buf);
+                        break;
                     }
                 }
-                Err(_) => {
-                    status = "Corrupted";
-                    error_msg = Some("Invalid zstd stream".into());
-                    failed = true;
-                }
             }
         } else {
-            sha.update(&compressed_data);
+            for id in &entry.chunks {
+                sha.update(&archive.chunks[*id as usize].data);
+            }
         }
 
         let sha256 = format!("{:x}", sha.finalize());
+        if deep && status == "ok" && entry.sha256.as_ref().is_some_and(|s| *s != sha256) {
+            status = "corrupted";
+            error_msg = Some("File hash mismatch".into());
+            failed = true;
+        }
         results.push(FileVerificationResult {
-            path: path.clone(),
+            path: entry.path.clone(),
             status: status.into(),
             sha256,
             error: error_msg,
@@ -136,28 +114,82 @@ pub fn run(archive_path: &str, password: Option<String>, deep: bool, json: bool)
         total_files += 1;
     }
 
-    if json {
-        println!("{}", serde_json::to_string_pretty(&results).unwrap());
-    } else {
-        for r in &results {
-            if r.status == "OK" {
-                println!("✅ {:<50} — SHA-256: {}", r.path, r.sha256);
-            } else {
-                println!(
-                    "❌ {:<50} — {}: {}",
-                    r.path,
-                    r.status,
-                    r.error.clone().unwrap_or_default()
-                );
-            }
-        }
+    if let Some(path) = &archive.truncated {
+        results.push(FileVerificationResult {
+            path: path.clone(),
+            status: "corrupted".into(),
+            sha256: "".into(),
+            error: Some("Incomplete compressed data".into()),
+        });
+        failed = true;
+    }
 
-        println!("\n🔍 Verified {} file(s).", total_files);
-        if failed {
-            println!("❗ Some files failed integrity checks.");
-            std::process::exit(1);
+    for r in &results {
+        output::file(r);
+        if r.status == "ok" {
+            say!("✅ {:<50} — SHA-256: {}", r.path, r.sha256);
         } else {
-            println!("✅ All files passed.");
+            say!(
+                "❌ {:<50} — {}: {}",
+                r.path,
+                r.status,
+                r.error.clone().unwrap_or_default()
+            );
         }
     }
+
+    say!("\n🔍 Verified {} file(s).", total_files);
+    if failed {
+        say!("❗ Some files failed integrity checks.");
+    } else {
+        say!("✅ All files passed.");
+    }
+    output::summary(
+        !failed,
+        VerificationSummary {
+            archive: &archive_path.to_string_lossy(),
+            files: total_files,
+            failed: results.iter().filter(|r| r.status != "ok").count(),
+            signature,
+        },
+    );
+}
+
+/// Checks the archive signature, exiting if it is invalid or, when trusted
+/// keys are given, missing or made by another key.
+fn check_signature(archive_path: &str, trusted: &[Signer]) -> Option<SignatureResult> {
+    let result = match exit_on_error(archive::check_signature(archive_path)) {
+        SignatureStatus::Unsigned => None,
+        SignatureStatus::Valid(signer) => Some(SignatureResult {
+            trusted: trusted.contains(&signer),
+            signer: signer.to_string(),
+            valid: true,
+        }),
+        SignatureStatus::Invalid(signer) => Some(SignatureResult {
+            signer,
+            valid: false,
+            trusted: false,
+        }),
+    };
+
+    let error = match &result {
+        None if !trusted.is_empty() => Some("Archive is not signed.".to_string()),
+        Some(sig) if !sig.valid => Some(format!(
+            "Signature by {} does not match the archive: it was modified after signing.",
+            sig.signer
+        )),
+        Some(sig) if !trusted.is_empty() && !sig.trusted => Some(format!(
+            "Archive is signed by {}, which is not a trusted key.",
+            sig.signer
+        )),
+        _ => None,
+    };
+    if let Some(error) = error {
+        fail(error);
+    }
+
+    if let Some(sig) = &result {
+        say!("✍️  Signed by {}", sig.signer);
+    }
+    result
 }
@@ -1,46 +1,316 @@
+use std::fmt;
+use std::marker::PhantomData;
+
 use aes_gcm::{
-    aead::{Aead, KeyInit, OsRng},
-    Aes256Gcm, Nonce,
+    aead::{
+        generic_array::typenum::Unsigned, generic_array::GenericArray, Aead, KeyInit, OsRng,
+        Payload,
+    },
+    Aes256Gcm,
 };
-use argon2::{password_hash::SaltString, Argon2, PasswordHasher};
+use anyhow::{bail, Context};
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::XChaCha20Poly1305;
+use clap::ValueEnum;
 use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use zeroize::Zeroizing;
+
+/// Upper bound on the KDF memory accepted from an archive header, so a
+/// corrupted or hostile header cannot make us allocate terabytes.
+const MAX_KDF_MEMORY_KIB: u32 = 4 * 1024 * 1024;
+/// Upper bound on the KDF passes, so a header cannot make opening the
+/// archive take hours. The strongest preset uses 4.
+const MAX_KDF_ITERATIONS: u32 = 64;
+/// Upper bound on the KDF lanes. The presets use 1.
+const MAX_KDF_PARALLELISM: u32 = 16;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum KdfAlgorithm {
+    Argon2id,
+    Argon2i,
+    Argon2d,
+}
 
-/// Derives a 256-bit key from password and salt using Argon2
-pub fn derive_key(password: &str, salt: &[u8]) -> [u8; 32] {
-    let salt = SaltString::encode_b64(salt).unwrap();
+/// Password KDF settings, stored next to every salt so that archives keep
+/// decrypting when the defaults of the argon2 crate change.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub struct KdfParams {
+    pub algorithm: KdfAlgorithm,
+    /// Memory cost in KiB.
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
 
-    let argon2 = Argon2::default();
-    let hash = argon2.hash_password(password.as_bytes(), &salt).unwrap();
+impl KdfParams {
+    /// What `Argon2::default()` used in argon2 0.5, which every archive and
+    /// repository written before parameters were recorded relies on.
+    pub const LEGACY: KdfParams = KdfParams {
+        algorithm: KdfAlgorithm::Argon2id,
+        memory_kib: 19 * 1024,
+        iterations: 2,
+        parallelism: 1,
+    };
+    pub const INTERACTIVE: KdfParams = KdfParams {
+        algorithm: KdfAlgorithm::Argon2id,
+        memory_kib: 64 * 1024,
+        iterations: 2,
+        parallelism: 1,
+    };
+    pub const MODERATE: KdfParams = KdfParams {
+        algorithm: KdfAlgorithm::Argon2id,
+        memory_kib: 256 * 1024,
+        iterations: 3,
+        parallelism: 1,
+    };
+    pub const PARANOID: KdfParams = KdfParams {
+        algorithm: KdfAlgorithm::Argon2id,
+        memory_kib: 1024 * 1024,
+        iterations: 4,
+        parallelism: 1,
+    };
 
-    let hash_output = hash.hash.unwrap(); // FIX: Bind temporary
-    let hash_bytes = hash_output.as_bytes();
+    fn argon2(&self) -> anyhow::Result<Argon2<'static>> {
+        if self.memory_kib > MAX_KDF_MEMORY_KIB {
+            bail!(
+                "KDF memory of {} MiB is above the supported maximum of {} MiB",
+                self.memory_kib / 1024,
+                MAX_KDF_MEMORY_KIB / 1024
+            );
+        }
+        if self.iterations > MAX_KDF_ITERATIONS {
+            bail!(
+                "KDF iteration count of {} is above the supported maximum of {}",
+                self.iterations,
+                MAX_KDF_ITERATIONS
+            );
+        }
+        if self.parallelism > MAX_KDF_PARALLELISM {
+            bail!(
+                "KDF parallelism of {} is above the supported maximum of {}",
+                self.parallelism,
+                MAX_KDF_PARALLELISM
+            );
+        }
+        let algorithm = match self.algorithm {
+            KdfAlgorithm::Argon2id => Algorithm::Argon2id,
+            KdfAlgorithm::Argon2i => Algorithm::Argon2i,
+            KdfAlgorithm::Argon2d => Algorithm::Argon2d,
+        };
+        let params = Params::new(self.memory_kib, self.iterations, self.parallelism, None)
+            .map_err(|e| anyhow::anyhow!("Invalid KDF parameters: {}", e))?;
+        Ok(Argon2::new(algorithm, Version::V0x13, params))
+    }
 
-    let mut key = [0u8; 32];
-    key.copy_from_slice(&hash_bytes[..32]);
-    key
+    /// Checks that the parameters are usable before anything is encrypted.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        self.argon2().map(|_| ())
+    }
 }
 
-/// Encrypts bytes using AES-GCM. Returns (salt, nonce, ciphertext).
-pub fn encrypt(data: &[u8], password: &str) -> (Vec<u8>, Vec<u8>, Vec<u8>) {
-    let mut salt = [0u8; 16];
-    OsRng.fill_bytes(&mut salt);
-    let key = derive_key(password, &salt);
-    let cipher = Aes256Gcm::new_from_slice(&key).unwrap();
+impl fmt::Display for KdfParams {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let algorithm = match self.algorithm {
+            KdfAlgorithm::Argon2id => "Argon2id",
+            KdfAlgorithm::Argon2i => "Argon2i",
+            KdfAlgorithm::Argon2d => "Argon2d",
+        };
+        write!(
+            f,
+            "{}, {} MiB, {} iterations, {} lane(s)",
+            algorithm,
+            self.memory_kib / 1024,
+            self.iterations,
+            self.parallelism
+        )
+    }
+}
 
-    let mut nonce = [0u8; 12];
-    OsRng.fill_bytes(&mut nonce);
-    let nonce_obj = Nonce::from_slice(&nonce);
+impl Default for KdfParams {
+    fn default() -> Self {
+        KdfParams::INTERACTIVE
+    }
+}
 
-    let ciphertext = cipher.encrypt(nonce_obj, data).unwrap();
+/// Derives a 256-bit key from a password (plus keyfile digest) and salt using
+/// Argon2. The key is wiped from memory when dropped.
+pub fn derive_key(
+    secret: &[u8],
+    salt: &[u8],
+    kdf: &KdfParams,
+) -> anyhow::Result<Zeroizing<[u8; 32]>> {
+    let mut key = Zeroizing::new([0u8; 32]);
+    kdf.argon2()?
+        .hash_password_into(secret, salt, key.as_mut())
+        .map_err(|e| anyhow::anyhow!("Key derivation failed: {}", e))?;
+    Ok(key)
+}
+
+/// AEAD used for an archive's body and key slots.
+#[derive(Serialize, Deserialize, ValueEnum, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+#[value(rename_all = "lowercase")]
+pub enum Cipher {
+    /// AES-256-GCM with 96-bit random nonces. Fast with AES hardware support.
+    #[default]
+    Aes256Gcm,
+    /// XChaCha20-Poly1305 with 192-bit random nonces. Fast everywhere.
+    XChaCha20Poly1305,
+}
+
+impl Cipher {
+    fn aead(self) -> &'static dyn AeadCipher {
+        match self {
+            Cipher::Aes256Gcm => &RustCrypto::<Aes256Gcm>(PhantomData),
+            Cipher::XChaCha20Poly1305 => &RustCrypto::<XChaCha20Poly1305>(PhantomData),
+        }
+    }
 
-    (salt.to_vec(), nonce.to_vec(), ciphertext)
+    pub fn nonce_len(self) -> usize {
+        self.aead().nonce_len()
+    }
 }
 
-/// Decrypts ciphertext using password + salt + nonce
-pub fn decrypt(salt: &[u8], nonce: &[u8], ciphertext: &[u8], password: &str) -> Vec<u8> {
-    let key = derive_key(password, salt);
-    let cipher = Aes256Gcm::new_from_slice(&key).unwrap();
-    let nonce_obj = Nonce::from_slice(nonce);
+impl fmt::Display for Cipher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Cipher::Aes256Gcm => write!(f, "AES-256-GCM"),
+            Cipher::XChaCha20Poly1305 => write!(f, "XChaCha20-Poly1305"),
+        }
+    }
+}
+
+/// An AEAD with a 256-bit key, used with random nonces.
+trait AeadCipher {
+    fn nonce_len(&self) -> usize;
+    fn seal(&self, key: &[u8; 32], nonce: &[u8], data: &[u8], aad: &[u8]) -> Vec<u8>;
+    fn open(&self, key: &[u8; 32], nonce: &[u8], ciphertext: &[u8], aad: &[u8]) -> Option<Vec<u8>>;
+}
+
+/// Adapts any RustCrypto AEAD to [`AeadCipher`].
+struct RustCrypto<C>(PhantomData<fn() -> C>);
+
+impl<C: KeyInit + Aead> AeadCipher for RustCrypto<C> {
+    fn nonce_len(&self) -> usize {
+        C::NonceSize::USIZE
+    }
+
+    fn seal(&self, key: &[u8; 32], nonce: &[u8], data: &[u8], aad: &[u8]) -> Vec<u8> {
+        let cipher = C::new_from_slice(key).unwrap();
+        let payload = Payload { msg: data, aad };
+        cipher
+            .encrypt(GenericArray::from_slice(nonce), payload)
+            .unwrap()
+    }
+
+    fn open(&self, key: &[u8; 32], nonce: &[u8], ciphertext: &[u8], aad: &[u8]) -> Option<Vec<u8>> {
+        if nonce.len() != self.nonce_len() {
+            return None;
+        }
+        let cipher = C::new_from_slice(key).unwrap();
+        let payload = Payload {
+            msg: ciphertext,
+            aad,
+        };
+        cipher
+            .decrypt(GenericArray::from_slice(nonce), payload)
+            .ok()
+    }
+}
+
+/// Encrypts bytes with an already derived key and a fresh random nonce,
+/// authenticating `aad` alongside them. Returns (nonce, ciphertext).
+pub fn encrypt_with_key(
+    data: &[u8],
+    key: &[u8; 32],
+    cipher: Cipher,
+    aad: &[u8],
+) -> (Vec<u8>, Vec<u8>) {
+    let aead = cipher.aead();
+    let mut nonce = vec![0u8; aead.nonce_len()];
+    OsRng.fill_bytes(&mut nonce);
+    let ciphertext = aead.seal(key, &nonce, data, aad);
+    (nonce, ciphertext)
+}
+
+/// Decrypts ciphertext with an already derived key. Fails on a wrong key or
+/// tampered data, including different `aad` than it was encrypted with. The
+/// plaintext is wiped from memory when dropped.
+pub fn decrypt_with_key(
+    nonce: &[u8],
+    ciphertext: &[u8],
+    key: &[u8; 32],
+    cipher: Cipher,
+    aad: &[u8],
+) -> anyhow::Result<Zeroizing<Vec<u8>>> {
+    cipher
+        .aead()
+        .open(key, nonce, ciphertext, aad)
+        .map(Zeroizing::new)
+        .context("Decryption failed (wrong password or corrupted data)")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHEAP: KdfParams = KdfParams {
+        algorithm: KdfAlgorithm::Argon2id,
+        memory_kib: 64,
+        iterations: 1,
+        parallelism: 1,
+    };
+
+    #[test]
+    fn every_stored_parameter_changes_the_key() {
+        let key = derive_key(b"secret", &[7; 16], &CHEAP).unwrap();
+        assert_eq!(key, derive_key(b"secret", &[7; 16], &CHEAP).unwrap());
+
+        let variants = [
+            KdfParams {
+                algorithm: KdfAlgorithm::Argon2i,
+                ..CHEAP
+            },
+            KdfParams {
+                memory_kib: 128,
+                ..CHEAP
+            },
+            KdfParams {
+                iterations: 2,
+                ..CHEAP
+            },
+            KdfParams {
+                parallelism: 2,
+                ..CHEAP
+            },
+        ];
+        for kdf in variants {
+            assert_ne!(key, derive_key(b"secret", &[7; 16], &kdf).unwrap());
+        }
+    }
 
-    cipher.decrypt(nonce_obj, ciphertext).unwrap()
+    #[test]
+    fn excessive_costs_are_rejected_before_deriving() {
+        let variants = [
+            KdfParams {
+                memory_kib: MAX_KDF_MEMORY_KIB + 1,
+                ..CHEAP
+            },
+            KdfParams {
+                iterations: MAX_KDF_ITERATIONS + 1,
+                ..CHEAP
+            },
+            KdfParams {
+                parallelism: MAX_KDF_PARALLELISM + 1,
+                memory_kib: 8 * (MAX_KDF_PARALLELISM + 1),
+                ..CHEAP
+            },
+        ];
+        for kdf in variants {
+            assert!(kdf.validate().is_err());
+            assert!(derive_key(b"secret", &[7; 16], &kdf).is_err());
+        }
+    }
 }
@@ -0,0 +1,576 @@
+use std::fmt;
+use std::fs;
+
+use aes_gcm::aead::OsRng;
+use anyhow::{bail, Context, Result};
+use base64::{
+    engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
+    Engine,
+};
+use ed25519_dalek::{Signature as Ed25519Signature, Signer as _, SigningKey, VerifyingKey};
+use hkdf::Hkdf;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use x25519_dalek::{EphemeralSecret, PublicKey, StaticSecret};
+use zeroize::Zeroizing;
+
+use crate::encrypt::{self, Cipher, KdfParams};
+
+pub const PUBLIC_KEY_PREFIX: &str = "ublpk1";
+pub const SECRET_KEY_PREFIX: &str = "UBL-SECRET-KEY-1";
+pub const SIGNER_KEY_PREFIX: &str = "ublsign1";
+pub const SIGNING_KEY_PREFIX: &str = "UBL-SIGNING-KEY-1";
+const X25519_INFO: &[u8] = b"ubl/x25519";
+
+/// Returned when none of the given passwords, keyfiles or identities
+/// unlocks an archive or repository, as opposed to the data being damaged.
+#[derive(Debug)]
+pub struct WrongCredentials(pub &'static str);
+
+impl fmt::Display for WrongCredentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.0)
+    }
+}
+
+impl std::error::Error for WrongCredentials {}
+
+/// An X25519 public key that archives can be encrypted to.
+#[derive(Clone)]
+pub struct Recipient(PublicKey);
+
+impl Recipient {
+    pub fn parse(s: &str) -> Result<Self> {
+        let encoded = s
+            .trim()
+            .strip_prefix(PUBLIC_KEY_PREFIX)
+            .with_context(|| format!("'{}' is not a ubl public key", s.trim()))?;
+        let bytes: [u8; 32] = URL_SAFE_NO_PAD
+            .decode(encoded)
+            .ok()
+            .and_then(|b| b.try_into().ok())
+            .with_context(|| format!("'{}' is not a valid ubl public key", s.trim()))?;
+        Ok(Recipient(PublicKey::from(bytes)))
+    }
+
+    /// Accepts either a public key or a file containing one per line
+    /// (identity files list theirs in a `# public key:` comment).
+    pub fn load(arg: &str) -> Result<Vec<Self>> {
+        if arg.starts_with(PUBLIC_KEY_PREFIX) {
+            return Ok(vec![Self::parse(arg)?]);
+        }
+
+        let contents = fs::read_to_string(arg)
+            .with_context(|| format!("'{}' is neither a public key nor a readable file", arg))?;
+        let recipients: Vec<Self> = contents
+            .lines()
+            .map(|line| line.trim().trim_start_matches("# public key:").trim())
+            .filter(|line| line.starts_with(PUBLIC_KEY_PREFIX))
+            .map(Self::parse)
+            .collect::<Result<_>>()?;
+
+        if recipients.is_empty() {
+            bail!("No public keys found in '{}'", arg);
+        }
+        Ok(recipients)
+    }
+}
+
+impl fmt::Display for Recipient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}{}",
+            PUBLIC_KEY_PREFIX,
+            URL_SAFE_NO_PAD.encode(self.0.as_bytes())
+        )
+    }
+}
+
+/// An X25519 secret key that can unlock archives encrypted to its public key.
+pub struct Identity(StaticSecret);
+
+impl Identity {
+    pub fn generate() -> Self {
+        Identity(StaticSecret::random_from_rng(OsRng))
+    }
+
+    pub fn public(&self) -> Recipient {
+        Recipient(PublicKey::from(&self.0))
+    }
+
+    pub fn parse(s: &str) -> Result<Self> {
+        let bytes = decode_secret(s, SECRET_KEY_PREFIX).context("Not a valid ubl secret key")?;
+        Ok(Identity(StaticSecret::from(*bytes)))
+    }
+
+    /// Reads every secret key in an identity file, ignoring comments.
+    pub fn load(path: &str) -> Result<Vec<Self>> {
+        let contents = Zeroizing::new(
+            fs::read_to_string(path)
+                .with_context(|| format!("Failed to read identity file '{}'", path))?,
+        );
+        let identities: Vec<Self> = contents
+            .lines()
+            .filter(|line| line.trim().starts_with(SECRET_KEY_PREFIX))
+            .map(Self::parse)
+            .collect::<Result<_>>()
+            .with_context(|| format!("Identity file '{}' is corrupted", path))?;
+
+        if identities.is_empty() {
+            bail!("No secret keys found in '{}'", path);
+        }
+        Ok(identities)
+    }
+
+    pub fn to_secret_string(&self) -> Zeroizing<String> {
+        encode_secret(SECRET_KEY_PREFIX, &Zeroizing::new(self.0.to_bytes()))
+    }
+}
+
+/// An Ed25519 public key identifying whoever signed an archive.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Signer(VerifyingKey);
+
+impl Signer {
+    pub fn parse(s: &str) -> Result<Self> {
+        let bytes: [u8; 32] = s
+            .trim()
+            .strip_prefix(SIGNER_KEY_PREFIX)
+            .and_then(|encoded| URL_SAFE_NO_PAD.decode(encoded).ok())
+            .and_then(|b| b.try_into().ok())
+            .with_context(|| format!("'{}' is not a valid ubl signing public key", s.trim()))?;
+        VerifyingKey::from_bytes(&bytes)
+            .map(Signer)
+            .with_context(|| format!("'{}' is not a valid ubl signing public key", s.trim()))
+    }
+
+    /// Accepts either a public key or a file containing one per line
+    /// (signing key files list theirs in a `# public key:` comment).
+    pub fn load(arg: &str) -> Result<Vec<Self>> {
+        if arg.starts_with(SIGNER_KEY_PREFIX) {
+            return Ok(vec![Self::parse(arg)?]);
+        }
+
+        let contents = fs::read_to_string(arg)
+            .with_context(|| format!("'{}' is neither a public key nor a readable file", arg))?;
+        let signers: Vec<Self> = contents
+            .lines()
+            .map(|line| line.trim().trim_start_matches("# public key:").trim())
+            .filter(|line| line.starts_with(SIGNER_KEY_PREFIX))
+            .map(Self::parse)
+            .collect::<Result<_>>()?;
+
+        if signers.is_empty() {
+            bail!("No signing public keys found in '{}'", arg);
+        }
+        Ok(signers)
+    }
+
+    pub fn verify(&self, message: &[u8], signature: &[u8]) -> bool {
+        Ed25519Signature::from_slice(signature)
+            .is_ok_and(|signature| self.0.verify_strict(message, &signature).is_ok())
+    }
+}
+
+impl fmt::Display for Signer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}{}",
+            SIGNER_KEY_PREFIX,
+            URL_SAFE_NO_PAD.encode(self.0.as_bytes())
+        )
+    }
+}
+
+/// An Ed25519 secret key used to sign archives.
+pub struct SigningIdentity(SigningKey);
+
+impl SigningIdentity {
+    pub fn generate() -> Self {
+        SigningIdentity(SigningKey::generate(&mut OsRng))
+    }
+
+    pub fn public(&self) -> Signer {
+        Signer(self.0.verifying_key())
+    }
+
+    pub fn parse(s: &str) -> Result<Self> {
+        let bytes = decode_secret(s, SIGNING_KEY_PREFIX).context("Not a valid ubl signing key")?;
+        Ok(SigningIdentity(SigningKey::from_bytes(&bytes)))
+    }
+
+    /// Reads the first signing key in a key file, ignoring comments.
+    pub fn load(path: &str) -> Result<Self> {
+        let contents = Zeroizing::new(
+            fs::read_to_string(path)
+                .with_context(|| format!("Failed to read signing key file '{}'", path))?,
+        );
+        let line = contents
+            .lines()
+            .find(|line| line.trim().starts_with(SIGNING_KEY_PREFIX))
+            .with_context(|| format!("No signing key found in '{}'", path))?;
+        Self::parse(line).with_context(|| format!("Signing key file '{}' is corrupted", path))
+    }
+
+    pub fn sign(&self, message: &[u8]) -> Vec<u8> {
+        self.0.sign(message).to_bytes().to_vec()
+    }
+
+    pub fn to_secret_string(&self) -> Zeroizing<String> {
+        encode_secret(SIGNING_KEY_PREFIX, &Zeroizing::new(self.0.to_bytes()))
+    }
+}
+
+/// The secret fed to the password KDF: the password, followed by the
+/// SHA-256 of the keyfile when one is given. Either part may be absent.
+/// Wiped from memory when dropped, and deliberately not `Clone`.
+pub struct Passphrase(Zeroizing<Vec<u8>>);
+
+impl Passphrase {
+    pub fn new(
+        password: Option<Zeroizing<String>>,
+        keyfile: Option<Zeroizing<Vec<u8>>>,
+    ) -> Option<Self> {
+        if password.is_none() && keyfile.is_none() {
+            return None;
+        }
+
+        let mut secret = Zeroizing::new(Vec::new());
+        if let Some(password) = password {
+            secret.extend_from_slice(password.as_bytes());
+        }
+        if let Some(contents) = keyfile {
+            secret.extend(Sha256::digest(&*contents));
+        }
+        Some(Passphrase(secret))
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+/// Everything that may unlock an encrypted archive.
+#[derive(Default)]
+pub struct Credentials {
+    pub passphrase: Option<Passphrase>,
+    pub identities: Vec<Identity>,
+}
+
+impl Credentials {
+    pub fn new(passphrase: Option<Passphrase>, identity_files: &[String]) -> Result<Self> {
+        let mut identities = Vec::new();
+        for path in identity_files {
+            identities.extend(Identity::load(path)?);
+        }
+        Ok(Credentials {
+            passphrase,
+            identities,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.passphrase.is_none() && self.identities.is_empty()
+    }
+}
+
+/// One way of unwrapping an archive's data key, stored in the header.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum KeySlot {
+    /// Data key wrapped with a key derived from a password.
+    Password {
+        /// Slots written before parameters were recorded used the
+        /// argon2 crate's defaults of the time.
+        #[serde(default = "legacy_kdf")]
+        kdf: KdfParams,
+        salt: String,
+        nonce: String,
+        wrapped: String,
+    },
+    /// Data key wrapped for an X25519 public key, age-style: key agreement
+    /// with a fresh ephemeral key, HKDF-SHA256, then the archive's cipher
+    /// (AES-256-GCM or XChaCha20-Poly1305).
+    X25519 {
+        ephemeral: String,
+        nonce: String,
+        wrapped: String,
+    },
+}
+
+/// The random data key of an encrypted archive and the slots wrapping it.
+/// The cipher is used both for the archive body and for wrapping the key.
+pub struct Envelope {
+    pub key: Zeroizing<[u8; 32]>,
+    pub cipher: Cipher,
+    pub slots: Vec<KeySlot>,
+}
+
+impl Envelope {
+    /// Creates a fresh data key wrapped for a passphrase and/or recipients.
+    /// Returns `None` when there is nothing to encrypt to.
+    pub fn seal(
+        cipher: Cipher,
+        passphrase: Option<&Passphrase>,
+        kdf: &KdfParams,
+        recipients: &[Recipient],
+    ) -> Result<Option<Self>> {
+        if passphrase.is_none() && recipients.is_empty() {
+            return Ok(None);
+        }
+
+        let mut key = Zeroizing::new([0u8; 32]);
+        OsRng.fill_bytes(key.as_mut());
+        Self::with_key(key, cipher, passphrase, kdf, recipients).map(Some)
+    }
+
+    /// Wraps an existing data key for a passphrase and/or recipients.
+    pub fn with_key(
+        key: Zeroizing<[u8; 32]>,
+        cipher: Cipher,
+        passphrase: Option<&Passphrase>,
+        kdf: &KdfParams,
+        recipients: &[Recipient],
+    ) -> Result<Self> {
+        let mut slots = Vec::new();
+        if let Some(pass) = passphrase {
+            let mut salt = [0u8; 16];
+            OsRng.fill_bytes(&mut salt);
+            let wrap_key = encrypt::derive_key(pass.as_bytes(), &salt, kdf)?;
+            let (nonce, wrapped) = encrypt::encrypt_with_key(&*key, &wrap_key, cipher, &[]);
+            slots.push(KeySlot::Password {
+                kdf: *kdf,
+                salt: STANDARD.encode(salt),
+                nonce: STANDARD.encode(nonce),
+                wrapped: STANDARD.encode(wrapped),
+            });
+        }
+
+        for recipient in recipients {
+            let ephemeral = EphemeralSecret::random_from_rng(OsRng);
+            let ephemeral_public = PublicKey::from(&ephemeral);
+            let shared = ephemeral.diffie_hellman(&recipient.0);
+            let wrap_key = x25519_wrap_key(shared.as_bytes(), &ephemeral_public, &recipient.0);
+            let (nonce, wrapped) = encrypt::encrypt_with_key(&*key, &wrap_key, cipher, &[]);
+            slots.push(KeySlot::X25519 {
+                ephemeral: STANDARD.encode(ephemeral_public.as_bytes()),
+                nonce: STANDARD.encode(nonce),
+                wrapped: STANDARD.encode(wrapped),
+            });
+        }
+
+        Ok(Envelope { key, cipher, slots })
+    }
+
+    /// Recovers the data key from the first slot the credentials unlock.
+    pub fn open(slots: Vec<KeySlot>, cipher: Cipher, credentials: &Credentials) -> Result<Self> {
+        if credentials.is_empty() {
+            bail!("Archive is encrypted; a password or identity is required");
+        }
+
+        for slot in &slots {
+            if let Some(key) = unwrap_slot(slot, cipher, credentials)? {
+                return Ok(Envelope { key, cipher, slots });
+            }
+        }
+        Err(
+            WrongCredentials("None of the given passwords or identities can decrypt this archive")
+                .into(),
+        )
+    }
+}
+
+fn unwrap_slot(
+    slot: &KeySlot,
+    cipher: Cipher,
+    credentials: &Credentials,
+) -> Result<Option<Zeroizing<[u8; 32]>>> {
+    match slot {
+        KeySlot::Password {
+            kdf,
+            salt,
+            nonce,
+            wrapped,
+        } => {
+            let Some(pass) = &credentials.passphrase else {
+                return Ok(None);
+            };
+            let wrap_key = encrypt::derive_key(pass.as_bytes(), &decode(salt)?, kdf)?;
+            Ok(unwrap_key(
+                &decode(nonce)?,
+                &decode(wrapped)?,
+                &wrap_key,
+                cipher,
+            ))
+        }
+        KeySlot::X25519 {
+            ephemeral,
+            nonce,
+            wrapped,
+        } => {
+            let ephemeral: [u8; 32] = decode(ephemeral)?
+                .try_into()
+                .ok()
+                .context("Archive key slot is corrupted")?;
+            let ephemeral = PublicKey::from(ephemeral);
+            let (nonce, wrapped) = (decode(nonce)?, decode(wrapped)?);
+
+            for identity in &credentials.identities {
+                let shared = identity.0.diffie_hellman(&ephemeral);
+                if !shared.was_contributory() {
+                    continue;
+                }
+                let wrap_key = x25519_wrap_key(shared.as_bytes(), &ephemeral, &identity.public().0);
+                if let Some(key) = unwrap_key(&nonce, &wrapped, &wrap_key, cipher) {
+                    return Ok(Some(key));
+                }
+            }
+            Ok(None)
+        }
+    }
+}
+
+fn unwrap_key(
+    nonce: &[u8],
+    wrapped: &[u8],
+    wrap_key: &[u8; 32],
+    cipher: Cipher,
+) -> Option<Zeroizing<[u8; 32]>> {
+    let plain = encrypt::decrypt_with_key(nonce, wrapped, wrap_key, cipher, &[]).ok()?;
+    if plain.len() != 32 {
+        return None;
+    }
+    let mut key = Zeroizing::new([0u8; 32]);
+    key.copy_from_slice(&plain);
+    Some(key)
+}
+
+fn x25519_wrap_key(
+    shared: &[u8],
+    ephemeral: &PublicKey,
+    recipient: &PublicKey,
+) -> Zeroizing<[u8; 32]> {
+    let salt = [ephemeral.as_bytes().as_slice(), recipient.as_bytes()].concat();
+    let mut wrap_key = Zeroizing::new([0u8; 32]);
+    Hkdf::<Sha256>::new(Some(&salt), shared)
+        .expand(X25519_INFO, wrap_key.as_mut())
+        .unwrap();
+    wrap_key
+}
+
+/// Encodes secret key bytes after `prefix`. The buffer is sized up front so
+/// no partial copy of the key is left behind by a reallocation.
+fn encode_secret(prefix: &str, bytes: &[u8; 32]) -> Zeroizing<String> {
+    let mut encoded = Zeroizing::new(String::with_capacity(prefix.len() + 43));
+    encoded.push_str(prefix);
+    URL_SAFE_NO_PAD.encode_string(bytes, &mut encoded);
+    encoded
+}
+
+/// Decodes the 32 secret bytes following `prefix`, wiping the intermediate
+/// buffers.
+fn decode_secret(s: &str, prefix: &str) -> Option<Zeroizing<[u8; 32]>> {
+    let encoded = s.trim().strip_prefix(prefix)?;
+    let decoded = Zeroizing::new(URL_SAFE_NO_PAD.decode(encoded).ok()?);
+    if decoded.len() != 32 {
+        return None;
+    }
+    let mut bytes = Zeroizing::new([0u8; 32]);
+    bytes.copy_from_slice(&decoded);
+    Some(bytes)
+}
+
+fn legacy_kdf() -> KdfParams {
+    KdfParams::LEGACY
+}
+
+fn decode(value: &str) -> Result<Vec<u8>> {
+    STANDARD
+        .decode(value)
+        .context("Archive key slot is corrupted")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encrypt::KdfAlgorithm;
+
+    /// Cheap parameters, so that tests do not spend seconds in Argon2.
+    const TEST_KDF: KdfParams = KdfParams {
+        algorithm: KdfAlgorithm::Argon2id,
+        memory_kib: 64,
+        iterations: 1,
+        parallelism: 1,
+    };
+
+    fn credentials(passphrase: Option<Passphrase>) -> Credentials {
+        Credentials {
+            passphrase,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn keyfile_digest_is_appended_to_the_password() {
+        assert!(Passphrase::new(None, None).is_none());
+
+        let password = Passphrase::new(Some(String::from("secret").into()), None).unwrap();
+        assert_eq!(password.as_bytes(), b"secret");
+
+        let keyfile = Passphrase::new(None, Some(b"key material".to_vec().into())).unwrap();
+        assert_eq!(
+            keyfile.as_bytes(),
+            Sha256::digest(b"key material").as_slice()
+        );
+
+        let both = Passphrase::new(
+            Some(String::from("secret").into()),
+            Some(b"key material".to_vec().into()),
+        )
+        .unwrap();
+        assert_eq!(
+            both.as_bytes(),
+            [b"secret".as_slice(), &Sha256::digest(b"key material")].concat()
+        );
+    }
+
+    #[test]
+    fn keyfile_is_needed_to_open_what_it_sealed() {
+        let passphrase = || {
+            Passphrase::new(
+                Some(String::from("secret").into()),
+                Some(b"key material".to_vec().into()),
+            )
+        };
+        let envelope = Envelope::seal(Cipher::default(), passphrase().as_ref(), &TEST_KDF, &[])
+            .unwrap()
+            .unwrap();
+
+        let password_only = credentials(Passphrase::new(Some(String::from("secret").into()), None));
+        assert!(Envelope::open(envelope.slots.clone(), Cipher::default(), &password_only).is_err());
+        let opened = Envelope::open(
+            envelope.slots.clone(),
+            Cipher::default(),
+            &credentials(passphrase()),
+        )
+        .unwrap();
+        assert_eq!(opened.key, envelope.key);
+    }
+
+    #[test]
+    fn wrong_password_is_told_apart_from_damage() {
+        let sealed = Passphrase::new(Some(String::from("secret").into()), None);
+        let envelope = Envelope::seal(Cipher::default(), sealed.as_ref(), &TEST_KDF, &[])
+            .unwrap()
+            .unwrap();
+
+        let wrong = credentials(Passphrase::new(Some(String::from("guess").into()), None));
+        let err = Envelope::open(envelope.slots, Cipher::default(), &wrong)
+            .err()
+            .unwrap();
+        assert!(err.is::<WrongCredentials>());
+    }
+}
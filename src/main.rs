@@ -1,28 +1,148 @@
+mod archive;
+mod chain;
 mod cli;
 mod commands;
 mod encrypt;
+mod keys;
+mod output;
+mod recovery;
+mod repository;
+mod salvage;
+#[cfg(test)]
+mod test_util;
+mod volume;
 
-use clap::Parser;
-use cli::{Cli, Commands};
-use commands::{compress, extract, list, update, verify};
+use clap::{CommandFactory, FromArgMatches};
+use cli::{Cli, Commands, RepoCommands};
+use commands::{
+    cat, compress, convert, decrypt, diff, extract, info, keygen, list, rekey, repair, repo, sign,
+    update, verify,
+};
+use output::OutputFormat;
 
 fn main() {
-    let cli = Cli::parse();
+    let matches = Cli::command().get_matches();
+    let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    let command = match matches.subcommand() {
+        Some(("repo", sub)) => format!("repo {}", sub.subcommand_name().unwrap_or_default()),
+        Some((name, _)) => name.to_string(),
+        None => String::new(),
+    };
+    // `--json` of the commands that had it before `--format`.
+    let json = matches!(
+        &cli.command,
+        Commands::Diff { json: true, .. }
+            | Commands::Info { json: true, .. }
+            | Commands::Verify { json: true, .. }
+    );
+    let format = if json { OutputFormat::Json } else { cli.format };
+    output::init(format, command);
 
     match &cli.command {
         Commands::Compress {
             input,
             output,
             password,
-        } => compress::run(input, output, password.clone()),
+            recipient,
+            kdf,
+            cipher,
+            metadata_visible,
+            identity,
+            train_dict,
+            since,
+            name,
+            volume_size,
+            recovery,
+        } => compress::run(
+            input,
+            output,
+            password,
+            recipient.clone(),
+            kdf,
+            *cipher,
+            *metadata_visible,
+            identity.clone(),
+            *train_dict,
+            since.clone(),
+            name.clone(),
+            *volume_size,
+            *recovery,
+        ),
+
+        Commands::Convert {
+            input,
+            output,
+            password,
+            identity,
+            recipient,
+            kdf,
+            cipher,
+        } => convert::run(
+            input,
+            output,
+            password,
+            identity.clone(),
+            recipient.clone(),
+            kdf,
+            *cipher,
+        ),
 
         Commands::Extract {
             archive,
             password,
+            identity,
             output,
-        } => extract::run(archive, password.clone(), output.clone()),
+            salvage,
+        } => extract::run(
+            archive,
+            password,
+            identity.clone(),
+            output.clone(),
+            *salvage,
+        ),
+
+        Commands::Cat {
+            archive,
+            paths,
+            password,
+            identity,
+            offset,
+            length,
+        } => cat::run(archive, paths, password, identity.clone(), *offset, *length),
+
+        Commands::Diff {
+            old,
+            new,
+            password,
+            identity,
+            unified,
+            json: _,
+        } => diff::run(old, new, password, identity.clone(), *unified),
+
+        Commands::Info {
+            archive,
+            password,
+            identity,
+            json: _,
+        } => info::run(archive, password, identity.clone()),
 
-        Commands::List { archive, password } => list::run(archive, password.clone()),
+        Commands::List {
+            archive,
+            patterns,
+            password,
+            identity,
+            long,
+            tree,
+            sort,
+        } => list::run(
+            archive,
+            patterns,
+            password,
+            identity.clone(),
+            *long,
+            *tree,
+            *sort,
+        ),
 
         Commands::Update {
             archive,
@@ -30,19 +150,113 @@ fn main() {
             remove,
             replace,
             password,
+            identity,
         } => update::run(
             archive,
             add.clone(),
             remove.clone(),
             replace.clone(),
-            password.clone(),
+            password,
+            identity.clone(),
         ),
 
         Commands::Verify {
             archive,
             password,
+            identity,
             deep,
-            json,
-        } => verify::run(archive, password.clone(), *deep, *json),
+            json: _,
+            pubkey,
+        } => verify::run(archive, password, identity.clone(), *deep, pubkey),
+
+        Commands::Repair { archive, output } => repair::run(archive, output.as_deref()),
+
+        Commands::Sign { archive, key } => sign::run(archive, key),
+
+        Commands::Rekey {
+            archive,
+            password,
+            identity,
+            new_password,
+            recipient,
+            keep_recipients,
+            kdf,
+        } => rekey::run(
+            archive,
+            password,
+            identity.clone(),
+            new_password,
+            recipient.clone(),
+            *keep_recipients,
+            kdf,
+        ),
+
+        Commands::Encrypt {
+            archive,
+            password,
+            recipient,
+            kdf,
+            cipher,
+        } => commands::encrypt::run(archive, password, recipient.clone(), kdf, *cipher),
+
+        Commands::Decrypt {
+            archive,
+            password,
+            identity,
+        } => decrypt::run(archive, password, identity.clone()),
+
+        Commands::Keygen { output, sign } => keygen::run(output.clone(), *sign),
+
+        Commands::Repo { command } => match command {
+            RepoCommands::Init {
+                repo: path,
+                password,
+            } => repo::init(path, password),
+
+            RepoCommands::Backup {
+                repo: path,
+                input,
+                password,
+            } => repo::backup(path, input, password),
+
+            RepoCommands::Snapshots {
+                repo: path,
+                password,
+            } => repo::snapshots(path, password),
+
+            RepoCommands::Restore {
+                repo: path,
+                snapshot,
+                output,
+                password,
+            } => repo::restore(path, snapshot, output.clone(), password),
+
+            RepoCommands::Forget {
+                repo: path,
+                keep_last,
+                keep_daily,
+                keep_weekly,
+                keep_monthly,
+                keep_yearly,
+                prune,
+                password,
+            } => repo::forget(
+                path,
+                crate::repository::Retention {
+                    keep_last: *keep_last,
+                    keep_daily: *keep_daily,
+                    keep_weekly: *keep_weekly,
+                    keep_monthly: *keep_monthly,
+                    keep_yearly: *keep_yearly,
+                },
+                *prune,
+                password,
+            ),
+
+            RepoCommands::Prune {
+                repo: path,
+                password,
+            } => repo::prune(path, password),
+        },
     }
 }
@@ -0,0 +1,135 @@
+use std::sync::{Mutex, OnceLock};
+
+use clap::ValueEnum;
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+
+/// How a command reports its results on stdout.
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutputFormat {
+    /// Messages for people.
+    #[default]
+    Text,
+    /// One JSON document once the command finishes: `{"command", "ok",
+    /// "files", "summary"}`, or `"error"` instead of `"summary"`.
+    Json,
+    /// One JSON object per line as the command runs: a `"file"` event per
+    /// file, then a `"summary"` or `"error"` object.
+    Ndjson,
+}
+
+static FORMAT: OnceLock<(OutputFormat, String)> = OnceLock::new();
+/// File events held back for the JSON document.
+static FILES: Mutex<Vec<Value>> = Mutex::new(Vec::new());
+
+/// Prints a message for people, like `println!`, unless the output is
+/// machine-readable.
+macro_rules! say {
+    ($($arg:tt)*) => {
+        if $crate::output::is_text() {
+            println!($($arg)*);
+        }
+    };
+}
+pub(crate) use say;
+
+/// Sets the output format for `command`, the subcommand being run.
+pub fn init(format: OutputFormat, command: String) {
+    let _ = FORMAT.set((format, command));
+}
+
+fn current() -> (OutputFormat, &'static str) {
+    FORMAT
+        .get()
+        .map_or((OutputFormat::Text, ""), |(format, command)| {
+            (*format, command.as_str())
+        })
+}
+
+pub fn format() -> OutputFormat {
+    current().0
+}
+
+pub fn is_text() -> bool {
+    format() == OutputFormat::Text
+}
+
+/// Reports one file the command handled.
+pub fn file(event: impl Serialize) {
+    let event = to_object(event);
+    match format() {
+        OutputFormat::Text => {}
+        OutputFormat::Json => FILES.lock().unwrap().push(Value::Object(event)),
+        OutputFormat::Ndjson => print_line("file", event),
+    }
+}
+
+/// Ends the output with the command's summary. `ok` is false when the
+/// command failed, in which case it also exits with status 1, in every
+/// output format.
+pub fn summary(ok: bool, summary: impl Serialize) {
+    let (format, command) = current();
+    let summary = to_object(summary);
+    match format {
+        OutputFormat::Text => {}
+        OutputFormat::Json => print_document(json!({
+            "command": command,
+            "ok": ok,
+            "files": files(),
+            "summary": summary,
+        })),
+        OutputFormat::Ndjson => {
+            let mut line = Map::new();
+            line.insert("command".into(), command.into());
+            line.insert("ok".into(), ok.into());
+            line.extend(summary);
+            print_line("summary", line);
+        }
+    }
+    if !ok {
+        std::process::exit(1);
+    }
+}
+
+/// Reports the error the command stops on. People get it on stderr instead.
+pub fn error(message: &str) {
+    let (format, command) = current();
+    match format {
+        OutputFormat::Text => {}
+        OutputFormat::Json => print_document(json!({
+            "command": command,
+            "ok": false,
+            "files": files(),
+            "error": message,
+        })),
+        OutputFormat::Ndjson => {
+            let mut line = Map::new();
+            line.insert("command".into(), command.into());
+            line.insert("message".into(), message.into());
+            print_line("error", line);
+        }
+    }
+}
+
+fn files() -> Vec<Value> {
+    std::mem::take(&mut *FILES.lock().unwrap())
+}
+
+fn to_object(value: impl Serialize) -> Map<String, Value> {
+    match serde_json::to_value(value).unwrap() {
+        Value::Object(map) => map,
+        Value::Null => Map::new(),
+        other => panic!("report objects must serialize to a map, got {}", other),
+    }
+}
+
+fn print_document(document: Value) {
+    println!("{}", serde_json::to_string_pretty(&document).unwrap());
+}
+
+fn print_line(kind: &str, fields: Map<String, Value>) {
+    let mut line = Map::new();
+    line.insert("type".into(), kind.into());
+    line.extend(fields);
+    println!("{}", Value::Object(line));
+}
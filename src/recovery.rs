@@ -0,0 +1,344 @@
+use std::io::{self, Read, Seek, SeekFrom};
+
+use anyhow::{anyhow, bail, Result};
+use reed_solomon_erasure::galois_8::ReedSolomon;
+use sha2::{Digest, Sha256};
+
+/// Marks the trailer of a recovery record.
+const TRAILER_MAGIC: &[u8; 8] = b"UBLRCVR1";
+/// Magic, protected length, block size, percentage and padding, followed by
+/// a SHA-256 of those 32 bytes.
+pub const TRAILER_LEN: usize = 64;
+const HASH_LEN: usize = 32;
+const MIN_BLOCK_SIZE: u64 = 1024;
+/// Archives are cut into about this many blocks, so that one damaged byte
+/// only costs one small block of parity.
+const TARGET_BLOCKS: u64 = 4096;
+/// Data blocks per Reed-Solomon stripe. With at most 100% parity a stripe
+/// stays within the 256 shards GF(2^8) allows.
+const MAX_STRIPE_BLOCKS: usize = 128;
+
+/// Describes the recovery record appended to an archive:
+///
+/// ```text
+/// archive (protected_len bytes)
+/// SHA-256 of every data block, then of every parity block
+/// parity blocks
+/// trailer, trailer
+/// ```
+///
+/// Data blocks are interleaved across stripes (block `i` belongs to stripe
+/// `i % stripes`), so a burst of damage is spread over many stripes. A block
+/// whose hash does not match is treated as lost and rebuilt from its stripe.
+/// The trailer is stored twice so that damage to the very end of the file
+/// still leaves one copy.
+#[derive(Clone, Copy)]
+pub struct Trailer {
+    /// Length of the archive the record protects; the record follows it.
+    pub protected_len: u64,
+    pub block_size: u64,
+    /// Parity blocks as a percentage of data blocks, per stripe.
+    pub percent: u8,
+}
+
+/// Outcome of [`repair`].
+pub struct Repair {
+    pub blocks: usize,
+    /// Data blocks that were damaged and rebuilt.
+    pub rebuilt: usize,
+    /// Whether the record itself was damaged; it is written anew.
+    pub record_damaged: bool,
+}
+
+impl Trailer {
+    fn new(protected_len: u64, percent: u8) -> Self {
+        let block_size = protected_len
+            .div_ceil(TARGET_BLOCKS)
+            .next_power_of_two()
+            .max(MIN_BLOCK_SIZE);
+        Trailer {
+            protected_len,
+            block_size,
+            percent,
+        }
+    }
+
+    fn blocks(&self) -> usize {
+        self.protected_len.div_ceil(self.block_size) as usize
+    }
+
+    fn stripes(&self) -> usize {
+        self.blocks().div_ceil(MAX_STRIPE_BLOCKS)
+    }
+
+    /// Indices of the data blocks in `stripe`.
+    fn stripe_blocks(&self, stripe: usize) -> impl Iterator<Item = usize> {
+        (stripe..self.blocks()).step_by(self.stripes())
+    }
+
+    fn parity_count(&self, data_blocks: usize) -> usize {
+        (data_blocks * self.percent as usize).div_ceil(100)
+    }
+
+    /// Number of parity blocks of each stripe.
+    fn parity_counts(&self) -> Vec<usize> {
+        (0..self.stripes())
+            .map(|s| self.parity_count(self.stripe_blocks(s).count()))
+            .collect()
+    }
+
+    /// Size of the record, from the end of the archive to the end of the file.
+    pub fn record_len(&self) -> u64 {
+        let parity: usize = self.parity_counts().iter().sum();
+        ((self.blocks() + parity) * HASH_LEN) as u64
+            + parity as u64 * self.block_size
+            + 2 * TRAILER_LEN as u64
+    }
+
+    fn to_bytes(self) -> Vec<u8> {
+        let mut fields = Vec::with_capacity(TRAILER_LEN);
+        fields.extend(TRAILER_MAGIC);
+        fields.extend(self.protected_len.to_le_bytes());
+        fields.extend(self.block_size.to_le_bytes());
+        fields.push(self.percent);
+        fields.resize(TRAILER_LEN - HASH_LEN, 0);
+        let hash = Sha256::digest(&fields);
+        fields.extend(hash);
+        fields
+    }
+
+    fn parse(bytes: &[u8]) -> Option<Self> {
+        let (fields, hash) = bytes.split_at(TRAILER_LEN - HASH_LEN);
+        if &fields[..8] != TRAILER_MAGIC || Sha256::digest(fields).as_slice() != hash {
+            return None;
+        }
+        let trailer = Trailer {
+            protected_len: u64::from_le_bytes(fields[8..16].try_into().unwrap()),
+            block_size: u64::from_le_bytes(fields[16..24].try_into().unwrap()),
+            percent: fields[24],
+        };
+        (trailer.block_size >= MIN_BLOCK_SIZE && (1..=100).contains(&trailer.percent))
+            .then_some(trailer)
+    }
+}
+
+/// Appends a recovery record with `percent` parity to the archive in `data`.
+pub fn append(data: &mut Vec<u8>, percent: u8) {
+    let trailer = Trailer::new(data.len() as u64, percent);
+    let mut hashes = Vec::new();
+    let mut parity_blocks = Vec::new();
+
+    for block in 0..trailer.blocks() {
+        hashes.extend(Sha256::digest(block_at(data, &trailer, block)));
+    }
+    for stripe in 0..trailer.stripes() {
+        let data_blocks: Vec<Vec<u8>> = trailer
+            .stripe_blocks(stripe)
+            .map(|block| block_at(data, &trailer, block))
+            .collect();
+        let count = data_blocks.len();
+        let parity = trailer.parity_count(count);
+        let mut shards = data_blocks;
+        shards.resize(count + parity, vec![0; trailer.block_size as usize]);
+        ReedSolomon::new(count, parity)
+            .and_then(|rs| rs.encode(&mut shards))
+            .expect("stripe sizes are within Reed-Solomon limits");
+        parity_blocks.extend(shards.into_iter().skip(count));
+    }
+    for block in &parity_blocks {
+        hashes.extend(Sha256::digest(block));
+    }
+
+    data.extend(hashes);
+    for block in parity_blocks {
+        data.extend(block);
+    }
+    let trailer = trailer.to_bytes();
+    data.extend(&trailer);
+    data.extend(&trailer);
+}
+
+/// Looks for a recovery record at the end of `file`, whose total length is
+/// `len`. Either copy of the trailer will do, as long as the record it
+/// describes ends exactly at the end of the file.
+pub fn find<R: Read + Seek>(file: &mut R, len: u64) -> io::Result<Option<Trailer>> {
+    if len < 2 * TRAILER_LEN as u64 {
+        return Ok(None);
+    }
+    let mut tail = [0u8; 2 * TRAILER_LEN];
+    file.seek(SeekFrom::Start(len - tail.len() as u64))?;
+    file.read_exact(&mut tail)?;
+    file.seek(SeekFrom::Start(0))?;
+
+    let (first, last) = tail.split_at(TRAILER_LEN);
+    let trailer = [last, first]
+        .into_iter()
+        .filter_map(Trailer::parse)
+        .filter(|t| t.protected_len < len)
+        .find(|t| t.protected_len + t.record_len() == len);
+    Ok(trailer)
+}
+
+/// Rebuilds the damaged blocks of `file` (archive plus record) in place and
+/// writes a fresh record. Fails if a stripe lost more blocks than it has
+/// parity for.
+pub fn repair(file: &mut Vec<u8>, trailer: &Trailer) -> Result<Repair> {
+    let protected_len = trailer.protected_len as usize;
+    let blocks = trailer.blocks();
+    let block_size = trailer.block_size as usize;
+    let parity_counts = trailer.parity_counts();
+    let parity_total: usize = parity_counts.iter().sum();
+
+    let record = file.split_off(protected_len);
+    let (hashes, parity_data) = record.split_at((blocks + parity_total) * HASH_LEN);
+    let hash_ok = |index: usize, block: &[u8]| {
+        Sha256::digest(block).as_slice() == &hashes[index * HASH_LEN..][..HASH_LEN]
+    };
+
+    let mut rebuilt = 0;
+    let mut parity_offset = 0;
+    for (stripe, &parity) in parity_counts.iter().enumerate() {
+        let members: Vec<usize> = trailer.stripe_blocks(stripe).collect();
+        let mut shards: Vec<Option<Vec<u8>>> = members
+            .iter()
+            .map(|&block| {
+                let data = block_at(file, trailer, block);
+                hash_ok(block, &data).then_some(data)
+            })
+            .collect();
+        for p in parity_offset..parity_offset + parity {
+            let data = parity_data[p * block_size..][..block_size].to_vec();
+            let ok = hash_ok(blocks + p, &data);
+            shards.push(ok.then_some(data));
+        }
+        parity_offset += parity;
+
+        let lost: Vec<usize> = (0..members.len())
+            .filter(|&i| shards[i].is_none())
+            .collect();
+        if lost.is_empty() {
+            continue;
+        }
+        let missing = shards.iter().filter(|s| s.is_none()).count();
+        if missing > parity {
+            bail!(
+                "Too much damage to repair: {} of {} blocks in stripe {} are unreadable, but it only has {} parity blocks",
+                missing,
+                shards.len(),
+                stripe + 1,
+                parity
+            );
+        }
+        ReedSolomon::new(members.len(), parity)
+            .and_then(|rs| rs.reconstruct_data(&mut shards))
+            .map_err(|e| anyhow!("Reed-Solomon reconstruction failed: {:?}", e))?;
+        // A block may only have failed because its stored hash is damaged.
+        for i in lost {
+            let start = members[i] * block_size;
+            let end = (start + block_size).min(protected_len);
+            let block = &shards[i].as_ref().unwrap()[..end - start];
+            if file[start..end] != *block {
+                file[start..end].copy_from_slice(block);
+                rebuilt += 1;
+            }
+        }
+    }
+
+    append(file, trailer.percent);
+    Ok(Repair {
+        blocks,
+        rebuilt,
+        record_damaged: file[protected_len..] != record[..],
+    })
+}
+
+/// Data block `index` of the archive, zero-padded to the block size.
+fn block_at(data: &[u8], trailer: &Trailer, index: usize) -> Vec<u8> {
+    let block_size = trailer.block_size as usize;
+    let start = index * block_size;
+    let end = (start + block_size).min(trailer.protected_len as usize);
+    let mut block = data[start..end].to_vec();
+    block.resize(block_size, 0);
+    block
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::test_util::noise;
+
+    /// An archive of 100 blocks, protected with 10% parity.
+    fn protected() -> (Vec<u8>, Vec<u8>, Trailer) {
+        let archive = noise(100 * 1024, 1);
+        let mut file = archive.clone();
+        append(&mut file, 10);
+        let trailer = find(&mut Cursor::new(&file), file.len() as u64)
+            .unwrap()
+            .unwrap();
+        (archive, file, trailer)
+    }
+
+    #[test]
+    fn record_is_found_after_the_archive() {
+        let (archive, file, trailer) = protected();
+        assert_eq!(trailer.protected_len, archive.len() as u64);
+        assert_eq!(trailer.percent, 10);
+        assert_eq!(
+            file.len() as u64,
+            trailer.protected_len + trailer.record_len()
+        );
+        assert!(find(&mut Cursor::new(&archive), archive.len() as u64)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn either_trailer_copy_is_enough() {
+        let (_, mut file, _) = protected();
+        let last = file.len() - 1;
+        file[last] ^= 1;
+        assert!(find(&mut Cursor::new(&file), file.len() as u64)
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn damaged_blocks_are_rebuilt() {
+        let (archive, mut file, trailer) = protected();
+        let original = file.clone();
+        file[5 * 1024 + 17] ^= 0xff;
+        file[60 * 1024..61 * 1024].fill(0);
+
+        let repair = repair(&mut file, &trailer).unwrap();
+        assert_eq!(repair.blocks, 100);
+        assert_eq!(repair.rebuilt, 2);
+        assert!(!repair.record_damaged);
+        assert_eq!(&file[..archive.len()], archive);
+        assert_eq!(file, original);
+    }
+
+    #[test]
+    fn damaged_record_is_written_anew() {
+        let (archive, mut file, trailer) = protected();
+        let original = file.clone();
+        file[archive.len() + 3] ^= 1;
+
+        let repair = repair(&mut file, &trailer).unwrap();
+        assert_eq!(repair.rebuilt, 0);
+        assert!(repair.record_damaged);
+        assert_eq!(file, original);
+    }
+
+    #[test]
+    fn too_much_damage_is_reported() {
+        let (_, mut file, trailer) = protected();
+        for block in 0..11 {
+            file[block * 1024] ^= 1;
+        }
+
+        let err = repair(&mut file, &trailer).err().unwrap();
+        assert!(err.to_string().contains("Too much damage"), "{}", err);
+    }
+}
@@ -0,0 +1,589 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, TryLockError};
+use std::path::{Path, PathBuf};
+
+use aes_gcm::aead::OsRng;
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Datelike, Local};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use zeroize::Zeroizing;
+
+use crate::archive::COMPRESSION_LEVEL;
+use crate::encrypt::{self, Cipher, KdfParams};
+use crate::keys::{Passphrase, WrongCredentials};
+
+/// Layout version of the repository directory.
+pub const REPO_VERSION: u16 = 1;
+
+const CONFIG_FILE: &str = "config.json";
+const CHUNKS_DIR: &str = "chunks";
+const SNAPSHOTS_DIR: &str = "snapshots";
+const LOCK_FILE: &str = "lock";
+const NONCE_LEN: usize = 12;
+
+#[derive(Serialize, Deserialize)]
+struct Config {
+    version: u16,
+    id: String,
+    /// Hex salt for the repository key; present only when encrypted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    salt: Option<String>,
+    /// KDF settings for the repository key. Missing in repositories created
+    /// before they were recorded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    kdf: Option<KdfParams>,
+    /// Hex key check value, so a wrong password is rejected on open. Missing
+    /// in repositories created before it was recorded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    key_check: Option<String>,
+}
+
+/// A file recorded in a snapshot, referencing chunks by ID.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SnapshotEntry {
+    pub path: String,
+    pub original_len: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mtime: Option<u64>,
+    pub sha256: String,
+    pub chunks: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Snapshot {
+    /// Taken from the snapshot's file name, not stored inside it.
+    #[serde(skip)]
+    pub id: String,
+    /// Creation time in seconds since the Unix epoch.
+    pub time: i64,
+    pub source: String,
+    pub entries: Vec<SnapshotEntry>,
+}
+
+impl Snapshot {
+    pub fn short_id(&self) -> &str {
+        &self.id[..8.min(self.id.len())]
+    }
+
+    pub fn local_time(&self) -> DateTime<Local> {
+        DateTime::from_timestamp(self.time, 0)
+            .unwrap_or_default()
+            .with_timezone(&Local)
+    }
+
+    pub fn total_size(&self) -> u64 {
+        self.entries.iter().map(|e| e.original_len).sum()
+    }
+}
+
+/// A directory holding many snapshots that share one deduplicated chunk store:
+///
+/// ```text
+/// repo/config.json
+/// repo/chunks/<2 hex>/<chunk id>    zstd frame, encrypted if the repo is
+/// repo/snapshots/<snapshot id>      snapshot JSON, encrypted if the repo is
+/// repo/lock                         empty; locked while the repo is changed
+/// ```
+pub struct Repository {
+    pub id: String,
+    root: PathBuf,
+    key: Option<Zeroizing<[u8; 32]>>,
+}
+
+/// Held while a command changes the repository; dropping it unlocks.
+pub struct RepoLock {
+    _file: File,
+}
+
+impl Repository {
+    /// Creates an empty repository. With a password, a key is derived once
+    /// and used for every chunk and snapshot.
+    pub fn init(root: &str, passphrase: Option<&Passphrase>) -> Result<Self> {
+        let root = PathBuf::from(root);
+        if root.join(CONFIG_FILE).exists() {
+            bail!("'{}' is already a repository", root.display());
+        }
+        if root.exists() && fs::read_dir(&root)?.next().is_some() {
+            bail!("'{}' exists and is not empty", root.display());
+        }
+
+        fs::create_dir_all(root.join(CHUNKS_DIR))?;
+        fs::create_dir_all(root.join(SNAPSHOTS_DIR))?;
+
+        let mut key = None;
+        let mut salt_hex = None;
+        let mut kdf = None;
+        if let Some(pass) = passphrase {
+            let mut salt = [0u8; 16];
+            OsRng.fill_bytes(&mut salt);
+            let params = KdfParams::default();
+            key = Some(encrypt::derive_key(pass.as_bytes(), &salt, &params)?);
+            salt_hex = Some(hex::encode(salt));
+            kdf = Some(params);
+        }
+
+        let config = Config {
+            version: REPO_VERSION,
+            id: random_id(),
+            salt: salt_hex,
+            kdf,
+            key_check: key.as_deref().map(key_check),
+        };
+        let id = config.id.clone();
+        write_atomic(
+            &root.join(CONFIG_FILE),
+            &serde_json::to_vec_pretty(&config)?,
+        )?;
+
+        Ok(Repository { id, root, key })
+    }
+
+    pub fn open(root: &str, passphrase: Option<&Passphrase>) -> Result<Self> {
+        let root = PathBuf::from(root);
+        let config_bytes = fs::read(root.join(CONFIG_FILE))
+            .with_context(|| format!("'{}' is not a repository", root.display()))?;
+        let config: Config =
+            serde_json::from_slice(&config_bytes).context("Repository config is corrupted")?;
+        if config.version > REPO_VERSION {
+            bail!(
+                "Repository version {} is newer than this tool supports ({})",
+                config.version,
+                REPO_VERSION
+            );
+        }
+
+        let key = match (&config.salt, passphrase) {
+            (Some(salt), Some(pass)) => {
+                let salt = hex::decode(salt).context("Repository config is corrupted")?;
+                let kdf = config.kdf.unwrap_or(KdfParams::LEGACY);
+                let key = encrypt::derive_key(pass.as_bytes(), &salt, &kdf)?;
+                if config
+                    .key_check
+                    .is_some_and(|check| check != key_check(&key))
+                {
+                    return Err(WrongCredentials("Wrong password for this repository").into());
+                }
+                Some(key)
+            }
+            (Some(_), None) => bail!("Repository is encrypted; a password is required"),
+            (None, _) => None,
+        };
+
+        Ok(Repository {
+            id: config.id,
+            root,
+            key,
+        })
+    }
+
+    /// Locks the repository for adding snapshots. Backups share the lock,
+    /// so several can run at once.
+    pub fn lock_shared(&self) -> Result<RepoLock> {
+        self.lock(false)
+    }
+
+    /// Locks the repository for removing snapshots and chunks. No backup
+    /// runs meanwhile, so a chunk a backup has just reused is never pruned.
+    pub fn lock_exclusive(&self) -> Result<RepoLock> {
+        self.lock(true)
+    }
+
+    fn lock(&self, exclusive: bool) -> Result<RepoLock> {
+        let path = self.root.join(LOCK_FILE);
+        let file = File::options()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)
+            .with_context(|| format!("Failed to open '{}'", path.display()))?;
+        let locked = if exclusive {
+            file.try_lock()
+        } else {
+            file.try_lock_shared()
+        };
+        match locked {
+            Ok(()) => Ok(RepoLock { _file: file }),
+            Err(TryLockError::WouldBlock) => bail!(
+                "Repository '{}' is in use by another ubl command; try again when it is done",
+                self.root.display()
+            ),
+            Err(TryLockError::Error(e)) => {
+                Err(e).with_context(|| format!("Failed to lock '{}'", path.display()))
+            }
+        }
+    }
+
+    /// Chunk IDs are the SHA-256 of the chunk contents, keyed with the
+    /// repository key when encrypted so that IDs reveal nothing about the data.
+    pub fn chunk_id(&self, data: &[u8]) -> String {
+        let mut sha = Sha256::new();
+        if let Some(key) = &self.key {
+            sha.update(key);
+        }
+        sha.update(data);
+        format!("{:x}", sha.finalize())
+    }
+
+    /// Stores a chunk unless it already exists. Returns its ID and the number
+    /// of bytes written (zero for a chunk that was already present).
+    pub fn store_chunk(&self, data: &[u8]) -> Result<(String, u64)> {
+        let id = self.chunk_id(data);
+        let path = self.chunk_path(&id);
+        if path.exists() {
+            return Ok((id, 0));
+        }
+
+        let compressed = zstd::encode_all(data, COMPRESSION_LEVEL)?;
+        let sealed = self.seal(&compressed);
+        fs::create_dir_all(path.parent().unwrap())?;
+        write_atomic(&path, &sealed)?;
+        Ok((id, sealed.len() as u64))
+    }
+
+    pub fn load_chunk(&self, id: &str) -> Result<Vec<u8>> {
+        let sealed =
+            fs::read(self.chunk_path(id)).with_context(|| format!("Chunk {} is missing", id))?;
+        let compressed = self
+            .open_sealed(&sealed)
+            .with_context(|| format!("Chunk {} is corrupted", id))?;
+        let data = zstd::decode_all(&compressed[..])
+            .with_context(|| format!("Chunk {} is corrupted", id))?;
+
+        if self.chunk_id(&data) != id {
+            bail!("Chunk {} does not match its ID", id);
+        }
+        Ok(data)
+    }
+
+    pub fn save_snapshot(&self, snapshot: &mut Snapshot) -> Result<()> {
+        snapshot.id = random_id();
+        let json = serde_json::to_vec(snapshot)?;
+        write_atomic(
+            &self.root.join(SNAPSHOTS_DIR).join(&snapshot.id),
+            &self.seal(&json),
+        )
+    }
+
+    /// All snapshots, oldest first.
+    pub fn snapshots(&self) -> Result<Vec<Snapshot>> {
+        let mut snapshots = Vec::new();
+        for entry in fs::read_dir(self.root.join(SNAPSHOTS_DIR))? {
+            let entry = entry?;
+            let id = entry.file_name().to_string_lossy().to_string();
+            if id.starts_with('.') {
+                continue;
+            }
+
+            let sealed = fs::read(entry.path())?;
+            let json = self
+                .open_sealed(&sealed)
+                .with_context(|| format!("Snapshot {} is unreadable", id))?;
+            let mut snapshot: Snapshot = serde_json::from_slice(&json)
+                .with_context(|| format!("Snapshot {} is corrupted", id))?;
+            snapshot.id = id;
+            snapshots.push(snapshot);
+        }
+
+        snapshots.sort_by(|a, b| a.time.cmp(&b.time).then_with(|| a.id.cmp(&b.id)));
+        Ok(snapshots)
+    }
+
+    /// Finds a snapshot by ID prefix, or the newest one for `latest`.
+    pub fn find_snapshot(&self, spec: &str) -> Result<Snapshot> {
+        let mut snapshots = self.snapshots()?;
+        if spec == "latest" {
+            return snapshots.pop().context("Repository has no snapshots");
+        }
+
+        let mut matches: Vec<_> = snapshots
+            .into_iter()
+            .filter(|s| s.id.starts_with(spec))
+            .collect();
+        match matches.len() {
+            0 => bail!("No snapshot matches '{}'", spec),
+            1 => Ok(matches.pop().unwrap()),
+            _ => bail!("Snapshot ID '{}' is ambiguous", spec),
+        }
+    }
+
+    pub fn remove_snapshot(&self, id: &str) -> Result<()> {
+        fs::remove_file(self.root.join(SNAPSHOTS_DIR).join(id))
+            .with_context(|| format!("Failed to remove snapshot {}", id))
+    }
+
+    /// Deletes every chunk no snapshot references. Returns the number of
+    /// chunks removed and the bytes freed.
+    pub fn prune(&self) -> Result<(usize, u64)> {
+        let referenced: HashSet<String> = self
+            .snapshots()?
+            .into_iter()
+            .flat_map(|s| s.entries)
+            .flat_map(|e| e.chunks)
+            .collect();
+
+        let mut removed = 0;
+        let mut freed = 0;
+        for shard in fs::read_dir(self.root.join(CHUNKS_DIR))? {
+            let shard = shard?;
+            if !shard.file_type()?.is_dir() {
+                continue;
+            }
+            for chunk in fs::read_dir(shard.path())? {
+                let chunk = chunk?;
+                let id = chunk.file_name().to_string_lossy().to_string();
+                if referenced.contains(&id) {
+                    continue;
+                }
+                freed += chunk.metadata()?.len();
+                fs::remove_file(chunk.path())?;
+                removed += 1;
+            }
+        }
+        Ok((removed, freed))
+    }
+
+    fn chunk_path(&self, id: &str) -> PathBuf {
+        self.root.join(CHUNKS_DIR).join(&id[..2]).join(id)
+    }
+
+    /// Encrypts data stored in the repository when it has a key.
+    fn seal(&self, data: &[u8]) -> Vec<u8> {
+        match &self.key {
+            Some(key) => {
+                let (nonce, ciphertext) =
+                    encrypt::encrypt_with_key(data, key, Cipher::Aes256Gcm, &[]);
+                [nonce, ciphertext].concat()
+            }
+            None => data.to_vec(),
+        }
+    }
+
+    fn open_sealed(&self, sealed: &[u8]) -> Result<Zeroizing<Vec<u8>>> {
+        match &self.key {
+            Some(key) => {
+                if sealed.len() < NONCE_LEN {
+                    bail!("Data is truncated");
+                }
+                let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+                encrypt::decrypt_with_key(nonce, ciphertext, key, Cipher::Aes256Gcm, &[])
+            }
+            None => Ok(Zeroizing::new(sealed.to_vec())),
+        }
+    }
+}
+
+/// How many snapshots to keep per time bucket when forgetting.
+#[derive(Default)]
+pub struct Retention {
+    pub keep_last: usize,
+    pub keep_daily: usize,
+    pub keep_weekly: usize,
+    pub keep_monthly: usize,
+    pub keep_yearly: usize,
+}
+
+impl Retention {
+    pub fn is_empty(&self) -> bool {
+        self.keep_last == 0
+            && self.keep_daily == 0
+            && self.keep_weekly == 0
+            && self.keep_monthly == 0
+            && self.keep_yearly == 0
+    }
+
+    /// Returns the IDs of the snapshots to keep. The policy applies to the
+    /// snapshots of each source separately, so backing up one directory
+    /// never pushes out the snapshots of another.
+    pub fn apply(&self, snapshots: &[Snapshot]) -> HashSet<String> {
+        let mut by_source: HashMap<&str, Vec<&Snapshot>> = HashMap::new();
+        for snapshot in snapshots {
+            by_source
+                .entry(&snapshot.source)
+                .or_default()
+                .push(snapshot);
+        }
+        by_source
+            .into_values()
+            .flat_map(|group| self.apply_to_source(group))
+            .collect()
+    }
+
+    /// For each rule, the newest snapshot in each of the most recent N
+    /// distinct buckets (days, weeks, ...) is kept; a snapshot survives if
+    /// any rule keeps it.
+    fn apply_to_source(&self, mut newest_first: Vec<&Snapshot>) -> HashSet<String> {
+        newest_first.sort_by_key(|s| std::cmp::Reverse(s.time));
+
+        type Bucket = fn(&DateTime<Local>) -> (i32, u32);
+        let rules: [(usize, Bucket); 5] = [
+            (self.keep_last, |_| (0, 0)),
+            (self.keep_daily, |t| (t.year(), t.ordinal())),
+            (self.keep_weekly, |t| {
+                let week = t.iso_week();
+                (week.year(), week.week())
+            }),
+            (self.keep_monthly, |t| (t.year(), t.month())),
+            (self.keep_yearly, |t| (t.year(), 0)),
+        ];
+
+        let mut keep = HashSet::new();
+        for (index, (count, bucket)) in rules.iter().enumerate() {
+            let mut remaining = *count;
+            let mut last_bucket = None;
+            for snapshot in &newest_first {
+                if remaining == 0 {
+                    break;
+                }
+                // keep-last counts every snapshot, the others one per bucket.
+                let current = bucket(&snapshot.local_time());
+                if index == 0 || last_bucket != Some(current) {
+                    keep.insert(snapshot.id.clone());
+                    last_bucket = Some(current);
+                    remaining -= 1;
+                }
+            }
+        }
+        keep
+    }
+}
+
+/// A short digest of the repository key. It reveals nothing about the key
+/// but lets a wrong password be told apart from damaged data.
+fn key_check(key: &[u8; 32]) -> String {
+    let mut sha = Sha256::new();
+    sha.update(b"ubl-key-check");
+    sha.update(key);
+    hex::encode(&sha.finalize()[..8])
+}
+
+fn random_id() -> String {
+    let mut id = [0u8; 16];
+    OsRng.fill_bytes(&mut id);
+    hex::encode(id)
+}
+
+/// Writes through a temporary file and renames it into place, so an
+/// interrupted backup never leaves a half-written chunk or snapshot behind.
+fn write_atomic(path: &Path, data: &[u8]) -> Result<()> {
+    let tmp = path.with_file_name(format!(
+        ".{}.tmp",
+        path.file_name().unwrap().to_string_lossy()
+    ));
+    fs::write(&tmp, data).with_context(|| format!("Failed to write '{}'", tmp.display()))?;
+    fs::rename(&tmp, path).with_context(|| format!("Failed to write '{}'", path.display()))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::archive_path;
+
+    /// 2024-03-01 10:00 UTC.
+    const DAY_ONE: i64 = 1_709_287_200;
+    const DAY: i64 = 24 * 60 * 60;
+
+    fn snapshot(id: &str, time: i64) -> Snapshot {
+        Snapshot {
+            id: id.to_string(),
+            time,
+            source: "/data".to_string(),
+            entries: Vec::new(),
+        }
+    }
+
+    /// Two snapshots on the first day, then one a day for three days.
+    fn history() -> Vec<Snapshot> {
+        vec![
+            snapshot("day1-early", DAY_ONE),
+            snapshot("day1-late", DAY_ONE + 1800),
+            snapshot("day2", DAY_ONE + DAY),
+            snapshot("day3", DAY_ONE + 2 * DAY),
+            snapshot("day4", DAY_ONE + 3 * DAY),
+        ]
+    }
+
+    fn kept(retention: Retention) -> Vec<String> {
+        let mut kept: Vec<_> = retention.apply(&history()).into_iter().collect();
+        kept.sort();
+        kept
+    }
+
+    #[test]
+    fn keep_last_keeps_the_newest_snapshots() {
+        let retention = Retention {
+            keep_last: 2,
+            ..Default::default()
+        };
+        assert_eq!(kept(retention), ["day3", "day4"]);
+    }
+
+    #[test]
+    fn keep_daily_keeps_the_newest_snapshot_of_each_day() {
+        let retention = Retention {
+            keep_daily: 4,
+            ..Default::default()
+        };
+        assert_eq!(kept(retention), ["day1-late", "day2", "day3", "day4"]);
+    }
+
+    #[test]
+    fn snapshot_kept_by_any_rule_survives() {
+        let retention = Retention {
+            keep_last: 1,
+            keep_monthly: 1,
+            keep_yearly: 2,
+            ..Default::default()
+        };
+        assert_eq!(kept(retention), ["day4"]);
+
+        let retention = Retention {
+            keep_last: 1,
+            keep_daily: 2,
+            ..Default::default()
+        };
+        assert_eq!(kept(retention), ["day3", "day4"]);
+    }
+
+    #[test]
+    fn each_source_keeps_its_own_snapshots() {
+        let mut snapshots = history();
+        snapshots.push(Snapshot {
+            source: "/home".to_string(),
+            ..snapshot("home-day1", DAY_ONE + 60)
+        });
+        let retention = Retention {
+            keep_last: 1,
+            ..Default::default()
+        };
+
+        let mut kept: Vec<_> = retention.apply(&snapshots).into_iter().collect();
+        kept.sort();
+        assert_eq!(kept, ["day4", "home-day1"]);
+    }
+
+    #[test]
+    fn empty_policy_keeps_nothing() {
+        let retention = Retention::default();
+        assert!(retention.is_empty());
+        assert!(kept(retention).is_empty());
+    }
+
+    #[test]
+    fn backups_share_the_lock_that_pruning_needs_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let repository = Repository::init(&archive_path(&dir, "repo"), None).unwrap();
+
+        let backup = repository.lock_shared().unwrap();
+        let other_backup = repository.lock_shared().unwrap();
+        assert!(repository.lock_exclusive().is_err());
+        drop((backup, other_backup));
+
+        let prune = repository.lock_exclusive().unwrap();
+        assert!(repository.lock_shared().is_err());
+        drop(prune);
+        repository.lock_shared().unwrap();
+    }
+}
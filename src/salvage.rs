@@ -0,0 +1,382 @@
+use std::collections::{BTreeMap, HashSet};
+use std::io::{Cursor, Read};
+
+use anyhow::{bail, Result};
+use sha2::{Digest, Sha256};
+
+use crate::archive::{self, Archive, BaseRef, Chunk, Entry, CHUNK_MARKER, ENTRY_MARKER, MAGIC};
+use crate::keys::Credentials;
+use crate::volume::ArchiveFile;
+
+/// Marks the start of a zstd dictionary.
+const DICTIONARY_MAGIC: [u8; 4] = [0x37, 0xa4, 0x30, 0xec];
+/// Marks the start of a zstd frame, such as the compressed index.
+const FRAME_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// A file that could not be restored, and why.
+pub struct Lost {
+    pub path: String,
+    pub reason: String,
+}
+
+/// Everything that could be read from a damaged archive.
+pub struct Salvage {
+    /// The readable entries, whose chunks are all present. Chunks of lost
+    /// entries are left empty.
+    pub archive: Archive,
+    pub lost: Vec<Lost>,
+    /// Byte ranges of the payload that held no readable record.
+    pub damaged: Vec<(u64, u64)>,
+    /// Readable chunks no known entry refers to, and their original size:
+    /// data of files whose names were lost.
+    pub orphaned: (usize, u64),
+    /// Whether the index was lost, so entries were named from their records.
+    pub index_lost: bool,
+    /// Whether the header was unreadable and the whole file was scanned.
+    pub header_lost: bool,
+    /// The archive this one is an incremental of; its files are not salvaged.
+    pub base: Option<BaseRef>,
+}
+
+/// A record of the chunk table, as found by [`scan`].
+enum Record {
+    Chunk(u32, Chunk),
+    Entry(Entry),
+}
+
+/// Reads what it can from the archive at `path`. Format 8 archives are
+/// scanned for chunk and entry records, each checked against its hash, so
+/// damage only costs the records it hits. Older archives have no sync
+/// markers and are read as far as they parse.
+pub fn salvage(path: &str, credentials: &Credentials) -> Result<Salvage> {
+    let header = archive::read_header(path);
+    if let Ok(Some(header)) = &header {
+        if header.version < 8 {
+            return salvage_unmarked(path, credentials);
+        }
+        let payload = archive::read_payload(path, credentials)?;
+        let index = payload.visible_index.as_deref().map(archive::parse_index);
+        let mut salvage = scan(&payload.data, header.dictionary, index);
+        salvage.base = payload.header.base;
+        return Ok(salvage);
+    }
+
+    // Without a header, the file may still hold readable records after it.
+    let mut data = Vec::new();
+    ArchiveFile::open(path)?.read_to_end(&mut data)?;
+    let start = payload_start(&data);
+    let mut salvage = scan(&data[start..], true, None);
+    salvage.header_lost = true;
+    if salvage.archive.entries.is_empty() && salvage.lost.is_empty() {
+        if matches!(header, Ok(None)) {
+            return salvage_unmarked(path, credentials);
+        }
+        bail!("Archive header is damaged and no readable records were found; if the archive is encrypted, nothing can be salvaged");
+    }
+    Ok(salvage)
+}
+
+/// Where the payload of an archive with an unreadable header would start,
+/// assuming its length field survived.
+fn payload_start(data: &[u8]) -> usize {
+    let Some(len) = data.get(4..8) else {
+        return 0;
+    };
+    let start = 8 + u32::from_le_bytes(len.try_into().unwrap()) as usize;
+    if data.starts_with(MAGIC) && start <= data.len() {
+        start
+    } else {
+        0
+    }
+}
+
+/// Reads an archive without sync markers. Formats 1 to 3 stop at the first
+/// truncated entry; later formats must parse in full.
+fn salvage_unmarked(path: &str, credentials: &Credentials) -> Result<Salvage> {
+    let mut archive = Archive::open(path, credentials).map_err(|e| {
+        e.context("Archives written before format 8 have no sync markers to salvage from")
+    })?;
+    let lost = archive
+        .truncated
+        .take()
+        .map(|path| Lost {
+            path,
+            reason: "archive is truncated here; later files are missing".to_string(),
+        })
+        .into_iter()
+        .collect();
+    archive.entries.retain(|e| !e.is_deleted());
+    Ok(Salvage {
+        base: archive.base.take(),
+        archive,
+        lost,
+        damaged: Vec::new(),
+        orphaned: (0, 0),
+        index_lost: false,
+        header_lost: false,
+    })
+}
+
+/// Scans a format 8 payload for records. `dictionary` says whether it may
+/// start with one. The index is looked for after the last record, unless
+/// it is stored outside the payload and given as `index`.
+fn scan(payload: &[u8], dictionary: bool, index: Option<Result<Vec<Entry>>>) -> Salvage {
+    let dictionary = dictionary.then(|| read_dictionary(payload)).flatten();
+    let mut table_start = dictionary.as_ref().map_or(0, |d| 4 + d.len());
+    if !payload[table_start..].starts_with(CHUNK_MARKER)
+        && !payload[table_start..].starts_with(ENTRY_MARKER)
+    {
+        // Skip the record count.
+        table_start = (table_start + 4).min(payload.len());
+    }
+    let mut archive = Archive::new(dictionary);
+
+    let mut chunks = BTreeMap::new();
+    let mut records = Vec::new();
+    let mut damaged = Vec::new();
+    let mut readable_end = table_start;
+    let mut pos = table_start;
+    while let Some(at) = find(payload, pos, &[CHUNK_MARKER, ENTRY_MARKER]) {
+        let Some((record, len)) = parse_record(&archive, &payload[at..]) else {
+            pos = at + 1;
+            continue;
+        };
+        if at > readable_end {
+            damaged.push((readable_end as u64, at as u64));
+        }
+        match record {
+            Record::Chunk(id, chunk) => {
+                chunks.insert(id, chunk);
+            }
+            Record::Entry(entry) => records.push(entry),
+        }
+        readable_end = at + len;
+        pos = readable_end;
+    }
+
+    // The index follows the last record, as one zstd frame.
+    let index = index.map(Result::ok).unwrap_or_else(|| {
+        let mut pos = readable_end;
+        while let Some(at) = find(payload, pos, &[&FRAME_MAGIC]) {
+            if let Ok(entries) = archive::parse_index(&payload[at..]) {
+                if at > readable_end {
+                    damaged.push((readable_end as u64, at as u64));
+                }
+                readable_end = payload.len();
+                return Some(entries);
+            }
+            pos = at + 1;
+        }
+        None
+    });
+    if readable_end < payload.len() {
+        damaged.push((readable_end as u64, payload.len() as u64));
+    }
+
+    let index_lost = index.is_none();
+    let entries = index.unwrap_or(records);
+    let mut lost = Vec::new();
+    let mut used = HashSet::new();
+    for entry in entries.into_iter().filter(|e| !e.is_deleted()) {
+        used.extend(entry.chunks.iter().copied());
+        let missing = entry
+            .chunks
+            .iter()
+            .filter(|id| !chunks.contains_key(id))
+            .count();
+        if missing > 0 {
+            lost.push(Lost {
+                reason: format!("{} of {} chunks are damaged", missing, entry.chunks.len()),
+                path: entry.path,
+            });
+            continue;
+        }
+        archive.entries.push(entry);
+    }
+
+    let orphaned = chunks
+        .iter()
+        .filter(|(id, _)| !used.contains(id))
+        .fold((0, 0), |(count, size), (_, chunk)| {
+            (count + 1, size + chunk.original_len)
+        });
+    // Chunks keep their ids as indices; missing ones are left empty.
+    let slots = chunks.keys().last().map_or(0, |id| *id as usize + 1);
+    archive.chunks = (0..slots)
+        .map(|_| Chunk {
+            hash: None,
+            original_len: 0,
+            data: Vec::new(),
+        })
+        .collect();
+    for (id, chunk) in chunks {
+        archive.chunks[id as usize] = chunk;
+    }
+
+    Salvage {
+        archive,
+        lost,
+        damaged,
+        orphaned,
+        index_lost,
+        header_lost: false,
+        base: None,
+    }
+}
+
+/// Reads the dictionary at the start of a payload, if it looks intact.
+fn read_dictionary(payload: &[u8]) -> Option<Vec<u8>> {
+    let len = u32::from_le_bytes(payload.get(..4)?.try_into().unwrap()) as usize;
+    let dictionary = payload.get(4..4 + len)?;
+    dictionary
+        .starts_with(&DICTIONARY_MAGIC)
+        .then(|| dictionary.to_vec())
+}
+
+/// Position of the first of `patterns` at or after `from`.
+fn find(data: &[u8], from: usize, patterns: &[&[u8]]) -> Option<usize> {
+    let first_bytes: Vec<u8> = patterns.iter().map(|p| p[0]).collect();
+    (from..data.len()).find(|&i| {
+        first_bytes.contains(&data[i]) && patterns.iter().any(|p| data[i..].starts_with(p))
+    })
+}
+
+/// Parses the record at the start of `data` and checks it against its hash.
+/// Returns the record and its length.
+fn parse_record(archive: &Archive, data: &[u8]) -> Option<(Record, usize)> {
+    let mut reader = Cursor::new(data);
+    let mut marker = [0u8; 8];
+    reader.read_exact(&mut marker).ok()?;
+
+    if &marker == ENTRY_MARKER {
+        let len = read_u32(&mut reader)? as usize;
+        let json = data.get(12..12 + len)?;
+        let hash = data.get(12 + len..12 + len + 32)?;
+        if Sha256::digest(json).as_slice() != hash {
+            return None;
+        }
+        let entry = serde_json::from_slice(json).ok()?;
+        return Some((Record::Entry(entry), 12 + len + 32));
+    }
+
+    let id = read_u32(&mut reader)?;
+    let mut hash = [0u8; 32];
+    reader.read_exact(&mut hash).ok()?;
+    let original_len = read_u64(&mut reader)?;
+    let compressed_len = usize::try_from(read_u64(&mut reader)?).ok()?;
+    let start = reader.position() as usize;
+    let chunk = Chunk {
+        hash: Some(hash),
+        original_len,
+        data: data
+            .get(start..start.checked_add(compressed_len)?)?
+            .to_vec(),
+    };
+    let contents = archive.decompress_chunk(&chunk).ok()?;
+    if contents.len() as u64 != original_len || Sha256::digest(&contents).as_slice() != hash {
+        return None;
+    }
+    Some((Record::Chunk(id, chunk), start + compressed_len))
+}
+
+fn read_u32(reader: &mut impl Read) -> Option<u32> {
+    let mut buf = [0u8; 4];
+    reader.read_exact(&mut buf).ok()?;
+    Some(u32::from_le_bytes(buf))
+}
+
+fn read_u64(reader: &mut impl Read) -> Option<u64> {
+    let mut buf = [0u8; 8];
+    reader.read_exact(&mut buf).ok()?;
+    Some(u64::from_le_bytes(buf))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{archive_path, noise, read_entry, sample_archive};
+
+    const PATHS: [&str; 4] = ["a.txt", "b/copy.txt", "big.bin", "empty"];
+
+    /// The payload of a plaintext archive: everything after the header.
+    fn payload(archive: &Archive) -> Vec<u8> {
+        let bytes = archive.to_bytes().unwrap();
+        bytes[payload_start(&bytes)..].to_vec()
+    }
+
+    /// Offset of the middle of the first chunk of the entry at `path` in
+    /// `payload`.
+    fn chunk_middle(archive: &Archive, payload: &[u8], path: &str) -> usize {
+        let entry = archive.entries.iter().find(|e| e.path == path).unwrap();
+        let data = &archive.chunks[entry.chunks[0] as usize].data;
+        find(payload, 0, &[data]).unwrap() + data.len() / 2
+    }
+
+    fn paths(salvage: &Salvage) -> Vec<&str> {
+        let mut paths: Vec<_> = salvage
+            .archive
+            .entries
+            .iter()
+            .map(|e| e.path.as_str())
+            .collect();
+        paths.sort();
+        paths
+    }
+
+    #[test]
+    fn intact_payload_is_read_in_full() {
+        let salvage = scan(&payload(&sample_archive()), false, None);
+        assert_eq!(paths(&salvage), PATHS);
+        assert!(salvage.lost.is_empty());
+        assert!(salvage.damaged.is_empty());
+        assert!(!salvage.index_lost);
+        assert_eq!(salvage.orphaned, (0, 0));
+    }
+
+    #[test]
+    fn damaged_chunk_only_loses_its_file() {
+        let archive = sample_archive();
+        let mut payload = payload(&archive);
+        let at = chunk_middle(&archive, &payload, "big.bin");
+        payload[at] ^= 0xff;
+
+        let salvage = scan(&payload, false, None);
+        assert_eq!(paths(&salvage), ["a.txt", "b/copy.txt", "empty"]);
+        assert_eq!(salvage.lost.len(), 1);
+        assert_eq!(salvage.lost[0].path, "big.bin");
+        assert_eq!(salvage.damaged.len(), 1);
+        assert_eq!(read_entry(&salvage.archive, "b/copy.txt"), b"hello");
+    }
+
+    #[test]
+    fn entries_are_named_from_their_records_without_the_index() {
+        let mut payload = payload(&sample_archive());
+        // The index is the last zstd frame.
+        let index_start = (0..payload.len())
+            .rev()
+            .find(|&i| payload[i..].starts_with(&FRAME_MAGIC))
+            .unwrap();
+        payload.truncate(index_start + 8);
+
+        let salvage = scan(&payload, false, None);
+        assert!(salvage.index_lost);
+        assert_eq!(paths(&salvage), PATHS);
+        assert_eq!(
+            read_entry(&salvage.archive, "big.bin"),
+            noise(200 * 1024, 1)
+        );
+    }
+
+    #[test]
+    fn archive_with_damaged_header_is_scanned() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = archive_path(&dir, "a.ubl");
+        let mut bytes = sample_archive().to_bytes().unwrap();
+        bytes[9] = b'#';
+        std::fs::write(&path, bytes).unwrap();
+
+        let salvage = salvage(&path, &Credentials::default()).unwrap();
+        assert!(salvage.header_lost);
+        assert_eq!(paths(&salvage), PATHS);
+    }
+}
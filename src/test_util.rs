@@ -0,0 +1,59 @@
+//! Fixtures shared by the unit tests.
+
+use std::io::Read;
+
+use clap::Parser;
+
+use crate::archive::Archive;
+
+/// Path of an archive named `name` inside the test's temporary directory.
+pub fn archive_path(dir: &tempfile::TempDir, name: &str) -> String {
+    dir.path().join(name).to_string_lossy().to_string()
+}
+
+/// Parses flags into an argument group, as clap does for a command that
+/// flattens it.
+pub fn parse_args<T: clap::Args>(args: &[&str]) -> T {
+    #[derive(Parser)]
+    struct Command<T: clap::Args> {
+        #[command(flatten)]
+        args: T,
+    }
+    Command::<T>::parse_from(std::iter::once("ubl").chain(args.iter().copied())).args
+}
+
+/// The contents of the entry stored under `path`.
+pub fn read_entry(archive: &Archive, path: &str) -> Vec<u8> {
+    let entry = archive.entries.iter().find(|e| e.path == path).unwrap();
+    let mut data = Vec::new();
+    archive
+        .decoder(entry)
+        .unwrap()
+        .read_to_end(&mut data)
+        .unwrap();
+    data
+}
+
+/// Incompressible data, long enough to be split into several chunks.
+pub fn noise(len: usize, seed: u64) -> Vec<u8> {
+    let mut state = seed | 1;
+    (0..len)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect()
+}
+
+/// An archive with a small file stored twice, a file of several chunks and
+/// an empty file.
+pub fn sample_archive() -> Archive {
+    let mut archive = Archive::new(None);
+    archive.insert("a.txt".into(), b"hello", Some(1_700_000_000));
+    archive.insert("b/copy.txt".into(), b"hello", None);
+    archive.insert("big.bin".into(), &noise(200 * 1024, 1), None);
+    archive.insert("empty".into(), b"", None);
+    archive
+}
@@ -0,0 +1,424 @@
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+
+use crate::recovery::{self, Trailer};
+
+/// One file of an archive on disk and where its bytes start in the archive.
+struct Volume {
+    path: String,
+    start: u64,
+    len: u64,
+}
+
+/// Read access to an archive stored either as one file or split into
+/// volumes `name.ubl.001`, `name.ubl.002`, ... The volumes are plain slices
+/// of the archive, so concatenating them gives the single-file archive, and
+/// every volume but the last has the same size. The last one is always
+/// shorter, even empty, so a missing final volume can be told from a set
+/// that ends on a volume boundary.
+///
+/// A recovery record at the end is not part of the archive: reads stop
+/// where it begins.
+pub struct ArchiveFile {
+    volumes: Vec<Volume>,
+    /// End of the archive data, before any recovery record.
+    end: u64,
+    recovery: Option<Trailer>,
+    pos: u64,
+    /// The volume currently open, positioned at `pos`.
+    open: Option<(usize, File)>,
+}
+
+impl ArchiveFile {
+    /// Opens the archive at `path`. If there is no such file but there are
+    /// volumes `path.001`, ..., opens those instead; `path.001` itself also
+    /// names the whole set.
+    pub fn open(path: &str) -> Result<Self> {
+        if let Some(base) = split_base(path) {
+            return ArchiveFile::new(find_volumes(base)?, None);
+        }
+        let file =
+            File::open(path).with_context(|| format!("Failed to read archive '{}'", path))?;
+        let len = file.metadata()?.len();
+        let volume = Volume {
+            path: path.to_string(),
+            start: 0,
+            len,
+        };
+        ArchiveFile::new(vec![volume], Some((0, file)))
+    }
+
+    fn new(volumes: Vec<Volume>, open: Option<(usize, File)>) -> Result<Self> {
+        let mut file = ArchiveFile {
+            end: volumes.last().map_or(0, |v| v.start + v.len),
+            volumes,
+            recovery: None,
+            pos: 0,
+            open,
+        };
+        let len = file.end;
+        file.recovery = recovery::find(&mut file, len)?;
+        if let Some(trailer) = &file.recovery {
+            file.end = trailer.protected_len;
+        }
+        Ok(file)
+    }
+
+    /// Size of all files the archive is stored in.
+    pub fn disk_len(&self) -> u64 {
+        self.volumes.last().map_or(0, |v| v.start + v.len)
+    }
+
+    /// The recovery record stored after the archive, if any.
+    pub fn recovery(&self) -> Option<Trailer> {
+        self.recovery
+    }
+
+    /// Reads everything stored, including the recovery record.
+    pub fn read_all(&mut self) -> io::Result<Vec<u8>> {
+        let disk_len = self.disk_len();
+        let end = std::mem::replace(&mut self.end, disk_len);
+        self.seek(SeekFrom::Start(0))?;
+        let mut data = Vec::new();
+        let read = self.read_to_end(&mut data);
+        self.end = end;
+        read.map(|_| data)
+    }
+
+    /// Replaces the stored bytes, as returned by [`ArchiveFile::read_all`],
+    /// with `data` of the same length, keeping the volume layout. Each file
+    /// is replaced atomically.
+    pub fn rewrite(&self, data: &[u8]) -> Result<()> {
+        if data.len() as u64 != self.disk_len() {
+            bail!("Rewritten archive must keep its size");
+        }
+        for volume in &self.volumes {
+            let start = volume.start as usize;
+            write_file(&volume.path, &data[start..start + volume.len as usize])?;
+        }
+        Ok(())
+    }
+
+    /// Number of files the archive is stored in.
+    pub fn volume_count(&self) -> usize {
+        self.volumes.len()
+    }
+}
+
+impl Read for ArchiveFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.end {
+            return Ok(0);
+        }
+        let Some(index) = self.volumes.iter().position(|v| self.pos < v.start + v.len) else {
+            return Ok(0);
+        };
+        let volume = &self.volumes[index];
+        if self.open.as_ref().map(|(i, _)| *i) != Some(index) {
+            let mut file = File::open(&volume.path)?;
+            file.seek(SeekFrom::Start(self.pos - volume.start))?;
+            self.open = Some((index, file));
+        }
+
+        let remaining = (volume.start + volume.len).min(self.end) - self.pos;
+        let want = buf
+            .len()
+            .min(usize::try_from(remaining).unwrap_or(usize::MAX));
+        let (_, file) = self.open.as_mut().unwrap();
+        let n = file.read(&mut buf[..want])?;
+        if n == 0 && want > 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("Volume '{}' is shorter than expected", volume.path),
+            ));
+        }
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for ArchiveFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.end.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        };
+        let Some(target) = target else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Seek before the start of the archive",
+            ));
+        };
+        if target != self.pos {
+            self.pos = target;
+            self.open = None;
+        }
+        Ok(target)
+    }
+}
+
+/// The path the volumes are named after, if `path` names a split archive:
+/// either `path.001` exists and `path` does not, or `path` is `base.001`
+/// and `base` does not exist.
+fn split_base(path: &str) -> Option<&str> {
+    match path.strip_suffix(".001") {
+        Some(base) if !Path::new(base).exists() => Some(base),
+        _ if !Path::new(path).exists() && volume_numbers(path).next().is_some() => Some(path),
+        _ => None,
+    }
+}
+
+/// Name of volume `number` (counting from 1) of the archive at `path`.
+pub fn volume_path(path: &str, number: usize) -> String {
+    format!("{}.{:03}", path, number)
+}
+
+/// Numbers of the volumes of `path` that exist, in no particular order.
+fn volume_numbers(path: &str) -> impl Iterator<Item = usize> {
+    let path = Path::new(path);
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let prefix = format!(
+        "{}.",
+        path.file_name().unwrap_or_default().to_string_lossy()
+    );
+    fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .filter_map(Result::ok)
+        .filter_map(move |entry| {
+            let name = entry.file_name().to_string_lossy().to_string();
+            let digits = name.strip_prefix(&prefix)?;
+            if digits.len() < 3 || !digits.bytes().all(|b| b.is_ascii_digit()) {
+                return None;
+            }
+            digits.parse().ok().filter(|n| *n > 0)
+        })
+}
+
+/// Lists the volumes of `path`, checking that none is missing or cut short.
+fn find_volumes(path: &str) -> Result<Vec<Volume>> {
+    let last = volume_numbers(path).max().unwrap_or(0);
+    let mut volumes = Vec::new();
+    let mut missing = Vec::new();
+    let mut start = 0;
+    for number in 1..=last {
+        let volume_path = volume_path(path, number);
+        match fs::metadata(&volume_path) {
+            Ok(meta) => {
+                volumes.push(Volume {
+                    path: volume_path,
+                    start,
+                    len: meta.len(),
+                });
+                start += meta.len();
+            }
+            Err(_) => missing.push(volume_path),
+        }
+    }
+    if !missing.is_empty() {
+        bail!(
+            "Split archive '{}' is incomplete: missing {}",
+            path,
+            missing.join(", ")
+        );
+    }
+
+    let Some((last, rest)) = volumes.split_last() else {
+        bail!("Archive '{}' not found", path);
+    };
+    let full_len = volumes[0].len;
+    if let Some(short) = rest.iter().find(|v| v.len != full_len) {
+        bail!(
+            "Volume '{}' is {} bytes but the other volumes are {}; it may be truncated",
+            short.path,
+            short.len,
+            full_len
+        );
+    }
+    if !rest.is_empty() && last.len == full_len {
+        bail!(
+            "Split archive '{}' is incomplete: missing {} (and possibly later volumes)",
+            path,
+            volume_path(path, volumes.len() + 1)
+        );
+    }
+    Ok(volumes)
+}
+
+/// Writes `data` as volumes of `volume_size` bytes, ending with a shorter
+/// (possibly empty) one. Returns the number of volumes. A single-file archive
+/// at `path` and volumes left over from an earlier, longer set are removed.
+pub fn write(path: &str, data: &[u8], volume_size: u64) -> Result<usize> {
+    let volume_size = usize::try_from(volume_size).unwrap_or(usize::MAX);
+    let mut pieces: Vec<&[u8]> = data.chunks(volume_size).collect();
+    if data.len().is_multiple_of(volume_size) {
+        pieces.push(&[]);
+    }
+
+    for (i, piece) in pieces.iter().enumerate() {
+        write_file(&volume_path(path, i + 1), piece)?;
+    }
+
+    remove_stale(path, pieces.len() + 1)?;
+    if Path::new(path).exists() {
+        fs::remove_file(path).with_context(|| format!("Failed to remove '{}'", path))?;
+    }
+    Ok(pieces.len())
+}
+
+/// Replaces the archive stored at `path` with `data`. A split archive, named
+/// by its base path or its first volume, is written back as volumes of the
+/// same size (a set of one volume stays one volume); otherwise `path` becomes
+/// a single file. Each file is replaced atomically.
+pub fn replace(path: &str, data: &[u8]) -> Result<()> {
+    let Some(base) = split_base(path) else {
+        write_file(path, data)?;
+        return remove_stale(path, 1);
+    };
+    let volume_size = if volume_numbers(base).nth(1).is_some() {
+        fs::metadata(volume_path(base, 1))
+            .with_context(|| format!("Failed to read '{}'", volume_path(base, 1)))?
+            .len()
+    } else {
+        data.len() as u64 + 1
+    };
+    write(base, data, volume_size.max(1)).map(|_| ())
+}
+
+/// Writes `data` to a temporary file next to `path`, then renames it over
+/// `path`, so that a failure leaves the old file intact.
+pub fn write_file(path: &str, data: &[u8]) -> Result<()> {
+    let tmp_path = format!("{}.tmp", path);
+    let result = (|| -> Result<()> {
+        let mut file =
+            File::create(&tmp_path).with_context(|| format!("Failed to create '{}'", tmp_path))?;
+        file.write_all(data)
+            .and_then(|_| file.sync_all())
+            .with_context(|| format!("Failed to write '{}'", tmp_path))?;
+        fs::rename(&tmp_path, path).with_context(|| format!("Failed to write '{}'", path))
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+    result
+}
+
+/// Removes the volumes of `path` from number `first` on, once the archive
+/// has been written in another shape.
+pub fn remove_stale(path: &str, first: usize) -> Result<()> {
+    for number in volume_numbers(path).filter(|n| *n >= first) {
+        let volume_path = volume_path(path, number);
+        fs::remove_file(&volume_path)
+            .with_context(|| format!("Failed to remove '{}'", volume_path))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{archive_path, noise};
+
+    fn read_all(path: &str) -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+        ArchiveFile::open(path)?.read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    #[test]
+    fn volumes_read_back_as_one_archive() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = archive_path(&dir, "a.ubl");
+        let data = noise(2500, 1);
+        assert_eq!(write(&path, &data, 1000).unwrap(), 3);
+
+        for name in [path.clone(), volume_path(&path, 1)] {
+            assert_eq!(ArchiveFile::open(&name).unwrap().volume_count(), 3);
+            assert_eq!(read_all(&name).unwrap(), data);
+        }
+        let mut file = ArchiveFile::open(&path).unwrap();
+        let mut tail = Vec::new();
+        file.seek(SeekFrom::Start(1500)).unwrap();
+        file.read_to_end(&mut tail).unwrap();
+        assert_eq!(tail, data[1500..]);
+    }
+
+    #[test]
+    fn set_ending_on_a_volume_boundary_gets_an_empty_last_volume() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = archive_path(&dir, "a.ubl");
+        let data = noise(2000, 1);
+        assert_eq!(write(&path, &data, 1000).unwrap(), 3);
+        assert_eq!(fs::metadata(volume_path(&path, 3)).unwrap().len(), 0);
+        assert_eq!(read_all(&path).unwrap(), data);
+    }
+
+    #[test]
+    fn empty_set_is_not_found() {
+        let dir = tempfile::tempdir().unwrap();
+        let err = find_volumes(&archive_path(&dir, "a.ubl")).err().unwrap();
+        assert!(err.to_string().contains("not found"), "{}", err);
+    }
+
+    #[test]
+    fn truncated_volume_is_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = archive_path(&dir, "a.ubl");
+        write(&path, &noise(2500, 1), 1000).unwrap();
+        fs::write(volume_path(&path, 2), noise(999, 2)).unwrap();
+
+        let err = ArchiveFile::open(&path).err().unwrap();
+        assert!(err.to_string().contains("may be truncated"), "{}", err);
+    }
+
+    #[test]
+    fn missing_volume_is_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = archive_path(&dir, "a.ubl");
+        write(&path, &noise(2500, 1), 1000).unwrap();
+
+        fs::remove_file(volume_path(&path, 3)).unwrap();
+        let err = ArchiveFile::open(&path).err().unwrap();
+        assert!(err.to_string().contains("incomplete"), "{}", err);
+
+        fs::write(volume_path(&path, 3), noise(500, 2)).unwrap();
+        fs::remove_file(volume_path(&path, 2)).unwrap();
+        let err = ArchiveFile::open(&path).err().unwrap();
+        assert!(err.to_string().contains("missing"), "{}", err);
+    }
+
+    #[test]
+    fn replace_keeps_the_volume_size_and_removes_stale_volumes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = archive_path(&dir, "a.ubl");
+        write(&path, &noise(3500, 1), 1000).unwrap();
+
+        let data = noise(1500, 2);
+        replace(&volume_path(&path, 1), &data).unwrap();
+        assert!(!Path::new(&path).exists());
+        assert_eq!(fs::metadata(volume_path(&path, 1)).unwrap().len(), 1000);
+        assert_eq!(volume_numbers(&path).count(), 2);
+        assert_eq!(read_all(&path).unwrap(), data);
+    }
+
+    #[test]
+    fn writing_a_single_file_removes_old_volumes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = archive_path(&dir, "a.ubl");
+        write(&path, &noise(2500, 1), 1000).unwrap();
+
+        let data = noise(1200, 2);
+        write_file(&path, &data).unwrap();
+        remove_stale(&path, 1).unwrap();
+        assert_eq!(volume_numbers(&path).count(), 0);
+        assert_eq!(ArchiveFile::open(&path).unwrap().volume_count(), 1);
+        assert_eq!(read_all(&path).unwrap(), data);
+    }
+}